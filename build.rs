@@ -2,10 +2,9 @@ extern crate pkg_config;
 extern crate gcc;
 
 fn main() {
-    match pkg_config::find_library("lua5.1") {
-        Ok(_) => return,
-        Err(..) => {}
-    };
+    if pkg_config::find_library("lua5.1").is_ok() {
+        return;
+    }
 
     gcc::Config::new()
         .file("dist/lua-5.1.5/src/lapi.c")
@@ -1,24 +1,31 @@
 extern crate flu;
 
+use std::cell::Cell;
+use std::rc::Rc;
+
 fn main() {
     let ctx = flu::Context::new();
-    let (mut x, mut y) = (25f32, 25f32);
+    let (x, y) = (Rc::new(Cell::new(25f32)), Rc::new(Cell::new(25f32)));
 
-    println!("x: {}, y: {}", x, y);
+    println!("x: {}, y: {}", x.get(), y.get());
 
-    ctx.set("move_x", |ctx: &mut flu::Context| {
+    // Lua keeps the callbacks, so they share the positions rather than
+    // borrowing them
+    let pos = x.clone();
+    ctx.set("move_x", move |ctx: &mut flu::Context| {
         let speed = ctx.pop::<f32>();
-        x += speed;
+        pos.set(pos.get() + speed);
         0
     });
 
-    ctx.set("move_y", |ctx: &mut flu::Context| {
+    let pos = y.clone();
+    ctx.set("move_y", move |ctx: &mut flu::Context| {
         let speed = ctx.pop::<f32>();
-        y += speed;
+        pos.set(pos.get() + speed);
         0
     });
 
     ctx.eval("for i=1,10 do move_x(1.0) move_y(2.0) end").unwrap();
 
-    println!("x: {}, y: {}", x, y);
+    println!("x: {}, y: {}", x.get(), y.get());
 }
@@ -0,0 +1,332 @@
+// Derives `flu::stack::Push` and `flu::stack::Read` for plain data structs.
+// Named fields map to table keys and tuple structs to sequences. `Read` also
+// derives `Size`, since reading off the stack needs both.
+//
+// Field attributes:
+//
+//   #[lua(rename = "key")]       use a different table key
+//   #[lua(default)]              use `Default::default()` when the key is nil
+//   #[lua(default = "path")]     call `path()` when the key is nil
+//   #[lua(skip)]                 never pushed, always read as its default
+//   #[lua(flatten)]              merge the field's table into this one, nil adds nothing
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as Tokens};
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Index, ItemImpl, ItemStruct, LitByteStr, LitStr, Type};
+
+mod userdata;
+
+#[proc_macro_derive(Push, attributes(lua))]
+pub fn derive_push(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, push).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+#[proc_macro_derive(Read, attributes(lua))]
+pub fn derive_read(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, read).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+#[proc_macro_attribute]
+pub fn userdata(args: TokenStream, input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as ItemStruct);
+    userdata::userdata(args.into(), input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+#[proc_macro_attribute]
+pub fn methods(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return syn::Error::new(proc_macro2::Span::call_site(), "#[flu::methods] takes no options").into_compile_error().into();
+    }
+    let input = parse_macro_input!(input as ItemImpl);
+    userdata::methods(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+enum FieldDefault {
+    None,
+    Trait,
+    Path(syn::Path),
+}
+
+struct Field {
+    // the Rust-side accessor, `name` or `0`
+    member: Tokens,
+    ty: Type,
+    key: String,
+    default: FieldDefault,
+    skip: bool,
+    flatten: bool,
+}
+
+impl Field {
+    fn default_expr(&self) -> Tokens {
+        match self.default {
+            FieldDefault::Path(ref path) => quote!(#path()),
+            _ => quote!(::std::default::Default::default()),
+        }
+    }
+
+    fn key_ptr(&self) -> Tokens {
+        let key = LitByteStr::new(format!("{}\0", self.key).as_bytes(), Span::call_site());
+        quote!(#key.as_ptr() as *const _)
+    }
+}
+
+fn fields(input: &DeriveInput) -> syn::Result<(Vec<Field>, bool)> {
+    let data = match input.data {
+        Data::Struct(ref data) => data,
+        _ => return Err(syn::Error::new_spanned(&input.ident, "flu can only derive Push and Read for structs")),
+    };
+
+    let named = !matches!(data.fields, Fields::Unnamed(..));
+
+    let mut out = Vec::new();
+    for (i, f) in data.fields.iter().enumerate() {
+        let mut field = Field {
+            member: match f.ident {
+                Some(ref ident) => quote!(#ident),
+                None => {
+                    let idx = Index::from(i);
+                    quote!(#idx)
+                }
+            },
+            ty: f.ty.clone(),
+            key: f.ident.as_ref().map(|i| i.to_string().trim_start_matches("r#").to_string()).unwrap_or_default(),
+            default: FieldDefault::None,
+            skip: false,
+            flatten: false,
+        };
+
+        for attr in f.attrs.iter().filter(|a| a.path().is_ident("lua")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    field.key = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("default") {
+                    field.default = match meta.input.peek(syn::Token![=]) {
+                        true => FieldDefault::Path(meta.value()?.parse::<LitStr>()?.parse()?),
+                        false => FieldDefault::Trait,
+                    };
+                } else if meta.path.is_ident("skip") {
+                    field.skip = true;
+                } else if meta.path.is_ident("flatten") {
+                    field.flatten = true;
+                } else {
+                    return Err(meta.error("unknown lua attribute"));
+                }
+                Ok(())
+            })?;
+        }
+
+        if !named && (field.flatten || !field.key.is_empty()) {
+            return Err(syn::Error::new_spanned(f, "tuple struct fields can't be renamed or flattened"));
+        }
+
+        out.push(field);
+    }
+
+    Ok((out, named))
+}
+
+fn expand(input: &DeriveInput, f: fn(&DeriveInput, &[Field], bool) -> Tokens) -> syn::Result<Tokens> {
+    let (fields, named) = fields(input)?;
+    Ok(f(input, &fields, named))
+}
+
+fn push(input: &DeriveInput, fields: &[Field], named: bool) -> Tokens {
+    let name = &input.ident;
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::flu::stack::Push));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let pushed: Vec<&Field> = fields.iter().filter(|f| !f.skip).collect();
+    let len = pushed.len() as i32;
+
+    let body = pushed.iter().enumerate().map(|(i, f)| {
+        let member = &f.member;
+        let key = f.key_ptr();
+        let seq = i as i32 + 1;
+
+        match (named, f.flatten) {
+            // a nil field, e.g. `None`, has nothing to merge; `Push` can't
+            // fail, so any other non-table panics with the field's path
+            (true, true) => {
+                let field = member.to_string();
+                quote! {
+                    ::flu::stack::Push::push(&self.#member, ctx);
+                    unsafe {
+                        if ::flu::ffi::lua_istable(ctx.handle, -1) {
+                            ::flu::ffi::lua_pushnil(ctx.handle);
+                            while ::flu::ffi::lua_next(ctx.handle, -2) != 0 {
+                                ::flu::ffi::lua_pushvalue(ctx.handle, -2);
+                                ::flu::ffi::lua_insert(ctx.handle, -2);
+                                ::flu::ffi::lua_rawset(ctx.handle, -5);
+                            }
+                        } else if !::flu::ffi::lua_isnil(ctx.handle, -1) {
+                            let mut err = ::flu::stack::ReadError::new::<::flu::Table>(ctx, -1).at_field(#field);
+                            err.expected = "table".to_string();
+                            ::flu::ffi::lua_pop(ctx.handle, 2);
+                            panic!("can't flatten {} into {}: {}", #field, stringify!(#name), err);
+                        }
+                        ::flu::ffi::lua_pop(ctx.handle, 1);
+                    }
+                }
+            }
+            (true, false) => quote! {
+                ::flu::stack::Push::push(&self.#member, ctx);
+                unsafe {
+                    ::flu::ffi::lua_setfield(ctx.handle, -2, #key);
+                }
+            },
+            (false, _) => quote! {
+                ::flu::stack::Push::push(&self.#member, ctx);
+                unsafe {
+                    ::flu::ffi::lua_rawseti(ctx.handle, -2, #seq);
+                }
+            },
+        }
+    });
+
+    let (narr, nrec) = match named {
+        true => (0, len),
+        false => (len, 0),
+    };
+
+    quote! {
+        impl #impl_generics ::flu::stack::Push for #name #ty_generics #where_clause {
+            fn push(&self, ctx: &::flu::Context) {
+                unsafe {
+                    ::flu::ffi::lua_createtable(ctx.handle, #narr, #nrec);
+                }
+                #(#body)*
+            }
+        }
+    }
+}
+
+fn read(input: &DeriveInput, fields: &[Field], named: bool) -> Tokens {
+    let name = &input.ident;
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::flu::stack::Read<'__lua>));
+    }
+    generics.params.insert(0, parse_quote!('__lua));
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (size_generics, ty_generics, size_where) = input.generics.split_for_impl();
+
+    let mut seq = 0;
+    let mut reads = Vec::new();
+    let mut checks = Vec::new();
+
+    for f in fields {
+        let member = &f.member;
+        let ty = &f.ty;
+        let default = f.default_expr();
+
+        if f.skip {
+            reads.push(quote!(#member: #default));
+            continue;
+        }
+
+        if f.flatten {
+            reads.push(quote!(#member: <#ty as ::flu::stack::Read<'__lua>>::read(ctx, idx)));
+            checks.push(quote! {
+                <#ty as ::flu::stack::Read<'__lua>>::validate(ctx, idx)?;
+            });
+            continue;
+        }
+
+        seq += 1;
+        let (get, at) = match named {
+            true => {
+                let key = f.key_ptr();
+                let name = &f.key;
+                (quote!(::flu::ffi::lua_getfield(ctx.handle, idx, #key)), quote!(e.at_field(#name)))
+            }
+            false => (quote!(::flu::ffi::lua_rawgeti(ctx.handle, idx, #seq)), quote!(e.at_index(#seq as usize))),
+        };
+        let (read, validate) = match f.default {
+            FieldDefault::None => (
+                quote!(<#ty as ::flu::stack::Read<'__lua>>::read(ctx, -1)),
+                quote!(<#ty as ::flu::stack::Read<'__lua>>::validate(ctx, -1)),
+            ),
+            _ => (
+                quote! {
+                    match unsafe { ::flu::ffi::lua_isnil(ctx.handle, -1) } {
+                        true => #default,
+                        false => <#ty as ::flu::stack::Read<'__lua>>::read(ctx, -1),
+                    }
+                },
+                quote! {
+                    match unsafe { ::flu::ffi::lua_isnil(ctx.handle, -1) } {
+                        true => Ok(()),
+                        false => <#ty as ::flu::stack::Read<'__lua>>::validate(ctx, -1),
+                    }
+                },
+            ),
+        };
+
+        reads.push(quote! {
+            #member: {
+                unsafe {
+                    #get;
+                }
+                let v = #read;
+                ctx.pop_discard(1);
+                v
+            }
+        });
+        checks.push(quote! {
+            unsafe {
+                #get;
+            }
+            let ret = #validate;
+            ctx.pop_discard(1);
+            ret.map_err(|e| #at)?;
+        });
+    }
+
+    quote! {
+        impl #impl_generics ::flu::stack::Read<'__lua> for #name #ty_generics #where_clause {
+            fn read(ctx: &'__lua ::flu::Context, idx: i32) -> Self {
+                let idx = match idx < 0 && idx > ::flu::ffi::LUA_REGISTRYINDEX {
+                    true => ctx.size() + idx + 1,
+                    false => idx,
+                };
+                #name { #(#reads),* }
+            }
+
+            fn check(ctx: &'__lua ::flu::Context, idx: i32) -> bool {
+                <Self as ::flu::stack::Read<'__lua>>::validate(ctx, idx).is_ok()
+            }
+
+            fn validate(ctx: &'__lua ::flu::Context, idx: i32) -> ::std::result::Result<(), ::flu::stack::ReadError> {
+                let idx = match idx < 0 && idx > ::flu::ffi::LUA_REGISTRYINDEX {
+                    true => ctx.size() + idx + 1,
+                    false => idx,
+                };
+                if !unsafe { ::flu::ffi::lua_istable(ctx.handle, idx) } {
+                    return Err(::flu::stack::ReadError::new::<Self>(ctx, idx));
+                }
+                #(#checks)*
+                Ok(())
+            }
+        }
+
+        impl #size_generics ::flu::stack::Size for #name #ty_generics #size_where {
+            fn size() -> i32 {
+                1
+            }
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// `#[flu::userdata]` implements `flu::UserData` for a struct, exposing fields
+// marked `#[lua(get)]`/`#[lua(set)]`; `#[flu::methods]` on its impl block
+// exposes the methods and associated functions. Every userdata struct needs
+// a `#[flu::methods]` block, even an empty one.
+//
+//   #[flu::userdata(name = "Vec2")]   the Lua-side type name
+//   #[lua(get, set)]                  field accessors
+//   #[lua(rename = "key")]            a different Lua name for a field or fn
+//   #[lua(skip)]                      keep a fn out of Lua
+
+use proc_macro2::TokenStream as Tokens;
+use quote::{format_ident, quote};
+use syn::punctuated::Punctuated;
+use syn::{Attribute, FnArg, ImplItem, ItemImpl, ItemStruct, LitStr, Meta, ReturnType, Token, Type};
+
+struct LuaAttrs {
+    rename: Option<String>,
+    get: bool,
+    set: bool,
+    skip: bool,
+}
+
+// parses and removes the `#[lua(...)]` attributes, which are only markers
+fn take_lua_attrs(attrs: &mut Vec<Attribute>) -> syn::Result<LuaAttrs> {
+    let mut out = LuaAttrs { rename: None, get: false, set: false, skip: false };
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("lua")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                out.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("get") {
+                out.get = true;
+            } else if meta.path.is_ident("set") {
+                out.set = true;
+            } else if meta.path.is_ident("skip") {
+                out.skip = true;
+            } else {
+                return Err(meta.error("unknown lua attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    attrs.retain(|a| !a.path().is_ident("lua"));
+    Ok(out)
+}
+
+pub fn userdata(args: Tokens, mut item: ItemStruct) -> syn::Result<Tokens> {
+    let ident = item.ident.clone();
+    let mut name = ident.to_string();
+
+    let metas = syn::parse::Parser::parse2(Punctuated::<Meta, Token![,]>::parse_terminated, args)?;
+    for meta in metas {
+        match meta {
+            Meta::NameValue(ref nv) if nv.path.is_ident("name") => {
+                name = match nv.value {
+                    syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(ref s), .. }) => s.value(),
+                    _ => return Err(syn::Error::new_spanned(&nv.value, "expected a string")),
+                };
+            }
+            other => return Err(syn::Error::new_spanned(other, "unknown userdata option")),
+        }
+    }
+
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&item.generics, "userdata types can't be generic"));
+    }
+
+    let mut accessors = Vec::new();
+    for field in item.fields.iter_mut() {
+        let attrs = take_lua_attrs(&mut field.attrs)?;
+        let member = match field.ident {
+            Some(ref ident) => ident.clone(),
+            None => return Err(syn::Error::new_spanned(&*field, "only named fields can be exposed")),
+        };
+        let key = attrs.rename.unwrap_or_else(|| member.to_string());
+        let ty = &field.ty;
+
+        if attrs.get {
+            accessors.push(quote! {
+                registry.getter(#key, |ctx| {
+                    match ctx.check_userdata::<#ident>(1, #key) {
+                        Ok(this) => {
+                            ::flu::stack::Push::push(&this.#member, ctx);
+                            1
+                        }
+                        Err(e) => e,
+                    }
+                });
+            });
+        }
+        if attrs.set {
+            accessors.push(quote! {
+                registry.setter(#key, |ctx| {
+                    let mut this = match ctx.check_userdata_mut::<#ident>(1, #key) {
+                        Ok(this) => this,
+                        Err(e) => return e,
+                    };
+                    match ctx.check_arg::<#ty>(3, #key) {
+                        Ok(v) => this.#member = v,
+                        Err(e) => return e,
+                    }
+                    0
+                });
+            });
+        }
+    }
+
+    Ok(quote! {
+        #item
+
+        impl ::flu::UserData for #ident {
+            const NAME: &'static str = #name;
+
+            fn register(registry: &mut ::flu::UserDataRegistry<Self>) {
+                #(#accessors)*
+                <Self as ::flu::UserDataMethods>::methods(registry);
+            }
+        }
+    })
+}
+
+fn is_self(ty: &Type, ident: &syn::Ident) -> bool {
+    match *ty {
+        Type::Path(ref p) => p.qself.is_none() && (p.path.is_ident("Self") || p.path.is_ident(ident)),
+        _ => false,
+    }
+}
+
+// `&T` and `&mut T` arguments other than `&str` and slices are borrowed
+// userdata; returns the referenced type and whether it is mutable
+fn userdata_ref(ty: &Type) -> Option<(&Type, bool)> {
+    match *ty {
+        Type::Reference(ref r) => match *r.elem {
+            Type::Path(ref p) if p.path.is_ident("str") => None,
+            Type::Slice(..) => None,
+            ref elem => Some((elem, r.mutability.is_some())),
+        },
+        _ => None,
+    }
+}
+
+fn is_result(ty: &Type) -> bool {
+    match *ty {
+        Type::Path(ref p) => p.path.segments.last().map(|s| s.ident == "Result").unwrap_or(false),
+        _ => false,
+    }
+}
+
+pub fn methods(mut item: ItemImpl) -> syn::Result<Tokens> {
+    let ident = match *item.self_ty {
+        Type::Path(ref p) if item.trait_.is_none() && p.path.get_ident().is_some() => p.path.get_ident().unwrap().clone(),
+        _ => return Err(syn::Error::new_spanned(&item.self_ty, "#[flu::methods] goes on an inherent impl of a userdata struct")),
+    };
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&item.generics, "userdata types can't be generic"));
+    }
+
+    let mut registrations = Vec::new();
+
+    for impl_item in item.items.iter_mut() {
+        let method = match *impl_item {
+            ImplItem::Fn(ref mut method) => method,
+            _ => continue,
+        };
+
+        let attrs = take_lua_attrs(&mut method.attrs)?;
+        if attrs.skip {
+            continue;
+        }
+
+        let sig = &method.sig;
+        let fn_ident = &sig.ident;
+        let name = attrs.rename.unwrap_or_else(|| fn_ident.to_string());
+
+        let mut receiver = None;
+        let mut args = Vec::new();
+        let mut passed = Vec::new();
+
+        for input in &sig.inputs {
+            match *input {
+                FnArg::Receiver(ref r) => {
+                    if r.reference.is_none() {
+                        return Err(syn::Error::new_spanned(r, "userdata methods take `&self` or `&mut self`"));
+                    }
+                    receiver = Some(r.mutability.is_some());
+                }
+                FnArg::Typed(ref pat) => {
+                    let idx = args.len() as i32 + if receiver.is_some() { 2 } else { 1 };
+                    let arg = format_ident!("arg{}", args.len());
+                    let ty = &pat.ty;
+
+                    // userdata arguments are borrowed through guards, so a
+                    // conflicting borrow like `p:add(p)` raises an error
+                    match userdata_ref(ty) {
+                        Some((elem, true)) => {
+                            args.push(quote! {
+                                let mut #arg = match ctx.check_userdata_mut::<#elem>(#idx, #name) {
+                                    Ok(v) => v,
+                                    Err(e) => return e,
+                                };
+                            });
+                            passed.push(quote!(&mut *#arg));
+                        }
+                        Some((elem, false)) => {
+                            args.push(quote! {
+                                let #arg = match ctx.check_userdata::<#elem>(#idx, #name) {
+                                    Ok(v) => v,
+                                    Err(e) => return e,
+                                };
+                            });
+                            passed.push(quote!(&*#arg));
+                        }
+                        None => {
+                            args.push(quote! {
+                                let #arg = match ctx.check_arg::<#ty>(#idx, #name) {
+                                    Ok(v) => v,
+                                    Err(e) => return e,
+                                };
+                            });
+                            passed.push(quote!(#arg));
+                        }
+                    }
+                }
+            }
+        }
+
+        let call = match receiver {
+            Some(true) => quote! {
+                let mut this = match ctx.check_userdata_mut::<#ident>(1, #name) {
+                    Ok(this) => this,
+                    Err(e) => return e,
+                };
+                #(#args)*
+                let ret = this.#fn_ident(#(#passed),*);
+            },
+            Some(false) => quote! {
+                let this = match ctx.check_userdata::<#ident>(1, #name) {
+                    Ok(this) => this,
+                    Err(e) => return e,
+                };
+                #(#args)*
+                let ret = this.#fn_ident(#(#passed),*);
+            },
+            None => quote! {
+                #(#args)*
+                let ret = #ident::#fn_ident(#(#passed),*);
+            },
+        };
+
+        let ret = match sig.output {
+            ReturnType::Default => quote! {
+                let () = ret;
+                0
+            },
+            ReturnType::Type(_, ref ty) if is_self(ty, &ident) => quote! {
+                ctx.push_userdata(ret);
+                1
+            },
+            ReturnType::Type(_, ref ty) if is_result(ty) => quote! {
+                ::flu::CallbackReturn::ret(ret, ctx)
+            },
+            ReturnType::Type(_, ref ty) => quote! {
+                ctx.push(ret);
+                <#ty as ::flu::stack::Size>::size()
+            },
+        };
+
+        let register = match receiver {
+            Some(_) => quote!(method),
+            None => quote!(function),
+        };
+
+        registrations.push(quote! {
+            registry.#register(#name, |ctx| {
+                #call
+                #ret
+            });
+        });
+    }
+
+    Ok(quote! {
+        #item
+
+        impl ::flu::UserDataMethods for #ident {
+            fn methods(registry: &mut ::flu::UserDataRegistry<Self>) {
+                #(#registrations)*
+            }
+        }
+    })
+}
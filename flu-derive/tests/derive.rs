@@ -0,0 +1,111 @@
+#[macro_use]
+extern crate flu_derive;
+extern crate flu;
+
+use flu::Context;
+use flu::Table;
+
+#[derive(Debug, PartialEq, Push, Read)]
+struct Stats {
+    hp: i32,
+    #[lua(rename = "move-speed")]
+    speed: f64,
+}
+
+#[derive(Debug, PartialEq, Push, Read)]
+struct Position(f64, f64);
+
+fn default_level() -> i32 {
+    1
+}
+
+#[derive(Debug, PartialEq, Push, Read)]
+struct Enemy {
+    name: String,
+    #[lua(flatten)]
+    stats: Stats,
+    pos: Position,
+    #[lua(default = "default_level")]
+    level: i32,
+    #[lua(default)]
+    tags: Vec<String>,
+    #[lua(skip)]
+    cache: Option<String>,
+}
+
+#[test]
+fn round_trip() {
+    let ctx = Context::new();
+
+    let enemy = Enemy {
+        name: "rat".to_string(),
+        stats: Stats { hp: 3, speed: 1.5 },
+        pos: Position(2.0, 4.0),
+        level: 7,
+        tags: vec!["small".to_string()],
+        cache: Some("ignored".to_string()),
+    };
+
+    flu::stack::Push::push(&enemy, &ctx);
+    let table = ctx.peek::<Table>(-1);
+    assert_eq!(table.get::<i32, _>("hp"), 3);
+    assert_eq!(table.get::<f64, _>("move-speed"), 1.5);
+    assert_eq!(table.get::<Option<String>, _>("cache"), None);
+
+    let read = ctx.pop::<Enemy>();
+    assert_eq!(read, Enemy { cache: None, ..enemy });
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn defaults() {
+    let ctx = Context::new();
+
+    ctx.eval("return { name = 'bat', hp = 1, ['move-speed'] = 3, pos = { 0, 1 } }").unwrap();
+    let enemy = ctx.pop::<Enemy>();
+
+    assert_eq!(enemy.level, 1);
+    assert_eq!(enemy.tags, Vec::<String>::new());
+    assert_eq!(enemy.pos, Position(0.0, 1.0));
+}
+
+#[test]
+fn errors() {
+    let ctx = Context::new();
+
+    ctx.eval("return { name = 'bat', hp = 1, ['move-speed'] = 3, pos = { 0, 'up' } }").unwrap();
+    let err = ctx.try_pop::<Enemy>().unwrap_err();
+    assert_eq!(err.to_string(), "expected f64 at pos[2], found string");
+
+    ctx.eval("return { name = 'bat', ['move-speed'] = 3, pos = { 0, 1 } }").unwrap();
+    let err = ctx.try_pop::<Enemy>().unwrap_err();
+    assert_eq!(err.to_string(), "expected i32 at hp, found nil");
+
+    ctx.push(5);
+    assert_eq!(ctx.try_pop::<Stats>().unwrap_err().to_string(), "expected Stats, found number");
+    assert_eq!(ctx.size(), 0);
+}
+
+#[derive(Push)]
+struct Tagged<T> {
+    name: String,
+    #[lua(flatten)]
+    extra: T,
+}
+
+#[test]
+fn flatten_non_table() {
+    let ctx = Context::new();
+
+    flu::stack::Push::push(&Tagged { name: "bat".to_string(), extra: None::<Stats> }, &ctx);
+    let table = ctx.pop::<Table>();
+    assert_eq!(table.get::<String, _>("name"), "bat");
+    assert_eq!(table.get::<Option<i32>, _>("hp"), None);
+
+    let err = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        flu::stack::Push::push(&Tagged { name: "bat".to_string(), extra: 5 }, &ctx);
+    })).unwrap_err();
+    assert_eq!(err.downcast_ref::<String>().map(|s| &s[..]),
+               Some("can't flatten extra into Tagged: expected table at extra, found number"));
+    assert_eq!(ctx.size(), 0);
+}
@@ -0,0 +1,118 @@
+extern crate flu;
+
+use flu::Context;
+use flu::UserDataRef;
+
+use std::fmt;
+
+#[flu::userdata(name = "Vec2")]
+struct Point {
+    #[lua(get, set)]
+    x: f64,
+    #[lua(get, set)]
+    y: f64,
+    #[lua(get, rename = "label")]
+    name: String,
+    hidden: i32,
+}
+
+#[derive(Debug)]
+struct ZeroLength;
+
+impl fmt::Display for ZeroLength {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can't normalize a zero length vector")
+    }
+}
+
+impl std::error::Error for ZeroLength {}
+
+#[flu::methods]
+impl Point {
+    fn new(x: f64, y: f64) -> Self {
+        Point { x, y, name: "point".to_string(), hidden: 0 }
+    }
+
+    fn len(&self) -> f64 {
+        (self.x * self.x + self.y * self.y).sqrt()
+    }
+
+    fn scale(&mut self, factor: f64) {
+        self.x *= factor;
+        self.y *= factor;
+        self.hidden += 1;
+    }
+
+    fn dot(&self, other: &Point) -> f64 {
+        self.x * other.x + self.y * other.y
+    }
+
+    fn add(&mut self, other: &Point) {
+        self.x += other.x;
+        self.y += other.y;
+    }
+
+    #[lua(rename = "normalized")]
+    fn normalize(&self) -> Result<f64, ZeroLength> {
+        match self.len() {
+            0.0 => Err(ZeroLength),
+            len => Ok(len)
+        }
+    }
+
+    #[lua(skip)]
+    #[allow(dead_code)]
+    fn internal(&self) -> i32 {
+        self.hidden
+    }
+}
+
+#[test]
+fn methods_and_fields() {
+    let ctx = Context::new();
+    ctx.register::<Point>();
+
+    ctx.eval("p = Vec2.new(3, 4)\n\
+              p:scale(2)\n\
+              p.x = p.x + 1\n\
+              return p:len(), p:dot(Vec2.new(1, 0)), p.label, p.hidden, p.internal").unwrap();
+
+    assert_eq!(ctx.pop::<Option<i32>>(), None);
+    assert_eq!(ctx.pop::<Option<i32>>(), None);
+    assert_eq!(ctx.pop::<String>(), "point");
+    assert_eq!(ctx.pop::<f64>(), 7.0);
+    assert_eq!(ctx.pop::<f64>(), (49.0f64 + 64.0).sqrt());
+
+    ctx.eval("return p").unwrap();
+    assert_eq!(ctx.pop::<UserDataRef<Point>>().hidden, 1);
+
+    // shared borrows of the same value can overlap
+    ctx.eval("p:add(Vec2.new(-8, -8)) return p:dot(p)").unwrap();
+    assert_eq!(ctx.pop::<f64>(), 1.0);
+}
+
+#[test]
+fn errors() {
+    let ctx = Context::new();
+    ctx.register::<Point>();
+
+    ctx.eval("p = Vec2.new(0, 0)").unwrap();
+
+    let err = ctx.eval("p:scale('big')").unwrap_err();
+    assert_eq!(err.to_string(), "bad argument #2 to 'scale' (expected f64, found string)");
+
+    let err = ctx.eval("p.dot(5, p)").unwrap_err();
+    assert_eq!(err.to_string(), "bad argument #1 to 'dot' (expected Vec2, found number)");
+
+    // a mutable borrow can't overlap with another borrow of the same value
+    let err = ctx.eval("p:add(p)").unwrap_err();
+    assert_eq!(err.to_string(), "bad argument #2 to 'add' (Vec2 is already mutably borrowed)");
+    ctx.eval("p:scale(1)").unwrap();
+
+    let err = ctx.eval("p.label = 'x'").unwrap_err();
+    assert_eq!(err.to_string(), "cannot assign 'label' on Vec2");
+
+    let err = ctx.eval("p:normalized()").unwrap_err();
+    assert_eq!(err.to_string(), "can't normalize a zero length vector");
+    assert_eq!(ctx.size(), 0);
+}
@@ -11,6 +11,12 @@ pub struct LuaRef<'a> {
     key: i32,
 }
 
+impl<'a> LuaRef<'a> {
+    pub fn context(&self) -> &'a Context {
+        self.ctx
+    }
+}
+
 impl<'a> Drop for LuaRef<'a> {
     fn drop(&mut self) {
         unsafe {
@@ -19,37 +25,21 @@ impl<'a> Drop for LuaRef<'a> {
     }
 }
 
+// Reading a reference copies the value at `idx` into the registry with
+// `luaL_ref` and leaves the stack unchanged, so it works with `peek` and at
+// any index. `Size` is 1, so `pop` removes the slot afterwards.
 impl<'a> Read<'a> for LuaRef<'a> {
     fn read(ctx: &'a Context, idx: i32) -> Self {
         unsafe {
-            let t = ffi::LUA_REGISTRYINDEX;
-
-            match ffi::lua_isnil(ctx.handle, idx) {
-                true => {
-                    ctx.pop_discard(-1);
-                    LuaRef { ctx: ctx, key: -1 }
-                }
-                false => {
-                    ffi::lua_rawgeti(ctx.handle, t, ffi::FREELIST_REF);
-                    let r = match ctx.pop::<i32>() {
-                        0 => {
-                            ffi::lua_objlen(ctx.handle, t) as i32 + 1
-                        }
-                        a @ _ => {
-                            ffi::lua_rawgeti(ctx.handle, t, a);
-                            ffi::lua_rawseti(ctx.handle, t, ffi::FREELIST_REF);
-                            a
-                        }
-                    };
-                    ffi::lua_rawseti(ctx.handle, t, r);
-
-                    LuaRef { ctx: ctx, key: r }
-                }
-            }
+            // `luaL_ref` pops the copy, leaving the stack as it was
+            ffi::lua_pushvalue(ctx.handle, idx);
+            let key = ffi::luaL_ref(ctx.handle, ffi::LUA_REGISTRYINDEX);
+
+            LuaRef { ctx, key }
         }
     }
 
-    fn check(ctx: &'a Context, idx: i32) -> bool {
+    fn check(_ctx: &'a Context, _idx: i32) -> bool {
         true
     }
 }
@@ -64,7 +54,7 @@ impl<'a> Push for LuaRef<'a> {
 
 impl<'a> Size for LuaRef<'a> {
     fn size() -> i32 {
-        0
+        1
     }
 }
 
@@ -86,3 +76,31 @@ fn read_ref() {
         assert_eq!(ctx.pop::<&str>(), "Hello world!");
     }
 }
+
+#[test]
+fn peek_ref() {
+    use Function;
+
+    let ctx = Context::new();
+
+    ctx.eval("return function() return 'f' end, 'below', nil").unwrap();
+    assert_eq!(ctx.size(), 3);
+
+    // peeking below the top copies the value and leaves the stack as it was
+    let r = ctx.peek::<LuaRef>(-2);
+    assert_eq!(ctx.size(), 3);
+    ctx.push(r);
+    assert_eq!(ctx.pop::<&str>(), "below");
+
+    let nil = ctx.peek::<LuaRef>(-1);
+    ctx.push(nil);
+    assert!(ctx.pop::<Option<i32>>().is_none());
+
+    let func = ctx.peek::<Function>(-3);
+    assert_eq!(ctx.size(), 3);
+    assert_eq!(func.call::<(), String>(()).unwrap(), "f");
+    ctx.pop_discard(1);
+
+    ctx.pop_discard(3);
+    assert_eq!(ctx.size(), 0);
+}
@@ -0,0 +1,177 @@
+// The parts `json` and `msgpack` share: the error type, the walk over a table
+// being encoded, the stack handling while decoding and the script library.
+
+use Context;
+use LuaValue;
+use Table;
+use ffi;
+
+use pretty::{compare_keys, table_ptr};
+use stack::read::value_segment;
+
+use libc;
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    // `path` is the key path of the value, e.g. `items[2].name`
+    Encode { path: String, message: String },
+    Decode { position: Position, message: String },
+}
+
+// where decoding failed: a line and column for JSON, a byte offset for
+// MessagePack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Text { line: usize, column: usize },
+    Byte(usize),
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Position::Text { line, column } => write!(f, "line {} column {}", line, column),
+            Position::Byte(offset) => write!(f, "byte {}", offset),
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::Encode { ref path, ref message } => {
+                match path.is_empty() {
+                    true => write!(f, "{}", message),
+                    false => write!(f, "{} at {}", message, path.trim_start_matches('.'))
+                }
+            }
+            CodecError::Decode { position, ref message } => write!(f, "{} at {}", message, position),
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn description(&self) -> &str {
+        match *self {
+            CodecError::Encode { ref message, .. } |
+            CodecError::Decode { ref message, .. } => message,
+        }
+    }
+}
+
+// The key path of the value being encoded and the tables it is inside, so
+// cycles and deep nesting are errors rather than a stack overflow.
+pub(crate) struct Walk {
+    path: String,
+    ancestors: Vec<*const libc::c_void>,
+    max_depth: usize,
+}
+
+impl Walk {
+    pub fn new(max_depth: usize) -> Self {
+        Walk { path: String::new(), ancestors: Vec::new(), max_depth }
+    }
+
+    pub fn error(&self, message: &str) -> CodecError {
+        CodecError::Encode { path: self.path.clone(), message: message.to_string() }
+    }
+
+    // Calls `f` with the entries of `table` sorted by key and whether they
+    // form an array, i.e. the keys are exactly `1..n`. Empty tables are
+    // arrays when `empty_array` is set.
+    pub fn table<F>(&mut self, table: &Table, empty_array: bool, f: F) -> Result<(), CodecError>
+        where F: FnOnce(&mut Walk, Vec<(LuaValue, LuaValue)>, bool) -> Result<(), CodecError>
+    {
+        let ptr = table_ptr(table);
+        if self.ancestors.contains(&ptr) {
+            return Err(self.error("cannot encode a table that contains itself"));
+        }
+        if self.ancestors.len() >= self.max_depth {
+            return Err(self.error("tables are nested too deeply"));
+        }
+
+        let mut entries = table.entries();
+        entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+
+        let is_array = match entries.is_empty() {
+            true => empty_array,
+            false => entries.iter().enumerate().all(|(i, e)| e.0 == LuaValue::Number(i as f64 + 1.0))
+        };
+
+        self.ancestors.push(ptr);
+        f(self, entries, is_array)?;
+        self.ancestors.pop();
+        Ok(())
+    }
+
+    // calls `f` with `key` added to the path
+    pub fn key<F>(&mut self, key: &LuaValue, f: F) -> Result<(), CodecError>
+        where F: FnOnce(&mut Walk) -> Result<(), CodecError>
+    {
+        let len = self.path.len();
+        self.path.push_str(&value_segment(key));
+        f(self)?;
+        self.path.truncate(len);
+        Ok(())
+    }
+}
+
+// Runs a decoder that pushes one value, leaving the stack as it was if it
+// fails part way through a table.
+pub(crate) fn decode_push<F>(ctx: &Context, decode: F) -> Result<(), CodecError>
+    where F: FnOnce() -> Result<(), CodecError>
+{
+    let top = ctx.size();
+    let ret = decode();
+
+    if ret.is_err() {
+        unsafe {
+            ffi::lua_settop(ctx.handle, top);
+        }
+    }
+    ret
+}
+
+// Pushes a table for a decoder to fill, with room for a key and a value on
+// top. Returns `false` if the stack can't grow that far.
+pub(crate) fn push_table(ctx: &Context, narr: i32, nrec: i32) -> bool {
+    unsafe {
+        if ffi::lua_checkstack(ctx.handle, 3) == 0 {
+            return false;
+        }
+        ffi::lua_createtable(ctx.handle, narr, nrec);
+    }
+    true
+}
+
+// Sets the global `name` to a table with `encode(value)` and `decode(data)`,
+// which raise errors instead of returning them.
+pub(crate) fn open<E, D>(ctx: &Context, name: &str, encode: E, decode: D)
+    where E: Fn(&LuaValue) -> Result<Vec<u8>, CodecError> + Clone + 'static,
+          D: Fn(&Context, &[u8]) -> Result<(), CodecError> + Clone + 'static
+{
+    let lib = Table::new(ctx);
+
+    lib.set("encode", move |ctx: &mut Context| {
+        // scripts get the message rather than the boxed error
+        let value = ctx.peek::<LuaValue>(1);
+        encode(&value).map(LuaValue::Bytes).map_err(|e| e.to_string())
+    });
+
+    lib.set("decode", move |ctx: &mut Context| {
+        let data = match ctx.peek::<LuaValue>(1) {
+            LuaValue::String(s) => s.into_bytes(),
+            LuaValue::Bytes(b) => b,
+            other => return ctx.error(format!("bad argument #1 to 'decode' (string expected, got {})", other.type_name()))
+        };
+
+        match decode(ctx, &data) {
+            Ok(()) => 1,
+            Err(e) => ctx.error(e.to_string())
+        }
+    });
+
+    ctx.set(name, lib);
+}
@@ -1,40 +1,138 @@
-
 use Context;
+use LuaError;
 use ffi;
-use std::ffi::CString;
 
+use stack::Push;
+
+use libc;
+
+// Pushes the value stored under a key. `get`/`set` go through `__index` and
+// `__newindex` like indexing does in Lua, the `raw_` variants bypass them.
+// Metamethods run in a protected call, so an error they raise comes back as
+// `Err` with the stack as it was before. Any pushable value can be a key,
+// but storing under a `nil` or NaN key is `LuaError::InvalidKey`.
 pub trait LuaIndex {
-    fn get(&self, ctx: &Context, idx: i32);
-    fn set(&self, ctx: &Context, idx: i32);
+    fn get<'a>(&self, ctx: &'a Context, idx: i32) -> Result<(), LuaError<'a>>;
+    // pops the value on top of the stack
+    fn set<'a>(&self, ctx: &'a Context, idx: i32) -> Result<(), LuaError<'a>>;
+    fn raw_get(&self, ctx: &Context, idx: i32);
+    fn raw_set<'a>(&self, ctx: &'a Context, idx: i32) -> Result<(), LuaError<'a>>;
 }
 
-macro_rules! integer_index {
-    ($ty:ident) => (
-        impl LuaIndex for $ty {
-            fn get(&self, ctx: &Context, idx: i32) {
-                unsafe { ffi::lua_rawgeti(ctx.handle, idx, *self as i32) }
-            }
+// turns a relative index into one that stays valid after pushing
+fn absolute(ctx: &Context, idx: i32) -> i32 {
+    match idx < 0 && idx > ffi::LUA_REGISTRYINDEX {
+        true => ctx.size() + idx + 1,
+        false => idx
+    }
+}
 
-            fn set(&self, ctx: &Context, idx: i32) {
-                unsafe { ffi::lua_rawseti(ctx.handle, idx, *self as i32) }
-            }
+// Lua raises an error when storing under these keys
+pub fn invalid_key(ctx: &Context, idx: i32) -> Option<&'static str> {
+    unsafe {
+        match ffi::lua_type(ctx.handle, idx) {
+            ffi::LUA_TNIL | ffi::LUA_TNONE => Some("nil"),
+            ffi::LUA_TNUMBER if ffi::lua_tonumber(ctx.handle, idx).is_nan() => Some("NaN"),
+            _ => None
         }
-    )
+    }
+}
+
+// pushes the key under the value on top of the stack, or pops the value if
+// the key is invalid
+fn insert_key<'a, K: Push>(key: &K, ctx: &'a Context) -> Result<(), LuaError<'a>> {
+    key.push(ctx);
+
+    if let Some(key) = invalid_key(ctx, -1) {
+        ctx.pop_discard(2);
+        return Err(LuaError::InvalidKey(key));
+    }
+
+    unsafe {
+        ffi::lua_insert(ctx.handle, -2);
+    }
+    Ok(())
+}
+
+// plain tables can't raise errors on access, so they skip the protected call
+fn is_plain_table(ctx: &Context, idx: i32) -> bool {
+    unsafe {
+        if !ffi::lua_istable(ctx.handle, idx) {
+            return false;
+        }
+        if ffi::lua_getmetatable(ctx.handle, idx) == 0 {
+            return true;
+        }
+    }
+    ctx.pop_discard(1);
+    false
 }
 
-integer_index!(i32);
-integer_index!(usize);
+unsafe extern "C" fn protected_get(state: *mut ffi::lua_State) -> libc::c_int {
+    ffi::lua_gettable(state, 1);
+    1
+}
+
+unsafe extern "C" fn protected_set(state: *mut ffi::lua_State) -> libc::c_int {
+    ffi::lua_settable(state, 1);
+    0
+}
+
+impl<K> LuaIndex for K where K: Push {
+    fn get<'a>(&self, ctx: &'a Context, idx: i32) -> Result<(), LuaError<'a>> {
+        let idx = absolute(ctx, idx);
+        if is_plain_table(ctx, idx) {
+            self.raw_get(ctx, idx);
+            return Ok(());
+        }
+
+        unsafe {
+            ffi::lua_pushcfunction(ctx.handle, protected_get);
+            ffi::lua_pushvalue(ctx.handle, idx);
+            self.push(ctx);
+
+            match ffi::lua_pcall(ctx.handle, 2, 1, 0) {
+                0 => Ok(()),
+                err => Err(LuaError::pop(ctx, err))
+            }
+        }
+    }
+
+    fn set<'a>(&self, ctx: &'a Context, idx: i32) -> Result<(), LuaError<'a>> {
+        let idx = absolute(ctx, idx);
+        if is_plain_table(ctx, idx) {
+            return self.raw_set(ctx, idx);
+        }
+
+        insert_key(self, ctx)?;
+        unsafe {
+            // `protected_set` wants the table, key and value as arguments
+            ffi::lua_pushcfunction(ctx.handle, protected_set);
+            ffi::lua_pushvalue(ctx.handle, idx);
+            ffi::lua_insert(ctx.handle, -4);
+            ffi::lua_insert(ctx.handle, -4);
+
+            match ffi::lua_pcall(ctx.handle, 3, 0, 0) {
+                0 => Ok(()),
+                err => Err(LuaError::pop(ctx, err))
+            }
+        }
+    }
 
-impl<'a, 'b> LuaIndex for &'b str {
-    fn get(&self, ctx: &Context, idx: i32) {
+    fn raw_get(&self, ctx: &Context, idx: i32) {
+        let idx = absolute(ctx, idx);
+        self.push(ctx);
         unsafe {
-            ffi::lua_getfield(ctx.handle, idx, unsafe { CString::new(*self).unwrap().as_ptr() as _ })
+            ffi::lua_rawget(ctx.handle, idx)
         }
     }
 
-    fn set(&self, ctx: &Context, idx: i32) {
+    fn raw_set<'a>(&self, ctx: &'a Context, idx: i32) -> Result<(), LuaError<'a>> {
+        let idx = absolute(ctx, idx);
+        insert_key(self, ctx)?;
         unsafe {
-            ffi::lua_setfield(ctx.handle, idx, unsafe { CString::new(*self).unwrap().as_ptr() as _ })
+            ffi::lua_rawset(ctx.handle, idx)
         }
+        Ok(())
     }
 }
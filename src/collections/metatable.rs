@@ -0,0 +1,97 @@
+use Context;
+use Table;
+
+use super::LuaIndex;
+
+use stack::Push;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MetaMethod {
+    Index,
+    NewIndex,
+    Call,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Unm,
+    Concat,
+    Len,
+    Eq,
+    Lt,
+    Le,
+    ToString,
+    Gc,
+    Mode,
+    Metatable,
+}
+
+impl MetaMethod {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MetaMethod::Index => "__index",
+            MetaMethod::NewIndex => "__newindex",
+            MetaMethod::Call => "__call",
+            MetaMethod::Add => "__add",
+            MetaMethod::Sub => "__sub",
+            MetaMethod::Mul => "__mul",
+            MetaMethod::Div => "__div",
+            MetaMethod::Mod => "__mod",
+            MetaMethod::Pow => "__pow",
+            MetaMethod::Unm => "__unm",
+            MetaMethod::Concat => "__concat",
+            MetaMethod::Len => "__len",
+            MetaMethod::Eq => "__eq",
+            MetaMethod::Lt => "__lt",
+            MetaMethod::Le => "__le",
+            MetaMethod::ToString => "__tostring",
+            MetaMethod::Gc => "__gc",
+            MetaMethod::Mode => "__mode",
+            MetaMethod::Metatable => "__metatable",
+        }
+    }
+}
+
+impl Push for MetaMethod {
+    fn push(&self, ctx: &Context) {
+        self.name().push(ctx)
+    }
+}
+
+// Builds a metatable whose entries are usually Rust callbacks:
+//
+//     let mt = MetatableBuilder::new(&ctx)
+//         .method(MetaMethod::Add, |ctx: &mut Context| { ... })
+//         .set(MetaMethod::Index, defaults)
+//         .build();
+//     table.set_metatable(Some(&mt));
+pub struct MetatableBuilder<'a> {
+    table: Table<'a>,
+}
+
+impl<'a> MetatableBuilder<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        MetatableBuilder { table: Table::new(ctx) }
+    }
+
+    pub fn method<F>(self, method: MetaMethod, func: F) -> Self
+        where F: Push
+    {
+        self.table.set(method, func);
+        self
+    }
+
+    pub fn set<K, T>(self, key: K, val: T) -> Self
+        where K: LuaIndex,
+              T: Push
+    {
+        self.table.set(key, val);
+        self
+    }
+
+    pub fn build(self) -> Table<'a> {
+        self.table
+    }
+}
@@ -1,5 +1,9 @@
 mod table;
 mod index;
+mod metatable;
 
 pub use self::table::Table;
 pub use self::index::LuaIndex;
+#[cfg(feature = "serde")]
+pub(crate) use self::index::invalid_key;
+pub use self::metatable::{MetaMethod, MetatableBuilder};
@@ -1,4 +1,5 @@
 use Context;
+use LuaError;
 use LuaValue;
 use LuaRef;
 use ffi;
@@ -7,14 +8,18 @@ use nil;
 use super::LuaIndex;
 
 use stack::Read;
+use stack::ReadError;
 use stack::Push;
 use stack::Size;
 
+use std::cmp::Ordering;
 use std::marker::PhantomData;
 use std::mem;
 use std::hash::Hash;
 use std::collections::HashMap;
 
+use libc;
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Table<'a> {
     pub ctx: &'a Context,
@@ -27,7 +32,7 @@ impl<'a> Table<'a> {
             ffi::lua_newtable(ctx.handle);
         }
 
-        Table { ctx: ctx, ptr: LuaRef::read(ctx, -1) }
+        Table { ctx, ptr: ctx.pop::<LuaRef>() }
     }
 
     pub fn from_map<K, V>(ctx: &'a Context, map: &HashMap<K, V>) -> Self
@@ -35,75 +40,178 @@ impl<'a> Table<'a> {
               V: Push + Size
     {
         unsafe {
-            ffi::lua_newtable(ctx.handle);
+            ffi::lua_createtable(ctx.handle, 0, map.len() as i32);
         }
 
         for (k, v) in map.iter() {
             v.push(ctx);
-            k.set(ctx, ctx.size() - V::size());
+            if let Err(err) = k.raw_set(ctx, ctx.size() - V::size()) {
+                ctx.pop_discard(1);
+                panic!("{}", err);
+            }
         }
 
-        Table { ctx: ctx, ptr: LuaRef::read(ctx, -1) }
+        Table { ctx, ptr: ctx.pop::<LuaRef>() }
     }
 
-    pub fn from_vec<V>(ctx: &'a Context, vec: &Vec<V>) -> Self
+    pub fn from_vec<V>(ctx: &'a Context, vec: &[V]) -> Self
         where V: Push + Size
     {
         unsafe {
-            ffi::lua_newtable(ctx.handle);
+            ffi::lua_createtable(ctx.handle, vec.len() as i32, 0);
         }
 
-        for (k, v) in vec.iter().enumerate() {
+        for (i, v) in vec.iter().enumerate() {
             v.push(ctx);
-            (k + 1).set(ctx, ctx.size() - V::size());
+            unsafe {
+                ffi::lua_rawseti(ctx.handle, -2, i as i32 + 1);
+            }
         }
 
-        Table { ctx: ctx, ptr: LuaRef::read(ctx, -1) }
+        Table { ctx, ptr: ctx.pop::<LuaRef>() }
     }
 
+    // panics if `__index` raises an error, see `try_get`
     pub fn get<T, K>(&self, idx: K) -> T
         where T: Read<'a> + Size,
               K: LuaIndex
+    {
+        match self.try_get(idx) {
+            Ok(val) => val,
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    pub fn try_get<T, K>(&self, idx: K) -> Result<T, LuaError<'a>>
+        where T: Read<'a> + Size,
+              K: LuaIndex
     {
         self.ptr.push(self.ctx);
 
-        idx.get(self.ctx, -1);
+        let ret = idx.get(self.ctx, -1).map(|_| self.ctx.pop::<T>());
+        self.ctx.pop_discard(1);
+        ret
+    }
+
+    // panics on a `nil` or NaN key or if `__newindex` raises an error, see
+    // `try_set`
+    pub fn set<T, K>(&self, idx: K, val: T)
+        where T: Push,
+              K: LuaIndex
+    {
+        if let Err(err) = self.try_set(idx, val) {
+            panic!("{}", err);
+        }
+    }
+
+    pub fn try_set<T, K>(&self, idx: K, val: T) -> Result<(), LuaError<'a>>
+        where T: Push,
+              K: LuaIndex
+    {
+        self.ptr.push(self.ctx);
+        self.ctx.push(val);
+
+        let ret = idx.set(self.ctx, -2);
+        self.ctx.pop_discard(1);
+        ret
+    }
+
+    pub fn raw_get<T, K>(&self, idx: K) -> T
+        where T: Read<'a> + Size,
+              K: LuaIndex
+    {
+        self.ptr.push(self.ctx);
+
+        idx.raw_get(self.ctx, -1);
 
         let ret = self.ctx.pop::<T>();
         self.ctx.pop_discard(1);
         ret
     }
 
-    pub fn set<T, K>(&'a self, idx: K, val: T)
+    // panics on a `nil` or NaN key
+    pub fn raw_set<T, K>(&self, idx: K, val: T)
         where T: Push,
               K: LuaIndex
     {
         self.ptr.push(self.ctx);
         self.ctx.push(val);
 
-        idx.set(self.ctx, -2);
-        self.ctx.dump();
-
+        let ret = idx.raw_set(self.ctx, -2);
         self.ctx.pop_discard(1);
+
+        if let Err(err) = ret {
+            panic!("{}", err);
+        }
     }
 
-    pub fn iter<T>(&self) -> TableIterator<T>
+    pub fn iter<T>(&self) -> Pairs<'a, '_, LuaValue<'a>, T>
         where T: Read<'a> + Size
     {
-        self.ptr.push(self.ctx);
+        self.pairs()
+    }
 
-        unsafe {
-            ffi::lua_pushnil(self.ctx.handle);
+    // Walks the table with `lua_next`. The first key or value that can't be
+    // read as `K` or `V` is yielded as an error and ends the iteration.
+    pub fn pairs<K, V>(&self) -> Pairs<'a, '_, K, V>
+        where K: Read<'a> + Size,
+              V: Read<'a> + Size
+    {
+        Pairs {
+            table: self,
+            key: None,
+            done: false,
+            _pd: PhantomData
         }
+    }
 
-        // TODO: make Context immutable during iter borrow
-        TableIterator {
-            ctx: self.ctx,
+    // every key and value, which unlike `pairs` can't fail
+    pub fn entries(&self) -> Vec<(LuaValue<'a>, LuaValue<'a>)> {
+        self.pairs().map(|e| e.expect("any value reads as a LuaValue")).collect()
+    }
+
+    // `ipairs`-style iteration over `t[1]`, `t[2]`, ... up to the first nil;
+    // a value that can't be read as `V` is yielded as an error and ends it
+    pub fn sequence<V>(&self) -> Sequence<'a, '_, V>
+        where V: Read<'a> + Size
+    {
+        Sequence {
+            table: self,
+            idx: 0,
+            done: false,
             _pd: PhantomData
         }
     }
 
-    pub fn len(&self) -> usize {
+    pub fn metatable(&self) -> Option<Table<'a>> {
+        self.ptr.push(self.ctx);
+
+        let mt = unsafe {
+            match ffi::lua_getmetatable(self.ctx.handle, -1) {
+                0 => None,
+                _ => Some(self.ctx.pop::<Table>())
+            }
+        };
+
+        self.ctx.pop_discard(1);
+        mt
+    }
+
+    pub fn set_metatable(&self, mt: Option<&Table>) {
+        self.ptr.push(self.ctx);
+        match mt {
+            Some(mt) => mt.ptr.push(self.ctx),
+            None => self.ctx.push(nil),
+        }
+
+        unsafe {
+            ffi::lua_setmetatable(self.ctx.handle, -2);
+        }
+        self.ctx.pop_discard(1);
+    }
+
+    // the length of the table itself, without `__len`
+    pub fn raw_len(&self) -> usize {
         self.ptr.push(self.ctx);
         let len = unsafe {
             ffi::lua_objlen(self.ctx.handle, -1) as usize
@@ -111,11 +219,256 @@ impl<'a> Table<'a> {
         self.ctx.pop_discard(1);
         len
     }
+
+    // Lua 5.1 ignores `__len` on tables, so this is the same as `raw_len`
+    pub fn len(&self) -> usize {
+        self.raw_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw_len() == 0
+    }
+
+    // The sequence helpers below use raw access like the `table` library
+    // does, so they work without it being loaded.
+
+    pub fn push<T>(&self, val: T)
+        where T: Push
+    {
+        let len = self.raw_len();
+        self.raw_set(len + 1, val);
+    }
+
+    // removes the last value, `None` when the table is empty
+    pub fn pop<T>(&self) -> Option<T>
+        where T: Read<'a> + Size
+    {
+        let len = self.raw_len();
+        self.remove(len)
+    }
+
+    // Shifts `pos..len` up, `pos` may be one past the end. Hands `val` back
+    // when `pos` is out of bounds.
+    pub fn insert<T>(&self, pos: usize, val: T) -> Result<(), T>
+        where T: Push
+    {
+        let len = self.raw_len();
+        if pos < 1 || pos > len + 1 {
+            return Err(val);
+        }
+
+        self.ptr.push(self.ctx);
+        unsafe {
+            for i in (pos..len + 1).rev() {
+                ffi::lua_rawgeti(self.ctx.handle, -1, i as i32);
+                ffi::lua_rawseti(self.ctx.handle, -2, i as i32 + 1);
+            }
+        }
+        self.ctx.pop_discard(1);
+
+        self.raw_set(pos, val);
+        Ok(())
+    }
+
+    // shifts `pos + 1..len` down and returns the removed value, `None` when
+    // `pos` is out of bounds
+    pub fn remove<T>(&self, pos: usize) -> Option<T>
+        where T: Read<'a> + Size
+    {
+        let len = self.raw_len();
+        if pos < 1 || pos > len {
+            return None;
+        }
+
+        self.ptr.push(self.ctx);
+        unsafe {
+            ffi::lua_rawgeti(self.ctx.handle, -1, pos as i32);
+            ffi::lua_insert(self.ctx.handle, -2);
+            for i in pos..len {
+                ffi::lua_rawgeti(self.ctx.handle, -1, i as i32 + 1);
+                ffi::lua_rawseti(self.ctx.handle, -2, i as i32);
+            }
+            ffi::lua_pushnil(self.ctx.handle);
+            ffi::lua_rawseti(self.ctx.handle, -2, len as i32);
+        }
+        self.ctx.pop_discard(1);
+
+        Some(self.ctx.pop::<T>())
+    }
+
+    // only looks at the table itself, not at `__index`
+    pub fn contains_key<K>(&self, key: K) -> bool
+        where K: LuaIndex
+    {
+        self.ptr.push(self.ctx);
+        key.raw_get(self.ctx, -1);
+
+        let found = unsafe {
+            !ffi::lua_isnil(self.ctx.handle, -1)
+        };
+        self.ctx.pop_discard(2);
+        found
+    }
+
+    pub fn clear(&self) {
+        self.ptr.push(self.ctx);
+
+        unsafe {
+            ffi::lua_pushnil(self.ctx.handle);
+            while ffi::lua_next(self.ctx.handle, -2) != 0 {
+                // assigning nil to existing fields is allowed while traversing
+                self.ctx.pop_discard(1);
+                ffi::lua_pushvalue(self.ctx.handle, -1);
+                ffi::lua_pushnil(self.ctx.handle);
+                ffi::lua_rawset(self.ctx.handle, -4);
+            }
+        }
+
+        self.ctx.pop_discard(1);
+    }
+
+    pub fn keys<K>(&self) -> Result<Vec<K>, ReadError>
+        where K: Read<'a> + Size
+    {
+        self.pairs::<K, LuaRef>().map(|e| e.map(|(k, _)| k)).collect()
+    }
+
+    pub fn values<V>(&self) -> Result<Vec<V>, ReadError>
+        where V: Read<'a> + Size
+    {
+        self.pairs::<LuaRef, V>().map(|e| e.map(|(_, v)| v)).collect()
+    }
+
+    // Sorts the sequence with Lua's `<`. The whole sort runs in one
+    // protected call, so values Lua can't compare or an erroring `__lt` come
+    // back as `Err` with the table unchanged.
+    pub fn sort(&self) -> Result<(), LuaError<'a>> {
+        unsafe {
+            ffi::lua_pushcfunction(self.ctx.handle, protected_sort);
+        }
+        self.ptr.push(self.ctx);
+
+        unsafe {
+            match ffi::lua_pcall(self.ctx.handle, 1, 0, 0) {
+                0 => Ok(()),
+                err => Err(LuaError::pop(self.ctx, err))
+            }
+        }
+    }
+
+    // fails without changing the table if a value doesn't read as `T`
+    pub fn sort_by<T, F>(&self, mut compare: F) -> Result<(), ReadError>
+        where T: Read<'a> + Size + Push,
+              F: FnMut(&T, &T) -> Ordering
+    {
+        let mut values: Vec<T> = self.sequence().collect::<Result<_, _>>()?;
+
+        values.sort_by(|a, b| compare(a, b));
+        self.replace_sequence(values);
+        Ok(())
+    }
+
+    fn replace_sequence<T>(&self, values: Vec<T>)
+        where T: Push
+    {
+        for (i, v) in values.into_iter().enumerate() {
+            self.ptr.push(self.ctx);
+            self.ctx.push(v);
+            unsafe {
+                ffi::lua_rawseti(self.ctx.handle, -2, i as i32 + 1);
+            }
+            self.ctx.pop_discard(1);
+        }
+    }
+
+    // like `table.concat`, fails on values that aren't strings or numbers
+    pub fn concat(&self, sep: &str) -> Result<String, ReadError> {
+        let mut out = String::new();
+
+        self.ptr.push(self.ctx);
+        for i in 1..self.raw_len() + 1 {
+            unsafe {
+                ffi::lua_rawgeti(self.ctx.handle, -1, i as i32);
+                if ffi::lua_isstring(self.ctx.handle, -1) == 0 {
+                    let err = ReadError::new::<String>(self.ctx, -1).at_index(i);
+                    self.ctx.pop_discard(2);
+                    return Err(err);
+                }
+            }
+
+            if i > 1 {
+                out.push_str(sep);
+            }
+            out.push_str(&self.ctx.pop::<String>());
+        }
+        self.ctx.pop_discard(1);
+
+        Ok(out)
+    }
+}
+
+// Sorts the sequence of the table at 1 with `lua_lessthan`, which may raise.
+// A bottom-up merge sort between two scratch tables, so it is stable, takes
+// O(n log n) comparisons and only writes the table once every comparison has
+// succeeded. Nothing here needs dropping if a comparison raises.
+unsafe extern "C" fn protected_sort(state: *mut ffi::lua_State) -> libc::c_int {
+    let n = ffi::lua_objlen(state, 1) as i32;
+    ffi::lua_createtable(state, n, 0);
+    ffi::lua_createtable(state, n, 0);
+
+    for i in 1..n + 1 {
+        ffi::lua_rawgeti(state, 1, i);
+        ffi::lua_rawseti(state, 2, i);
+    }
+
+    // `src` holds runs of `width` sorted values, merged pairwise into `dst`
+    let (mut src, mut dst) = (2, 3);
+    let mut width = 1;
+    while width < n {
+        let mut lo = 1;
+        while lo <= n {
+            let mid = (lo + width).min(n + 1);
+            let hi = (lo + 2 * width).min(n + 1);
+            let (mut i, mut j) = (lo, mid);
+
+            for k in lo..hi {
+                // takes from the left run unless the right value is smaller,
+                // which keeps equal values in order
+                let take_right = j < hi && (i == mid || less_than(state, src, j, i));
+                let from = match take_right {
+                    true => &mut j,
+                    false => &mut i
+                };
+                ffi::lua_rawgeti(state, src, *from);
+                ffi::lua_rawseti(state, dst, k);
+                *from += 1;
+            }
+
+            lo = hi;
+        }
+
+        mem::swap(&mut src, &mut dst);
+        width *= 2;
+    }
+
+    for i in 1..n + 1 {
+        ffi::lua_rawgeti(state, src, i);
+        ffi::lua_rawseti(state, 1, i);
+    }
+    0
+}
+
+unsafe fn less_than(state: *mut ffi::lua_State, table: i32, a: i32, b: i32) -> bool {
+    ffi::lua_rawgeti(state, table, a);
+    ffi::lua_rawgeti(state, table, b);
+    let less = ffi::lua_lessthan(state, -2, -1) != 0;
+    ffi::lua_settop(state, -3);
+    less
 }
 
 impl<'a> Read<'a> for Table<'a> {
     fn read(ctx: &'a Context, idx: i32) -> Self {
-        Table { ctx: ctx, ptr: LuaRef::read(ctx, idx) }
+        Table { ctx, ptr: LuaRef::read(ctx, idx) }
     }
 
     fn check(ctx: &'a Context, idx: i32) -> bool {
@@ -137,32 +490,91 @@ impl<'a> Size for Table<'a> {
     }
 }
 
-pub struct TableIterator<'a, T> {
-    ctx: &'a Context,
-    _pd: PhantomData<T>
+// The current key lives in the registry between steps rather than on the
+// stack, so the stack is free to use inside a loop and left untouched when
+// iteration stops early.
+pub struct Pairs<'a: 'b, 'b, K, V> {
+    table: &'b Table<'a>,
+    key: Option<LuaRef<'a>>,
+    done: bool,
+    _pd: PhantomData<(K, V)>
 }
 
-impl<'a, T> Iterator for TableIterator<'a, T>
-        where T: Read<'a> + Size
+impl<'a, 'b, K, V> Iterator for Pairs<'a, 'b, K, V>
+        where K: Read<'a> + Size,
+              V: Read<'a> + Size
     {
-    type Item = (LuaValue<'a>, T);
+    type Item = Result<(K, V), ReadError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let ctx = self.table.ctx;
+        if self.done {
+            return None;
+        }
+
+        self.table.ptr.push(ctx);
+        match self.key.take() {
+            Some(key) => key.push(ctx),
+            None => ctx.push(nil)
+        }
+
         unsafe {
-            match ffi::lua_next(self.ctx.handle, -2) > 0 {
-                true => {
-                    ffi::lua_pushvalue(self.ctx.handle, -2);
-                    let k = self.ctx.peek::<LuaValue>(-1);
-                    let v = self.ctx.peek::<T>(-2);
-                    self.ctx.pop_discard(2);
+            if ffi::lua_next(ctx.handle, -2) == 0 {
+                ctx.pop_discard(1);
+                self.done = true;
+                return None;
+            }
 
-                    Some((k, v))
-                }
+            self.key = Some(LuaRef::read(ctx, -2));
+
+            // read a copy so converting the key can't confuse `lua_next`
+            ffi::lua_pushvalue(ctx.handle, -2);
+            let item = K::validate(ctx, -1)
+                .and_then(|_| V::validate(ctx, -2).map_err(|e| e.at_key(ctx, -1)))
+                .map(|_| (ctx.peek::<K>(-1), ctx.peek::<V>(-2)));
+            ctx.pop_discard(4);
+
+            self.done = item.is_err();
+            Some(item)
+        }
+    }
+}
+
+pub struct Sequence<'a: 'b, 'b, V> {
+    table: &'b Table<'a>,
+    idx: i32,
+    done: bool,
+    _pd: PhantomData<V>
+}
+
+impl<'a, 'b, V> Iterator for Sequence<'a, 'b, V>
+        where V: Read<'a> + Size
+    {
+    type Item = Result<V, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ctx = self.table.ctx;
+        if self.done {
+            return None;
+        }
+
+        self.table.ptr.push(ctx);
+        unsafe {
+            ffi::lua_rawgeti(ctx.handle, -1, self.idx + 1);
+
+            let ret = match ffi::lua_isnil(ctx.handle, -1) {
+                true => None,
                 false => {
-                    self.ctx.pop_discard(1);
-                    None
+                    self.idx += 1;
+                    Some(V::validate(ctx, -1)
+                        .map(|_| ctx.peek::<V>(-1))
+                        .map_err(|e| e.at_index(self.idx as usize)))
                 }
-            }
+            };
+            ctx.pop_discard(2);
+
+            self.done = ret.as_ref().map(|r| r.is_err()).unwrap_or(true);
+            ret
         }
     }
 }
@@ -205,14 +617,115 @@ fn iter() {
     table.set(2, 15);
     table.set("woop", false);
 
-    assert_eq!(table.iter::<LuaValue>().collect::<Vec<(LuaValue, LuaValue)>>(), vec![
+    assert_eq!(table.entries(), vec![
         (LuaValue::Number(1f64), LuaValue::Number(5f64)),
         (LuaValue::Number(2f64), LuaValue::Number(15f64)),
-        (LuaValue::String("woop"), LuaValue::Bool(false)),
+        (LuaValue::String("woop".to_string()), LuaValue::Bool(false)),
     ]);
     assert_eq!(ctx.size(), 0);
 }
 
+#[test]
+fn metatable() {
+    use MetaMethod;
+    use MetatableBuilder;
+
+    let ctx = Context::new();
+
+    let defaults = Table::new(&ctx);
+    defaults.set("hp", 100);
+
+    let mt = MetatableBuilder::new(&ctx)
+        .set(MetaMethod::Index, defaults)
+        .method(MetaMethod::Add, |ctx: &mut Context| {
+            let b = ctx.pop::<Table>().get::<f64, _>("hp");
+            let a = ctx.pop::<Table>().get::<f64, _>("hp");
+            Ok::<_, String>(a + b)
+        })
+        .build();
+
+    let player = Table::new(&ctx);
+    assert!(player.metatable().is_none());
+
+    player.set_metatable(Some(&mt));
+    assert_eq!(player.get::<i32, _>("hp"), 100);
+    assert_eq!(player.metatable().unwrap().get::<Option<i32>, _>("hp"), None);
+
+    let enemy = Table::new(&ctx);
+    enemy.set("hp", 20);
+    enemy.set_metatable(Some(&mt));
+
+    ctx.set("player", player);
+    ctx.set("enemy", enemy);
+    ctx.eval("total = player + enemy").unwrap();
+    assert_eq!(ctx.get::<f64>("total"), 120f64);
+
+    ctx.get::<Table>("player").set_metatable(None);
+    assert_eq!(ctx.get::<Table>("player").get::<Option<i32>, _>("hp"), None);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn raw_access() {
+    let ctx = Context::new();
+
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.eval("log = {}\n\
+              proxy = setmetatable({}, {\n\
+                  __index = function(t, k) return 'default' end,\n\
+                  __newindex = function(t, k, v) log[#log + 1] = k rawset(t, k, v) end,\n\
+              })").unwrap();
+
+    let proxy = ctx.get::<Table>("proxy");
+    let log = ctx.get::<Table>("log");
+
+    assert_eq!(proxy.get::<&str, _>(1), "default");
+    assert_eq!(proxy.get::<&str, _>("name"), "default");
+    assert_eq!(proxy.raw_get::<Option<&str>, _>(1), None);
+    assert_eq!(proxy.raw_get::<Option<&str>, _>("name"), None);
+
+    proxy.set(1, "one");
+    proxy.set("name", "flu");
+    assert_eq!(log.len(), 2);
+    assert_eq!(log.get::<i32, _>(1), 1);
+    assert_eq!(log.get::<&str, _>(2), "name");
+
+    proxy.raw_set(2, "two");
+    proxy.raw_set("kind", "proxy");
+    assert_eq!(log.len(), 2);
+    assert_eq!(proxy.raw_get::<&str, _>(2), "two");
+    assert_eq!(proxy.get::<&str, _>("kind"), "proxy");
+    assert_eq!(proxy.raw_len(), 2);
+    assert_eq!(proxy.len(), 2);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn metamethod_errors() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.eval("strict = setmetatable({}, {\n\
+                  __index = function(t, k) error('no field ' .. k, 0) end,\n\
+                  __newindex = function(t, k, v) error({ key = k }) end,\n\
+              })").unwrap();
+    let strict = ctx.get::<Table>("strict");
+
+    let err = strict.try_get::<i32, _>("hp").unwrap_err();
+    assert_eq!(err.to_string(), "no field hp");
+    assert_eq!(ctx.size(), 0);
+
+    let err = strict.try_set("hp", 1).unwrap_err();
+    assert_eq!(err.table().unwrap().get::<String, _>("key"), "hp");
+    assert_eq!(strict.raw_get::<Option<i32>, _>("hp"), None);
+    assert_eq!(ctx.size(), 0);
+
+    let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| strict.get::<i32, _>(1)));
+    assert_eq!(panicked.unwrap_err().downcast_ref::<String>().unwrap(), "no field 1");
+    assert_eq!(ctx.size(), 0);
+}
+
 #[test]
 fn from_map() {
     let ctx = Context::new();
@@ -245,3 +758,203 @@ fn from_vec() {
     assert_eq!(table.get::<i32, _>(3), 6);
     assert_eq!(table.get::<i32, _>(4), 8);
 }
+
+#[test]
+fn key_types() {
+    let ctx = Context::new();
+
+    let table = Table::new(&ctx);
+
+    table.set(String::from("name"), "flu");
+    table.set(1i64 << 40, 1);
+    table.set(2.5f64, 2);
+    table.set(true, 3);
+
+    assert_eq!(table.get::<String, _>("name"), "flu");
+    assert_eq!(table.get::<i32, _>(1i64 << 40), 1);
+    assert_eq!(table.raw_get::<i32, _>(2.5f64), 2);
+    assert_eq!(table.get::<i32, _>(true), 3);
+    assert_eq!(table.get::<Option<i32>, _>(nil), None);
+
+    assert_eq!(table.try_set(nil, 5).unwrap_err().to_string(), "table index is nil");
+    assert_eq!(table.try_set(f64::NAN, 5).unwrap_err().to_string(), "table index is NaN");
+    assert!(table.try_set(3, 5).is_ok());
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn nil_key() {
+    let ctx = Context::new();
+
+    let table = Table::new(&ctx);
+    let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| table.set(nil, 1)));
+    assert_eq!(panicked.unwrap_err().downcast_ref::<String>().unwrap(), "table index is nil");
+
+    // nothing is left behind on the stack
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn pairs() {
+    let ctx = Context::new();
+
+    let table = Table::new(&ctx);
+
+    table.set("a", 1);
+    table.set("b", 2);
+    table.set(1, 3);
+    table.set("c", "three");
+
+    // the number key reads as a string, but "three" isn't an i32
+    let err = table.pairs::<String, i32>().find(|e| e.is_err()).unwrap().unwrap_err();
+    assert_eq!(err.to_string(), "expected i32 at c, found string");
+    assert_eq!(ctx.size(), 0);
+
+    // and the mismatch ends the iteration
+    let results: Vec<_> = table.pairs::<String, i32>().collect();
+    assert!(results.last().unwrap().is_err());
+    assert_eq!(results.iter().filter(|e| e.is_err()).count(), 1);
+
+    table.set("c", 4);
+    let mut entries = table.pairs::<String, i32>().collect::<Result<Vec<_>, _>>().unwrap();
+    entries.sort();
+    assert_eq!(entries, vec![("1".to_string(), 3), ("a".to_string(), 1), ("b".to_string(), 2), ("c".to_string(), 4)]);
+    assert_eq!(ctx.size(), 0);
+
+    // using the stack inside the loop doesn't disturb the traversal
+    for entry in table.pairs::<String, i32>().take(2) {
+        ctx.push(entry.unwrap().1);
+        ctx.pop_discard(1);
+    }
+    assert_eq!(ctx.size(), 0);
+    assert_eq!(table.pairs::<LuaValue, LuaValue>().count(), 4);
+}
+
+#[test]
+fn sequence() {
+    let ctx = Context::new();
+
+    let table = Table::from_vec(&ctx, &[1, 2, 3]);
+    table.set(5, 5);
+
+    assert_eq!(table.sequence::<i32>().collect::<Result<Vec<_>, _>>().unwrap(), vec![1, 2, 3]);
+    assert_eq!(table.sequence::<i32>().take(1).count(), 1);
+
+    table.set(2, "two");
+    let results: Vec<_> = table.sequence::<i32>().collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[1].as_ref().unwrap_err().to_string(), "expected i32 at [2], found string");
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn sequence_mutation() {
+    let ctx = Context::new();
+
+    let table = Table::new(&ctx);
+    table.push(1);
+    table.push(3);
+    table.insert(2, 2).unwrap();
+    table.insert(1, 0).unwrap();
+    assert_eq!(table.insert(6, 9), Err(9));
+    assert_eq!(table.insert(0, 9), Err(9));
+    assert_eq!(table.sequence::<i32>().collect::<Result<Vec<_>, _>>().unwrap(), vec![0, 1, 2, 3]);
+
+    assert_eq!(table.pop::<i32>(), Some(3));
+    assert_eq!(table.remove::<i32>(1), Some(0));
+    assert_eq!(table.remove::<i32>(3), None);
+    assert_eq!(table.sequence::<i32>().collect::<Result<Vec<_>, _>>().unwrap(), vec![1, 2]);
+    assert_eq!(table.len(), 2);
+
+    assert_eq!(table.concat(", ").unwrap(), "1, 2");
+
+    table.remove::<i32>(1);
+    table.remove::<i32>(1);
+    assert_eq!(table.pop::<i32>(), None);
+    assert_eq!(table.remove::<i32>(1), None);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn keys_and_values() {
+    let ctx = Context::new();
+
+    let table = Table::new(&ctx);
+    table.set("a", 1);
+    table.set("b", 2);
+
+    assert!(table.contains_key("a"));
+    assert!(!table.contains_key("c"));
+
+    let mut keys = table.keys::<String>().unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "b"]);
+
+    let mut values = table.values::<i32>().unwrap();
+    values.sort();
+    assert_eq!(values, vec![1, 2]);
+
+    table.set("c", "three");
+    assert!(table.values::<i32>().is_err());
+
+    table.clear();
+    assert_eq!(table.keys::<String>().unwrap().len(), 0);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn sort() {
+    let ctx = Context::new();
+
+    let table = Table::from_vec(&ctx, &[3, 1, 2]);
+    table.sort().unwrap();
+    assert_eq!(table.sequence::<i32>().collect::<Result<Vec<_>, _>>().unwrap(), vec![1, 2, 3]);
+
+    table.sort_by(|a: &i32, b: &i32| b.cmp(a)).unwrap();
+    assert_eq!(table.sequence::<i32>().collect::<Result<Vec<_>, _>>().unwrap(), vec![3, 2, 1]);
+
+    let words = Table::from_vec(&ctx, &["pear", "apple", "fig"]);
+    words.sort().unwrap();
+    assert_eq!(words.concat(" ").unwrap(), "apple fig pear");
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn sort_errors() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.eval("return { 2, \"two\", 1 }").unwrap();
+    let table = ctx.pop::<Table>();
+    let err = table.sort().unwrap_err();
+    assert_eq!(err.to_string(), "attempt to compare string with number");
+    assert_eq!(table.concat(" ").unwrap(), "2 two 1");
+    assert_eq!(ctx.size(), 0);
+
+    // an erroring `__lt` doesn't unwind through Rust
+    ctx.eval("local mt = { __lt = function() error('no order') end }\n\
+              return { setmetatable({}, mt), setmetatable({}, mt) }").unwrap();
+    let table = ctx.pop::<Table>();
+    assert!(table.sort().unwrap_err().to_string().ends_with("no order"));
+    assert_eq!(ctx.size(), 0);
+
+    // reading or joining a value of the wrong type
+    ctx.eval("return { 1, {}, 3 }").unwrap();
+    let table = ctx.pop::<Table>();
+    let err = table.sort_by(|a: &i32, b: &i32| a.cmp(b)).unwrap_err();
+    assert_eq!(err.to_string(), "expected i32 at [2], found table");
+    assert_eq!(table.concat(" ").unwrap_err().to_string(), "expected String at [2], found table");
+    assert_eq!(table.get::<i32, _>(3), 3);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn sort_long() {
+    let ctx = Context::new();
+
+    let values: Vec<i32> = (0..1000).map(|i| (i * 7919) % 1000).collect();
+    let table = Table::from_vec(&ctx, &values);
+    table.sort().unwrap();
+    assert_eq!(table.sequence::<i32>().collect::<Result<Vec<_>, _>>().unwrap(), (0..1000).collect::<Vec<_>>());
+    assert_eq!(ctx.size(), 0);
+}
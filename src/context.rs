@@ -1,18 +1,31 @@
 
-use LuaValue;
-use Table;
+use LuaError;
 use ffi;
+use error;
+use value;
 
 use stack::Read;
+use stack::ReadError;
 use stack::Push;
 use stack::Size;
 
-use std::ffi::CString;
+use std::cell::Cell;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::thread;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(PartialEq, Eq)]
 pub struct Context {
     pub handle: *mut ffi::lua_State,
     owner: bool,
+    pub(crate) raised: Cell<bool>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
@@ -22,15 +35,16 @@ impl Context {
                 ffi::luaL_newstate()
             },
             owner: true,
+            raised: Cell::new(false),
         }
     }
 
     pub fn from_state(state: *mut ffi::lua_State) -> Self {
-        Context { handle: state, owner: true }
+        Context { handle: state, owner: true, raised: Cell::new(false) }
     }
 
     pub fn from_state_weak(state: *mut ffi::lua_State) -> Self {
-        Context { handle: state, owner: false }
+        Context { handle: state, owner: false, raised: Cell::new(false) }
     }
 
     /*pub fn load(&mut self, path: std::path::Path) -> Result<(), IoError> {
@@ -49,22 +63,64 @@ impl Context {
         unimplemented!()
     }*/
 
-    pub fn eval(&self, code: &str) -> Result<(), &str> {
+    pub fn eval(&self, code: &str) -> Result<(), LuaError<'_>> {
+        unsafe {
+            let ret = match ffi::luaL_loadstring(self.handle, CString::new(code).unwrap().as_ptr()) {
+                0 => ffi::lua_pcall(self.handle, 0, ffi::LUA_MULTRET, 0),
+                err => err
+            };
+
+            match ret {
+                0 => Ok(()),
+                err => Err(LuaError::pop(self, err))
+            }
+        }
+    }
+
+    // like `eval`, but names the chunk for error messages and debug info,
+    // e.g. "@scripts/init.lua" for a file
+    pub fn eval_chunk(&self, code: &str, name: &str) -> Result<(), LuaError<'_>> {
         unsafe {
-            let ret = ffi::luaL_dostring(self.handle, CString::new(code).unwrap().as_ptr());
+            let name = CString::new(name).unwrap();
+            let ret = match ffi::luaL_loadbuffer(self.handle, code.as_ptr() as _, code.len(), name.as_ptr()) {
+                0 => ffi::lua_pcall(self.handle, 0, ffi::LUA_MULTRET, 0),
+                err => err
+            };
 
             match ret {
-                false => Ok(()),
-                true => Err(self.pop::<&str>())
+                0 => Ok(()),
+                err => Err(LuaError::pop(self, err))
             }
         }
     }
 
+    // raises `val` as a Lua error once the calling Rust callback returns,
+    // use as `return ctx.error(val)`
+    pub fn error<T>(&self, val: T) -> i32
+        where T: Push
+    {
+        val.push(self);
+        self.raised.set(true);
+        0
+    }
+
+    // like `error`, but boxes a Rust error into a userdata that scripts can
+    // `tostring` and that `LuaError::downcast_ref` turns back into `E`
+    pub fn raise<E>(&self, err: E) -> i32
+        where E: Error + 'static
+    {
+        unsafe {
+            error::push_rust_error(self, Box::new(err));
+        }
+        self.raised.set(true);
+        0
+    }
+
     pub fn get<'a, T>(&'a self, idx: &str) -> T
         where T: Read<'a> + Size
     {
         unsafe {
-            ffi::lua_getfield(self.handle, ffi::LUA_GLOBALSINDEX, unsafe { CString::new(idx).unwrap().as_ptr() as _ });
+            ffi::lua_getfield(self.handle, ffi::LUA_GLOBALSINDEX, CString::new(idx).unwrap().as_ptr() as _);
         }
 
         self.pop::<T>()
@@ -76,7 +132,7 @@ impl Context {
         val.push(self);
 
         unsafe {
-            ffi::lua_setfield(self.handle, ffi::LUA_GLOBALSINDEX, unsafe { CString::new(idx).unwrap().as_ptr() as _ });
+            ffi::lua_setfield(self.handle, ffi::LUA_GLOBALSINDEX, CString::new(idx).unwrap().as_ptr() as _);
         }
     }
 
@@ -102,6 +158,24 @@ impl Context {
         ret
     }
 
+    // checks the value first, naming the offending key path of a nested value
+    pub fn try_peek<'a, T>(&'a self, idx: i32) -> Result<T, ReadError>
+        where T: Read<'a>
+    {
+        T::validate(self, idx).map(|_| T::read(self, idx))
+    }
+
+    // pops the value whether or not it could be read
+    pub fn try_pop<'a, T>(&'a self) -> Result<T, ReadError>
+        where T: Read<'a> + Size
+    {
+        let ret = self.try_peek::<T>(-1);
+        if T::size() > 0 {
+            self.pop_discard(1);
+        }
+        ret
+    }
+
     pub fn pop_discard(&self, idx: i32) {
         unsafe {
             ffi::lua_pop(self.handle, idx)
@@ -130,33 +204,76 @@ impl Context {
         }
     }
 
-    pub fn dump(&self) {
-        let size = self.size();
-
-        print!("[ ({}) ", size);
-        unsafe {
-            for i in 1..(size + 1) {
-                print!("{}: {:?} ", i,  match ffi::lua_type(self.handle, i) {
-                    ffi::LUA_TNONE => "none",
-                    ffi::LUA_TNIL => "nil",
-                    ffi::LUA_TBOOLEAN => "bool",
-                    ffi::LUA_TLIGHTUSERDATA => "lightuserdata",
-                    ffi::LUA_TNUMBER => "number",
-                    ffi::LUA_TSTRING => "string",
-                    ffi::LUA_TTABLE => "table",
-                    ffi::LUA_TFUNCTION => "function",
-                    ffi::LUA_TUSERDATA => "userdata",
-                    ffi::LUA_TTHREAD => "thread",
-                    _ => panic!("unknown type"),
-                });
+    // every slot on the stack, bottom first
+    pub fn stack_snapshot(&self) -> Vec<StackSlot> {
+        (1..self.size() + 1).map(|i| {
+            StackSlot {
+                index: i,
+                type_name: unsafe {
+                    let t = ffi::lua_type(self.handle, i);
+                    CStr::from_ptr(ffi::lua_typename(self.handle, t)).to_string_lossy().into_owned()
+                },
+                preview: value::preview(self, i),
             }
-        }
-        println!(" ]");
+        }).collect()
     }
+
+    // checks on drop that the stack is back to its current height
+    pub fn guard(&self) -> StackGuard<'_> {
+        StackGuard { ctx: self, top: self.size() }
+    }
+
     // TODO: more stuff
 
 }
 
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("handle", &self.handle)
+            .field("stack", &self.stack_snapshot())
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct StackSlot {
+    pub index: i32,
+    pub type_name: String,
+    // `value::preview`, e.g. `"hello"` or `table: 0x...`
+    pub preview: String,
+}
+
+impl fmt::Debug for StackSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // `table: 0x...` is shown as `table 0x...` and `nil` only once
+        let preview = self.preview.trim_start_matches(self.type_name.as_str()).trim_start_matches(": ");
+        match preview.is_empty() {
+            true => write!(f, "{}: {}", self.index, self.type_name),
+            false => write!(f, "{}: {} {}", self.index, self.type_name, preview)
+        }
+    }
+}
+
+// Panics in debug builds if the stack height differs from when the guard was
+// made, catching pushes that are never popped:
+//
+//     let _guard = ctx.guard();
+//     table.get::<i32, _>("x");
+pub struct StackGuard<'a> {
+    ctx: &'a Context,
+    top: i32,
+}
+
+impl<'a> Drop for StackGuard<'a> {
+    fn drop(&mut self) {
+        // a second panic while unwinding would abort
+        if cfg!(debug_assertions) && !thread::panicking() && self.ctx.size() != self.top {
+            panic!("stack height changed from {} to {}: {:?}", self.top, self.ctx.size(), self.ctx.stack_snapshot());
+        }
+    }
+}
+
 impl Drop for Context {
     fn drop(&mut self) {
         if self.owner {
@@ -191,3 +308,36 @@ fn stack_size() {
     assert_eq!(ctx.size(), 4);
 }
 
+
+#[test]
+fn stack_snapshot() {
+    let ctx = Context::new();
+
+    ctx.push((true, 4.5, "hi"));
+    unsafe { ffi::lua_pushnil(ctx.handle) };
+
+    let slots: Vec<String> = ctx.stack_snapshot().iter().map(|s| format!("{:?}", s)).collect();
+    assert_eq!(slots, ["1: boolean true", "2: number 4.5", "3: string \"hi\"", "4: nil"]);
+    assert!(format!("{:?}", ctx).ends_with("stack: [1: boolean true, 2: number 4.5, 3: string \"hi\", 4: nil] }"));
+
+    ctx.eval("return {}").unwrap();
+    assert!(format!("{:?}", ctx.stack_snapshot()[4]).starts_with("5: table 0x"));
+}
+
+#[test]
+fn stack_guard() {
+    let ctx = Context::new();
+
+    {
+        let _guard = ctx.guard();
+        ctx.push(1);
+        ctx.pop_discard(1);
+    }
+
+    let leaked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        let _guard = ctx.guard();
+        ctx.push("leak");
+    }));
+    let err = leaked.unwrap_err();
+    assert_eq!(err.downcast_ref::<String>().unwrap(), "stack height changed from 0 to 1: [1: string \"leak\"]");
+}
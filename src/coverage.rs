@@ -0,0 +1,168 @@
+use Context;
+use HookEvent;
+use HookMask;
+
+use prototype::Prototype;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+use std::fs::File;
+use std::io;
+use std::io::Write as IoWrite;
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Default)]
+struct CoverageData {
+    hits: BTreeMap<String, BTreeMap<i32, u64>>,
+    executable: BTreeMap<String, BTreeSet<i32>>,
+    // prototypes already dumped, as chunk and line range; closures come and
+    // go, but their prototype is the same
+    seen: HashSet<(String, i32, i32)>,
+}
+
+// Records how often each line runs, keyed by chunk name ("@file.lua" becomes
+// "file.lua"). Executable lines come from the line info of every function
+// prototype seen while collecting, so lines that never ran still show up.
+pub struct Coverage {
+    data: Rc<RefCell<CoverageData>>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {
+            data: Rc::new(RefCell::new(CoverageData::default())),
+        }
+    }
+
+    pub fn start(&self, ctx: &Context) {
+        let data = self.data.clone();
+
+        ctx.set_hook(HookMask::LINE, 0, move |ctx, event| {
+            let line = match event.kind() {
+                HookEvent::Line(line) => line,
+                _ => return
+            };
+            let info = event.info();
+            let chunk = info.chunk();
+            let mut data = data.borrow_mut();
+
+            // only functions on the same single line share a range, and then
+            // they share their lines too
+            if data.seen.insert((chunk.clone(), info.linedefined, info.lastlinedefined)) {
+                event.push_function();
+                if let Some(proto) = Prototype::dump(ctx, -1) {
+                    data.executable.entry(chunk.clone()).or_default().extend(proto.active_lines());
+                }
+                ctx.pop_discard(1);
+            }
+
+            *data.hits.entry(chunk).or_default().entry(line).or_insert(0) += 1;
+        });
+    }
+
+    pub fn stop(&self, ctx: &Context) {
+        ctx.remove_hook();
+    }
+
+    pub fn reset(&self) {
+        *self.data.borrow_mut() = CoverageData::default();
+    }
+
+    pub fn chunks(&self) -> Vec<String> {
+        let data = self.data.borrow();
+        let mut chunks: BTreeSet<String> = data.hits.keys().cloned().collect();
+        chunks.extend(data.executable.keys().cloned());
+        chunks.into_iter().collect()
+    }
+
+    // hit counts for every executable line of `chunk`, including zeroes
+    pub fn lines(&self, chunk: &str) -> BTreeMap<i32, u64> {
+        let data = self.data.borrow();
+        let mut lines = BTreeMap::new();
+
+        if let Some(executable) = data.executable.get(chunk) {
+            for line in executable {
+                lines.insert(*line, 0);
+            }
+        }
+        if let Some(hits) = data.hits.get(chunk) {
+            for (line, count) in hits {
+                lines.insert(*line, *count);
+            }
+        }
+        lines
+    }
+
+    pub fn lcov(&self) -> String {
+        let mut out = String::new();
+
+        for chunk in self.chunks() {
+            let lines = self.lines(&chunk);
+
+            writeln!(out, "TN:").unwrap();
+            writeln!(out, "SF:{}", chunk).unwrap();
+            for (line, count) in &lines {
+                writeln!(out, "DA:{},{}", line, count).unwrap();
+            }
+            writeln!(out, "LF:{}", lines.len()).unwrap();
+            writeln!(out, "LH:{}", lines.values().filter(|c| **c > 0).count()).unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+
+    pub fn write_lcov<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        f.write_all(self.lcov().as_bytes())
+    }
+}
+
+#[test]
+fn collect() {
+    let ctx = Context::new();
+    let coverage = Coverage::new();
+
+    coverage.start(&ctx);
+    ctx.eval_chunk("local function f(x)\n\
+                    if x then\n\
+                    return 1\n\
+                    end\n\
+                    return 2\n\
+                    end\n\
+                    f(false)\n\
+                    f(false)", "@test.lua").unwrap();
+    coverage.stop(&ctx);
+    ctx.eval_chunk("local a = 1", "@other.lua").unwrap();
+
+    assert_eq!(coverage.chunks(), vec!["test.lua".to_string()]);
+
+    let lines = coverage.lines("test.lua");
+    assert_eq!(lines.get(&2), Some(&2));
+    assert_eq!(lines.get(&3), Some(&0));
+    assert_eq!(lines.get(&5), Some(&2));
+    assert_eq!(lines.get(&7), Some(&1));
+
+    let lcov = coverage.lcov();
+    assert!(lcov.starts_with("TN:\nSF:test.lua\n"));
+    assert!(lcov.contains("DA:3,0\n"));
+    assert!(lcov.ends_with("end_of_record\n"));
+
+    // closures made in a loop share one prototype
+    coverage.reset();
+    coverage.start(&ctx);
+    ctx.eval_chunk("for i = 1, 3 do\n\
+                    local g = function() return i end\n\
+                    g()\n\
+                    end", "@loop.lua").unwrap();
+    coverage.stop(&ctx);
+    assert_eq!(coverage.data.borrow().seen.len(), 2);
+    assert_eq!(coverage.lines("loop.lua").get(&2), Some(&6));
+}
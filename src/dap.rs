@@ -0,0 +1,490 @@
+// A Debug Adapter Protocol server for the `Debugger`, enabled by the `dap`
+// feature. Lua runs on the host's thread, so requests are only read while
+// configuring in `start` and while the program is paused.
+
+use Breakpoint;
+use Command;
+use Context;
+use Debugger;
+use Frontend;
+use PauseReason;
+use prototype::Prototype;
+use Session;
+use ffi;
+
+use serde_json::Value;
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+struct Connection {
+    reader: Box<dyn BufRead>,
+    writer: Box<dyn Write>,
+    seq: i64,
+    closed: bool,
+}
+
+impl Connection {
+    fn read(&mut self) -> io::Result<Value> {
+        let mut length = None;
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client disconnected"));
+            }
+
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if line.to_ascii_lowercase().starts_with("content-length:") {
+                length = line[15..].trim().parse::<usize>().ok();
+            }
+        }
+
+        let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body)?;
+
+        serde_json::from_slice(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn send(&mut self, mut msg: Value) -> io::Result<()> {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+
+        let body = msg.to_string();
+        write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.writer.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn respond(&mut self, req: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut msg = json!({
+            "type": "response",
+            "request_seq": req["seq"],
+            "command": req["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => msg["body"] = body,
+            Err(e) => msg["message"] = json!(e),
+        }
+        self.send(msg)
+    }
+}
+
+enum Outcome {
+    Handled,
+    Configured,
+    Resume(Command),
+    Disconnect,
+}
+
+// Answers one request. Requests that need a paused program fail while
+// `session` is `None`.
+fn handle(conn: &mut Connection, req: &Value, debugger: Option<&Debugger>, mut session: Option<&mut Session>) -> io::Result<Outcome> {
+    let args = &req["arguments"];
+    let level = |id: &Value| id.as_i64().unwrap_or(0) as i32;
+
+    let (result, outcome) = match req["command"].as_str().unwrap_or("") {
+        "initialize" => {
+            conn.respond(req, Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsEvaluateForHovers": true,
+            })))?;
+            conn.event("initialized", json!({}))?;
+            return Ok(Outcome::Handled);
+        }
+        "launch" | "attach" | "setExceptionBreakpoints" => (Ok(json!({})), Outcome::Handled),
+        "configurationDone" => (Ok(json!({})), Outcome::Configured),
+        "setBreakpoints" => {
+            let path = args["source"]["path"].as_str().unwrap_or("");
+            let chunk = chunk_name(path);
+            let lines: Vec<i32> = args["breakpoints"].as_array()
+                .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_i64()).map(|l| l as i32).collect())
+                .unwrap_or_default();
+
+            // lines without code are moved to the next line that has some
+            let active = active_lines(path);
+            let resolved: Vec<Result<i32, String>> = lines.iter().map(|&line| match active {
+                Ok(ref active) => active.iter().cloned().find(|&l| l >= line)
+                    .ok_or_else(|| format!("no code at or after line {}", line)),
+                Err(ref e) => Err(e.clone()),
+            }).collect();
+            let valid = resolved.iter().filter_map(|r| r.as_ref().ok()).map(|&line| Breakpoint::new(&chunk, line));
+
+            match (debugger, session.as_mut()) {
+                (_, Some(session)) => {
+                    session.clear_breakpoints(&chunk);
+                    for bp in valid {
+                        session.add_breakpoint(bp);
+                    }
+                }
+                (Some(debugger), None) => {
+                    debugger.clear_breakpoints(&chunk);
+                    for bp in valid {
+                        debugger.add_breakpoint(bp);
+                    }
+                }
+                (None, None) => {}
+            }
+
+            let verified: Vec<Value> = lines.iter().zip(&resolved).map(|(line, r)| match *r {
+                Ok(l) => json!({ "verified": true, "line": l }),
+                Err(ref e) => json!({ "verified": false, "line": line, "message": e }),
+            }).collect();
+            (Ok(json!({ "breakpoints": verified })), Outcome::Handled)
+        }
+        "threads" => (Ok(json!({ "threads": [{ "id": 1, "name": "main" }] })), Outcome::Handled),
+        "continue" => (Ok(json!({ "allThreadsContinued": true })), Outcome::Resume(Command::Continue)),
+        "next" => (Ok(json!({})), Outcome::Resume(Command::StepOver)),
+        "stepIn" => (Ok(json!({})), Outcome::Resume(Command::StepIn)),
+        "stepOut" => (Ok(json!({})), Outcome::Resume(Command::StepOut)),
+        "disconnect" => (Ok(json!({})), Outcome::Disconnect),
+        command => match session {
+            None => (Err(format!("`{}` needs a paused program", command)), Outcome::Handled),
+            Some(session) => {
+                let result = match command {
+                    "stackTrace" => {
+                        let frames: Vec<Value> = session.backtrace().iter().enumerate().map(|(i, frame)| json!({
+                            "id": i,
+                            "name": frame.name.clone().unwrap_or_else(|| frame.what.clone()),
+                            "source": { "name": frame.chunk(), "path": frame.chunk() },
+                            "line": frame.currentline,
+                            "column": 1,
+                        })).collect();
+                        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+                    }
+                    // odd references list locals, even ones upvalues
+                    "scopes" => {
+                        let frame = level(&args["frameId"]);
+                        Ok(json!({ "scopes": [
+                            { "name": "Locals", "variablesReference": frame * 2 + 1, "expensive": false },
+                            { "name": "Upvalues", "variablesReference": frame * 2 + 2, "expensive": false },
+                        ]}))
+                    }
+                    "variables" => {
+                        let reference = level(&args["variablesReference"]);
+                        let vars = match reference % 2 {
+                            1 => session.locals((reference - 1) / 2),
+                            _ => session.upvalues((reference - 2) / 2),
+                        };
+                        let vars: Vec<Value> = vars.iter().map(|v| json!({
+                            "name": v.name,
+                            "value": v.value,
+                            "type": v.kind,
+                            "variablesReference": 0,
+                        })).collect();
+                        Ok(json!({ "variables": vars }))
+                    }
+                    "evaluate" => {
+                        let expr = args["expression"].as_str().unwrap_or("");
+                        session.evaluate(level(&args["frameId"]), expr)
+                            .map(|val| json!({ "result": val, "variablesReference": 0 }))
+                    }
+                    _ => Err(format!("unsupported request `{}`", command))
+                };
+                (result, Outcome::Handled)
+            }
+        }
+    };
+
+    conn.respond(req, result)?;
+    Ok(outcome)
+}
+
+// The chunk name Lua gives a file loaded by `path`: clients send absolute
+// paths, scripts are usually loaded relative to the working directory.
+fn chunk_name(path: &str) -> String {
+    let path = path.replace('\\', "/");
+
+    let mut clean = PathBuf::new();
+    for component in Path::new(&path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if clean.file_name().is_some() => { clean.pop(); }
+            c => clean.push(c.as_os_str()),
+        }
+    }
+
+    let relative = env::current_dir().ok()
+        .and_then(|cwd| clean.strip_prefix(cwd).ok().map(|p| p.to_path_buf()));
+    relative.unwrap_or(clean).to_string_lossy().replace('\\', "/")
+}
+
+// the lines of the file at `path` that hold code
+fn active_lines(path: &str) -> Result<Vec<i32>, String> {
+    let code = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+
+    let ctx = Context::new();
+    unsafe {
+        if ffi::luaL_loadbuffer(ctx.handle, code.as_ptr() as _, code.len(), c_str!("=source")) != 0 {
+            return Err(ctx.pop::<String>());
+        }
+    }
+
+    Prototype::dump(&ctx, -1)
+        .map(|proto| proto.active_lines())
+        .ok_or_else(|| format!("cannot read the line info of {}", path))
+}
+
+struct DapFrontend {
+    conn: Rc<RefCell<Connection>>,
+}
+
+impl DapFrontend {
+    fn serve(&mut self, session: &mut Session) -> io::Result<Command> {
+        let mut conn = self.conn.borrow_mut();
+
+        let reason = match *session.reason() {
+            PauseReason::Breakpoint(..) => "breakpoint",
+            PauseReason::Step => "step",
+        };
+        conn.event("stopped", json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true }))?;
+
+        loop {
+            let req = conn.read()?;
+            match handle(&mut conn, &req, None, Some(session))? {
+                Outcome::Resume(command) => return Ok(command),
+                Outcome::Disconnect => {
+                    conn.closed = true;
+                    return Ok(Command::Continue);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Frontend for DapFrontend {
+    fn paused(&mut self, session: &mut Session) -> Command {
+        if self.conn.borrow().closed {
+            return Command::Continue;
+        }
+
+        self.serve(session).unwrap_or_else(|_| {
+            self.conn.borrow_mut().closed = true;
+            Command::Continue
+        })
+    }
+}
+
+pub struct DapServer {
+    conn: Rc<RefCell<Connection>>,
+    debugger: Debugger,
+}
+
+impl DapServer {
+    pub fn new<R, W>(reader: R, writer: W) -> Self
+        where R: Read + 'static,
+              W: Write + 'static
+    {
+        let conn = Rc::new(RefCell::new(Connection {
+            reader: Box::new(BufReader::new(reader)),
+            writer: Box::new(writer),
+            seq: 0,
+            closed: false,
+        }));
+
+        DapServer {
+            debugger: Debugger::new(DapFrontend { conn: conn.clone() }),
+            conn,
+        }
+    }
+
+    pub fn stdio() -> Self {
+        DapServer::new(io::stdin(), io::stdout())
+    }
+
+    // waits for a single client to connect
+    pub fn accept<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        DapServer::from_stream(stream)
+    }
+
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        Ok(DapServer::new(stream.try_clone()?, stream))
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    // serves the client until it sends `configurationDone`, then attaches
+    // the debugger to `ctx`
+    pub fn start(&self, ctx: &Context) -> io::Result<()> {
+        {
+            let mut conn = self.conn.borrow_mut();
+            loop {
+                let req = conn.read()?;
+                match handle(&mut conn, &req, Some(&self.debugger), None)? {
+                    Outcome::Configured => break,
+                    Outcome::Disconnect => {
+                        conn.closed = true;
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.debugger.attach(ctx);
+        Ok(())
+    }
+
+    // detaches and tells the client the program has ended
+    pub fn finish(&self, ctx: &Context) -> io::Result<()> {
+        self.debugger.detach(ctx);
+
+        let mut conn = self.conn.borrow_mut();
+        if conn.closed {
+            return Ok(());
+        }
+
+        conn.event("exited", json!({ "exitCode": 0 }))?;
+        conn.event("terminated", json!({}))?;
+        conn.closed = true;
+        Ok(())
+    }
+}
+
+#[test]
+fn session() {
+    use std::thread;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        seq: i64,
+    }
+
+    impl Client {
+        fn read(&mut self) -> Value {
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).unwrap();
+                match line.trim() {
+                    "" => break,
+                    l => length = l["Content-Length:".len()..].trim().parse().unwrap(),
+                }
+            }
+
+            let mut body = vec![0; length];
+            self.reader.read_exact(&mut body).unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        fn wait_event(&mut self, event: &str) -> Value {
+            loop {
+                let msg = self.read();
+                if msg["type"] == "event" && msg["event"] == event {
+                    return msg;
+                }
+            }
+        }
+
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let body = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments }).to_string();
+            write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+
+            loop {
+                let msg = self.read();
+                if msg["type"] == "response" && msg["request_seq"] == self.seq {
+                    assert_eq!(msg["success"], true, "{}", msg);
+                    return msg["body"].clone();
+                }
+            }
+        }
+    }
+
+    let code = "local factor = 3\n\
+                local function scale(x)\n\
+                local y = x * factor\n\
+                return y\n\
+                end\n\
+                result = scale(4)";
+    let path = env::temp_dir().join(format!("flu-dap-{}", ::std::process::id())).join("test.lua");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, code).unwrap();
+
+    // the client names the file through a `..`
+    let parent = path.parent().unwrap();
+    let source = parent.join("..").join(parent.file_name().unwrap()).join("test.lua");
+    let source = source.to_string_lossy().into_owned();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = Client { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream, seq: 0 };
+
+        client.request("initialize", json!({ "adapterID": "flu" }));
+        client.request("launch", json!({}));
+        let bps = client.request("setBreakpoints", json!({ "source": { "path": source }, "breakpoints": [{ "line": 9 }] }));
+        assert_eq!(bps["breakpoints"][0]["verified"], false);
+        assert_eq!(bps["breakpoints"][0]["message"], "no code at or after line 9");
+
+        // the header of `scale` has no code, the breakpoint moves into its body
+
+        let bps = client.request("setBreakpoints", json!({ "source": { "path": source }, "breakpoints": [{ "line": 2 }] }));
+        assert_eq!(bps["breakpoints"][0]["verified"], true);
+        assert_eq!(bps["breakpoints"][0]["line"], 3);
+        client.request("configurationDone", json!({}));
+
+        let stopped = client.wait_event("stopped");
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["stackFrames"][0]["name"], "scale");
+        assert_eq!(trace["stackFrames"][0]["line"], 3);
+        assert_eq!(trace["stackFrames"][1]["line"], 6);
+
+        let scopes = client.request("scopes", json!({ "frameId": 0 }));
+        let locals = scopes["scopes"][0]["variablesReference"].clone();
+        let vars = client.request("variables", json!({ "variablesReference": locals }));
+        assert_eq!(vars["variables"][0]["name"], "x");
+        assert_eq!(vars["variables"][0]["value"], "4");
+
+        let upvalues = scopes["scopes"][1]["variablesReference"].clone();
+        let vars = client.request("variables", json!({ "variablesReference": upvalues }));
+        assert_eq!(vars["variables"][0]["name"], "factor");
+
+        let eval = client.request("evaluate", json!({ "expression": "x * factor", "frameId": 0 }));
+        assert_eq!(eval["result"], "12");
+
+        client.request("next", json!({ "threadId": 1 }));
+        let stopped = client.wait_event("stopped");
+        assert_eq!(stopped["body"]["reason"], "step");
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["stackFrames"][0]["line"], 4);
+
+        client.request("continue", json!({ "threadId": 1 }));
+        client.wait_event("terminated");
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let server = DapServer::from_stream(stream).unwrap();
+    let ctx = Context::new();
+
+    server.start(&ctx).unwrap();
+    ctx.eval_chunk(code, &format!("@{}", path.to_string_lossy())).unwrap();
+    server.finish(&ctx).unwrap();
+
+    client.join().unwrap();
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    assert_eq!(ctx.get::<i32>("result"), 12);
+}
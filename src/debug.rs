@@ -0,0 +1,464 @@
+use Context;
+use Function;
+use LuaValue;
+use ffi;
+
+use stack::Push;
+use stack::Read;
+
+use libc;
+
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::BitOr;
+use std::ptr;
+
+type Hook = Box<dyn FnMut(&mut Context, &DebugEvent)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookMask(i32);
+
+impl HookMask {
+    pub const CALL: HookMask = HookMask(ffi::LUA_MASKCALL);
+    pub const RETURN: HookMask = HookMask(ffi::LUA_MASKRET);
+    pub const LINE: HookMask = HookMask(ffi::LUA_MASKLINE);
+    pub const COUNT: HookMask = HookMask(ffi::LUA_MASKCOUNT);
+
+    pub fn empty() -> Self {
+        HookMask(0)
+    }
+
+    pub fn bits(&self) -> i32 {
+        self.0
+    }
+
+    pub fn contains(&self, other: HookMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for HookMask {
+    type Output = HookMask;
+
+    fn bitor(self, rhs: HookMask) -> HookMask {
+        HookMask(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Call,
+    Return,
+    // a return from a function whose frame was reused by a tail call
+    TailReturn,
+    Line(i32),
+    Count,
+}
+
+pub struct DebugEvent<'a> {
+    state: *mut ffi::lua_State,
+    ar: *mut ffi::lua_Debug,
+    _pd: PhantomData<&'a mut ffi::lua_Debug>,
+}
+
+impl<'a> DebugEvent<'a> {
+    pub fn kind(&self) -> HookEvent {
+        unsafe {
+            match (*self.ar).event {
+                ffi::LUA_HOOKCALL => HookEvent::Call,
+                ffi::LUA_HOOKRET => HookEvent::Return,
+                ffi::LUA_HOOKTAILRET => HookEvent::TailReturn,
+                ffi::LUA_HOOKLINE => HookEvent::Line((*self.ar).currentline),
+                ffi::LUA_HOOKCOUNT => HookEvent::Count,
+                _ => unreachable!()
+            }
+        }
+    }
+
+    // the function running when the event fired
+    pub fn info(&self) -> DebugInfo {
+        unsafe {
+            ffi::lua_getinfo(self.state, c_str!("nSl"), self.ar);
+            DebugInfo::from_raw(&*self.ar)
+        }
+    }
+
+    // pushes the running function onto the stack
+    pub(crate) fn push_function(&self) {
+        unsafe {
+            ffi::lua_getinfo(self.state, c_str!("f"), self.ar);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub source: String,
+    pub short_src: String,
+    pub what: String,
+    pub name: Option<String>,
+    pub namewhat: String,
+    pub currentline: i32,
+    pub linedefined: i32,
+    pub lastlinedefined: i32,
+}
+
+impl DebugInfo {
+    // `ar` must have been filled by `lua_getinfo` with at least "nSl"
+    pub(crate) unsafe fn from_raw(ar: &ffi::lua_Debug) -> Self {
+        unsafe fn string(s: *const libc::c_char) -> Option<String> {
+            match s.is_null() {
+                true => None,
+                false => Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+            }
+        }
+
+        DebugInfo {
+            source: string(ar.source).unwrap_or_default(),
+            short_src: string(ar.short_src.as_ptr()).unwrap_or_default(),
+            what: string(ar.what).unwrap_or_default(),
+            name: string(ar.name),
+            namewhat: string(ar.namewhat).unwrap_or_default(),
+            currentline: ar.currentline,
+            linedefined: ar.linedefined,
+            lastlinedefined: ar.lastlinedefined,
+        }
+    }
+
+    // the chunk name used by breakpoints and coverage: the path for file
+    // chunks ("@foo.lua" is "foo.lua"), `short_src` for string chunks
+    pub fn chunk(&self) -> String {
+        match self.source.starts_with('@') || self.source.starts_with('=') {
+            true => self.source[1..].to_string(),
+            false => self.short_src.clone()
+        }
+    }
+}
+
+// An active function on the call stack. It is only valid while that call is
+// running, e.g. inside a hook or a Rust callback it called.
+pub struct StackFrame<'a> {
+    ctx: &'a Context,
+    ar: ffi::lua_Debug,
+}
+
+impl<'a> StackFrame<'a> {
+    pub fn info(&self) -> DebugInfo {
+        unsafe {
+            let mut ar: ffi::lua_Debug = ptr::read(&self.ar);
+            ffi::lua_getinfo(self.ctx.handle, c_str!("nSl"), &mut ar);
+            DebugInfo::from_raw(&ar)
+        }
+    }
+
+    pub fn function(&self) -> Function<'a> {
+        unsafe {
+            let mut ar: ffi::lua_Debug = ptr::read(&self.ar);
+            ffi::lua_getinfo(self.ctx.handle, c_str!("f"), &mut ar);
+        }
+        self.ctx.pop::<Function>()
+    }
+
+    // all active locals in declaration order, including internal ones such
+    // as "(for index)"; local `n` is at position `n - 1`
+    pub fn locals(&self) -> Vec<(String, LuaValue<'a>)> {
+        let mut locals = Vec::new();
+
+        unsafe {
+            let mut n = 1;
+            loop {
+                let name = ffi::lua_getlocal(self.ctx.handle, &self.ar, n);
+                if name.is_null() {
+                    break;
+                }
+
+                let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+                Vec::push(&mut locals, (name, self.ctx.pop::<LuaValue>()));
+                n += 1;
+            }
+        }
+        locals
+    }
+
+    pub fn local<T>(&self, name: &str) -> Option<T>
+        where T: Read<'a>
+    {
+        unsafe {
+            let mut n = 1;
+            loop {
+                let local = ffi::lua_getlocal(self.ctx.handle, &self.ar, n);
+                if local.is_null() {
+                    return None;
+                }
+
+                let found = CStr::from_ptr(local).to_bytes() == name.as_bytes();
+                let val = match found && T::check(self.ctx, -1) {
+                    true => Some(T::read(self.ctx, -1)),
+                    false => None
+                };
+                self.ctx.pop_discard(1);
+
+                if found {
+                    return val;
+                }
+                n += 1;
+            }
+        }
+    }
+
+    // assigns local `n` (starting at 1), returning its name or `None` if
+    // there is no such local
+    pub fn set_local<T>(&self, n: i32, val: T) -> Option<String>
+        where T: Push
+    {
+        self.ctx.push(val);
+
+        unsafe {
+            let mut ar: ffi::lua_Debug = ptr::read(&self.ar);
+            let name = ffi::lua_setlocal(self.ctx.handle, &mut ar, n);
+
+            match name.is_null() {
+                true => None,
+                false => Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    pub fn upvalues(&self) -> Vec<(String, LuaValue<'a>)> {
+        self.function().upvalues()
+    }
+}
+
+impl Context {
+    // the function running at `level`, where 0 is the current function
+    pub fn stack_frame(&self, level: i32) -> Option<StackFrame<'_>> {
+        unsafe {
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+
+            match ffi::lua_getstack(self.handle, level, &mut ar) {
+                0 => None,
+                _ => Some(StackFrame { ctx: self, ar })
+            }
+        }
+    }
+
+    // installs `hook` for the events in `mask`, replacing any previous hook.
+    // `count` is the instruction interval for `HookMask::COUNT` events and
+    // must be positive with it, Lua would never fire the hook otherwise.
+    // Calling `ctx.error` or `ctx.raise` inside the hook aborts the running
+    // code with that error.
+    pub fn set_hook<F>(&self, mask: HookMask, count: i32, hook: F)
+        where F: FnMut(&mut Context, &DebugEvent) + 'static
+    {
+        assert!(!mask.contains(HookMask::COUNT) || count > 0, "HookMask::COUNT needs a positive count, got {}", count);
+
+        unsafe {
+            let ud = ffi::lua_newuserdata(self.handle, mem::size_of::<Hook>() as libc::size_t);
+            ptr::write(ud as *mut Hook, Box::new(hook));
+
+            ffi::lua_createtable(self.handle, 0, 1);
+            ffi::lua_pushcfunction(self.handle, hook_gc);
+            ffi::lua_setfield(self.handle, -2, c_str!("__gc"));
+            ffi::lua_setmetatable(self.handle, -2);
+
+            ffi::lua_setfield(self.handle, ffi::LUA_REGISTRYINDEX, c_str!("flu.hook"));
+            ffi::lua_sethook(self.handle, Some(hook_wrapper), mask.bits(), count);
+        }
+    }
+
+    pub fn remove_hook(&self) {
+        unsafe {
+            ffi::lua_sethook(self.handle, None, 0, 0);
+
+            ffi::lua_pushnil(self.handle);
+            ffi::lua_setfield(self.handle, ffi::LUA_REGISTRYINDEX, c_str!("flu.hook"));
+        }
+    }
+
+    // information about the function running at `level`, where 0 is the
+    // current function and 1 the function that called it
+    pub fn stack_info(&self, level: i32) -> Option<DebugInfo> {
+        unsafe {
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+
+            match ffi::lua_getstack(self.handle, level, &mut ar) {
+                0 => None,
+                _ => {
+                    ffi::lua_getinfo(self.handle, c_str!("nSl"), &mut ar);
+                    Some(DebugInfo::from_raw(&ar))
+                }
+            }
+        }
+    }
+
+    pub fn hook_mask(&self) -> HookMask {
+        unsafe {
+            HookMask(ffi::lua_gethookmask(self.handle))
+        }
+    }
+}
+
+unsafe extern "C" fn hook_wrapper(state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+    let raised = {
+        let mut ctx = Context::from_state_weak(state);
+
+        // the userdata stays on the stack while the hook runs, so the hook
+        // isn't collected if it replaces or removes itself
+        ffi::lua_getfield(state, ffi::LUA_REGISTRYINDEX, c_str!("flu.hook"));
+        let top = ctx.size();
+        let hook = ffi::lua_touserdata(state, -1) as *mut Hook;
+
+        if !hook.is_null() {
+            let event = DebugEvent { state, ar, _pd: PhantomData };
+            (*hook)(&mut ctx, &event);
+        }
+
+        // an error raised by the hook is on top of the userdata
+        let raised = ctx.raised.get();
+        if !raised {
+            ffi::lua_settop(state, top - 1);
+        }
+        raised
+    };
+
+    if raised {
+        ffi::lua_error(state);
+    }
+}
+
+unsafe extern "C" fn hook_gc(state: *mut ffi::lua_State) -> libc::c_int {
+    ptr::drop_in_place(ffi::lua_touserdata(state, 1) as *mut Hook);
+    0
+}
+
+#[test]
+fn frame_locals() {
+    let ctx = Context::new();
+
+    ctx.set("inspect", |ctx: &mut Context| {
+        let frame = ctx.stack_frame(1).unwrap();
+        let locals: Vec<String> = frame.locals().into_iter().map(|(name, _)| name).collect();
+
+        assert_eq!(locals, vec!["a", "b"]);
+        assert_eq!(frame.local::<i32>("b"), Some(2));
+        assert_eq!(frame.local::<i32>("c"), None);
+        assert_eq!(frame.info().name, Some("f".to_string()));
+
+        assert_eq!(frame.set_local(2, 40), Some("b".to_string()));
+        assert_eq!(frame.set_local(3, 0), None);
+        0
+    });
+
+    ctx.eval("function f(a) local b = 2 inspect() return a + b end").unwrap();
+    ctx.eval("result = f(2)").unwrap();
+
+    assert_eq!(ctx.get::<i32>("result"), 42);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn line_hook() {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    let ctx = Context::new();
+    let lines = Rc::new(RefCell::new(Vec::new()));
+
+    let l = lines.clone();
+    ctx.set_hook(HookMask::LINE, 0, move |_, event| {
+        if let HookEvent::Line(line) = event.kind() {
+            Vec::push(&mut l.borrow_mut(), (event.info().what, line));
+        }
+    });
+
+    ctx.eval("local a = 1\nlocal b = 2\n\nlocal c = a + b").unwrap();
+    ctx.remove_hook();
+    ctx.eval("local d = 4").unwrap();
+
+    assert_eq!(*lines.borrow(), vec![
+        ("main".to_string(), 1),
+        ("main".to_string(), 2),
+        ("main".to_string(), 4),
+    ]);
+}
+
+#[test]
+fn call_hook() {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    let ctx = Context::new();
+    let calls = Rc::new(RefCell::new(Vec::new()));
+
+    let c = calls.clone();
+    ctx.set_hook(HookMask::CALL | HookMask::RETURN, 0, move |_, event| {
+        let info = event.info();
+        if info.what == "Lua" {
+            Vec::push(&mut c.borrow_mut(), (event.kind(), info.name, info.linedefined));
+        }
+    });
+
+    ctx.eval("function foo()\nreturn 1\nend\nfoo()").unwrap();
+
+    assert_eq!(*calls.borrow(), vec![
+        (HookEvent::Call, Some("foo".to_string()), 1),
+        (HookEvent::Return, Some("foo".to_string()), 1),
+    ]);
+}
+
+#[test]
+fn abort_from_hook() {
+    let ctx = Context::new();
+
+    ctx.set_hook(HookMask::COUNT, 1000, |ctx, _| {
+        ctx.error("instruction limit reached");
+    });
+
+    let err = ctx.eval("while true do end").unwrap_err();
+
+    assert_eq!(format!("{}", err), "instruction limit reached");
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn remove_hook_from_hook() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct DropFlag(Rc<Cell<bool>>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    let ctx = Context::new();
+    let dropped = Rc::new(Cell::new(false));
+    let seen = Rc::new(Cell::new(None));
+
+    let flag = DropFlag(dropped.clone());
+    let (dropped_in_hook, seen_in_hook) = (dropped.clone(), seen.clone());
+    ctx.set_hook(HookMask::LINE, 0, move |ctx, _| {
+        let _keep = &flag;
+        ctx.remove_hook();
+        unsafe { ffi::lua_gc(ctx.handle, ffi::LUA_GCCOLLECT, 0) };
+        seen_in_hook.set(Some(dropped_in_hook.get()));
+    });
+
+    ctx.eval("local a = 1\nlocal b = 2").unwrap();
+    assert_eq!(seen.get(), Some(false));
+    assert_eq!(ctx.hook_mask(), HookMask::empty());
+
+    unsafe { ffi::lua_gc(ctx.handle, ffi::LUA_GCCOLLECT, 0) };
+    assert!(dropped.get());
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+#[should_panic(expected = "HookMask::COUNT needs a positive count")]
+fn zero_count() {
+    let ctx = Context::new();
+    ctx.set_hook(HookMask::COUNT, 0, |_, _| {});
+}
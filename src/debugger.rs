@@ -0,0 +1,622 @@
+use Context;
+use DebugInfo;
+use HookEvent;
+use HookMask;
+
+use ffi;
+use value;
+
+use libc;
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::mem;
+use std::rc::Rc;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Breakpoint {
+    pub chunk: String,
+    pub line: i32,
+}
+
+impl Breakpoint {
+    pub fn new(chunk: &str, line: i32) -> Self {
+        Breakpoint { chunk: chunk.to_string(), line }
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    // "chunk:line", where the chunk itself may contain colons
+    fn from_str(s: &str) -> Result<Self, String> {
+        let pos = s.rfind(':').ok_or_else(|| format!("expected `chunk:line`, got `{}`", s))?;
+        let line = s[pos + 1..].parse::<i32>().map_err(|_| format!("invalid line in `{}`", s))?;
+
+        Ok(Breakpoint::new(&s[..pos], line))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.chunk, self.line)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    StepIn,
+    StepOver,
+    StepOut,
+    // stops the running code with a Lua error
+    Abort,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PauseReason {
+    Breakpoint(Breakpoint),
+    Step,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub kind: String,
+    pub value: String,
+}
+
+// The view a front-end gets of the paused program. Levels count from 0, the
+// function that is about to run `line`.
+pub struct Session<'a> {
+    ctx: &'a Context,
+    reason: PauseReason,
+    info: DebugInfo,
+    // a copy of the debugger's breakpoints; `edits` are applied to the
+    // debugger once the frontend returns
+    breakpoints: BTreeSet<Breakpoint>,
+    edits: Vec<Edit>,
+}
+
+enum Edit {
+    Add(Breakpoint),
+    Remove(Breakpoint),
+    Clear(String),
+}
+
+impl<'a> Session<'a> {
+    pub fn context(&self) -> &Context {
+        self.ctx
+    }
+
+    pub fn reason(&self) -> &PauseReason {
+        &self.reason
+    }
+
+    pub fn chunk(&self) -> String {
+        self.info.chunk()
+    }
+
+    pub fn line(&self) -> i32 {
+        self.info.currentline
+    }
+
+    pub fn backtrace(&self) -> Vec<DebugInfo> {
+        let mut frames = Vec::new();
+        while let Some(info) = self.ctx.stack_info(frames.len() as i32) {
+            frames.push(info);
+        }
+        frames
+    }
+
+    pub fn locals(&self, level: i32) -> Vec<Variable> {
+        let mut vars = Vec::new();
+
+        unsafe {
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+            if ffi::lua_getstack(self.ctx.handle, level, &mut ar) == 0 {
+                return vars;
+            }
+
+            let mut n = 1;
+            loop {
+                let name = ffi::lua_getlocal(self.ctx.handle, &ar, n);
+                if name.is_null() {
+                    break;
+                }
+
+                let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+                // internal slots such as "(for index)" and "(*temporary)"
+                if !name.starts_with('(') {
+                    vars.push(variable(self.ctx, name));
+                }
+                self.ctx.pop_discard(1);
+                n += 1;
+            }
+        }
+        vars
+    }
+
+    pub fn upvalues(&self, level: i32) -> Vec<Variable> {
+        let mut vars = Vec::new();
+
+        unsafe {
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+            if ffi::lua_getstack(self.ctx.handle, level, &mut ar) == 0 {
+                return vars;
+            }
+            ffi::lua_getinfo(self.ctx.handle, c_str!("f"), &mut ar);
+
+            let mut n = 1;
+            loop {
+                let name = ffi::lua_getupvalue(self.ctx.handle, -1, n);
+                if name.is_null() {
+                    break;
+                }
+
+                let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+                vars.push(variable(self.ctx, name));
+                self.ctx.pop_discard(1);
+                n += 1;
+            }
+            self.ctx.pop_discard(1);
+        }
+        vars
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<Breakpoint> {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, bp: Breakpoint) {
+        self.breakpoints.insert(bp.clone());
+        self.edits.push(Edit::Add(bp));
+    }
+
+    pub fn remove_breakpoint(&mut self, bp: &Breakpoint) -> bool {
+        self.edits.push(Edit::Remove(bp.clone()));
+        self.breakpoints.remove(bp)
+    }
+
+    pub fn clear_breakpoints(&mut self, chunk: &str) {
+        self.breakpoints.retain(|bp| bp.chunk != chunk);
+        self.edits.push(Edit::Clear(chunk.to_string()));
+    }
+
+    // evaluates `expr` with the locals and upvalues of the function at
+    // `level` in scope, falling back to globals
+    pub fn evaluate(&self, level: i32, expr: &str) -> Result<String, String> {
+        let handle = self.ctx.handle;
+
+        unsafe {
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+            if ffi::lua_getstack(handle, level, &mut ar) == 0 {
+                return Err(format!("no function at level {}", level));
+            }
+
+            let code = format!("return {}", expr);
+            if ffi::luaL_loadbuffer(handle, code.as_ptr() as _, code.len(), c_str!("=eval")) != 0 {
+                self.ctx.pop_discard(1);
+                if ffi::luaL_loadbuffer(handle, expr.as_ptr() as _, expr.len(), c_str!("=eval")) != 0 {
+                    return Err(self.ctx.pop::<String>());
+                }
+            }
+
+            ffi::lua_newtable(handle);
+            ffi::lua_getinfo(handle, c_str!("f"), &mut ar);
+            let mut n = 1;
+            loop {
+                let name = ffi::lua_getupvalue(handle, -1, n);
+                if name.is_null() {
+                    break;
+                }
+                ffi::lua_setfield(handle, -3, name);
+                n += 1;
+            }
+            self.ctx.pop_discard(1);
+
+            let mut n = 1;
+            loop {
+                let name = ffi::lua_getlocal(handle, &ar, n);
+                if name.is_null() {
+                    break;
+                }
+                match *name == b'(' as libc::c_char {
+                    true => self.ctx.pop_discard(1),
+                    false => ffi::lua_setfield(handle, -2, name),
+                }
+                n += 1;
+            }
+
+            ffi::lua_createtable(handle, 0, 1);
+            ffi::lua_pushvalue(handle, ffi::LUA_GLOBALSINDEX);
+            ffi::lua_setfield(handle, -2, c_str!("__index"));
+            ffi::lua_setmetatable(handle, -2);
+            ffi::lua_setfenv(handle, -2);
+
+            match ffi::lua_pcall(handle, 0, 1, 0) {
+                0 => {
+                    let ret = value::preview(self.ctx, -1);
+                    self.ctx.pop_discard(1);
+                    Ok(ret)
+                }
+                _ => Err(self.ctx.pop::<String>())
+            }
+        }
+    }
+}
+
+fn variable(ctx: &Context, name: String) -> Variable {
+    let kind = unsafe {
+        let t = ffi::lua_type(ctx.handle, -1);
+        CStr::from_ptr(ffi::lua_typename(ctx.handle, t)).to_string_lossy().into_owned()
+    };
+
+    Variable { name, kind, value: value::preview(ctx, -1) }
+}
+
+pub trait Frontend {
+    // called whenever the debugger pauses; the program resumes according to
+    // the returned command
+    fn paused(&mut self, session: &mut Session) -> Command;
+}
+
+impl<F> Frontend for F where F: FnMut(&mut Session) -> Command {
+    fn paused(&mut self, session: &mut Session) -> Command {
+        self(session)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Run,
+    In,
+    Over(i32),
+    Out(i32),
+}
+
+struct DebuggerState {
+    breakpoints: BTreeSet<Breakpoint>,
+    step: Step,
+    // taken out while the frontend runs
+    frontend: Option<Box<dyn Frontend>>,
+}
+
+// A line-stepping debugger driven by a `Frontend`. Attaching installs the
+// debug hook, replacing any other hook on the state.
+pub struct Debugger {
+    state: Rc<RefCell<DebuggerState>>,
+}
+
+impl Debugger {
+    pub fn new<F>(frontend: F) -> Self
+        where F: Frontend + 'static
+    {
+        Debugger {
+            state: Rc::new(RefCell::new(DebuggerState {
+                breakpoints: BTreeSet::new(),
+                step: Step::Run,
+                frontend: Some(Box::new(frontend)),
+            })),
+        }
+    }
+
+    pub fn add_breakpoint(&self, bp: Breakpoint) {
+        self.state.borrow_mut().breakpoints.insert(bp);
+    }
+
+    pub fn remove_breakpoint(&self, bp: &Breakpoint) -> bool {
+        self.state.borrow_mut().breakpoints.remove(bp)
+    }
+
+    pub fn clear_breakpoints(&self, chunk: &str) {
+        self.state.borrow_mut().breakpoints.retain(|bp| bp.chunk != chunk);
+    }
+
+    // pause on the first line that runs after attaching
+    pub fn break_on_entry(&self) {
+        self.state.borrow_mut().step = Step::In;
+    }
+
+    pub fn attach(&self, ctx: &Context) {
+        let state = self.state.clone();
+
+        ctx.set_hook(HookMask::LINE, 0, move |ctx, event| {
+            let line = match event.kind() {
+                HookEvent::Line(line) => line,
+                _ => return
+            };
+
+            let depth = stack_depth(ctx);
+
+            // the frontend runs without the state borrowed, so it can use the
+            // `Debugger` and run code through the session
+            let (reason, info, breakpoints, mut frontend) = {
+                let mut state = state.borrow_mut();
+
+                // code run by a paused frontend doesn't pause again
+                if state.frontend.is_none() {
+                    return;
+                }
+
+                let stepped = match state.step {
+                    Step::Run => false,
+                    Step::In => true,
+                    Step::Over(d) => depth <= d,
+                    Step::Out(d) => depth < d,
+                };
+                if !stepped && !state.breakpoints.iter().any(|bp| bp.line == line) {
+                    return;
+                }
+
+                let info = event.info();
+                let reason = match stepped {
+                    true => PauseReason::Step,
+                    false => {
+                        let bp = Breakpoint::new(&info.chunk(), line);
+                        match state.breakpoints.contains(&bp) {
+                            true => PauseReason::Breakpoint(bp),
+                            false => return
+                        }
+                    }
+                };
+
+                (reason, info, state.breakpoints.clone(), state.frontend.take().unwrap())
+            };
+
+            let mut session = Session {
+                ctx,
+                reason,
+                info,
+                breakpoints,
+                edits: Vec::new(),
+            };
+            let command = frontend.paused(&mut session);
+
+            let mut state = state.borrow_mut();
+            state.frontend = Some(frontend);
+            for edit in session.edits {
+                match edit {
+                    Edit::Add(bp) => { state.breakpoints.insert(bp); }
+                    Edit::Remove(bp) => { state.breakpoints.remove(&bp); }
+                    Edit::Clear(chunk) => state.breakpoints.retain(|bp| bp.chunk != chunk),
+                }
+            }
+
+            state.step = match command {
+                Command::Continue => Step::Run,
+                Command::StepIn => Step::In,
+                Command::StepOver => Step::Over(depth),
+                Command::StepOut => Step::Out(depth),
+                Command::Abort => {
+                    state.step = Step::Run;
+                    drop(state);
+                    ctx.error("aborted by debugger");
+                    return;
+                }
+            };
+        });
+    }
+
+    pub fn detach(&self, ctx: &Context) {
+        ctx.remove_hook();
+        self.state.borrow_mut().step = Step::Run;
+    }
+}
+
+fn stack_depth(ctx: &Context) -> i32 {
+    unsafe {
+        let mut ar: ffi::lua_Debug = mem::zeroed();
+        let mut depth = 0;
+        while ffi::lua_getstack(ctx.handle, depth, &mut ar) != 0 {
+            depth += 1;
+        }
+        depth
+    }
+}
+
+// A line based front-end, e.g. `TerminalFrontend::stdio()` for an
+// interactive prompt. Type `help` at the prompt for the commands.
+pub struct TerminalFrontend<R, W> {
+    input: R,
+    output: W,
+}
+
+impl TerminalFrontend<io::BufReader<io::Stdin>, io::Stdout> {
+    pub fn stdio() -> Self {
+        TerminalFrontend::new(io::BufReader::new(io::stdin()), io::stdout())
+    }
+}
+
+impl<R, W> TerminalFrontend<R, W>
+        where R: BufRead,
+              W: Write {
+    pub fn new(input: R, output: W) -> Self {
+        TerminalFrontend { input, output }
+    }
+
+    fn prompt(&mut self, session: &mut Session) -> io::Result<Command> {
+        match session.reason() {
+            PauseReason::Breakpoint(bp) => writeln!(self.output, "breakpoint {}", bp)?,
+            &PauseReason::Step => writeln!(self.output, "{}:{}", session.chunk(), session.line())?,
+        }
+
+        loop {
+            write!(self.output, "(flu) ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(Command::Continue);
+            }
+
+            let mut words = line.split_whitespace();
+            let level = |arg: Option<&str>| arg.and_then(|l| l.parse().ok()).unwrap_or(0);
+
+            match words.next() {
+                Some("c") | Some("continue") => return Ok(Command::Continue),
+                Some("s") | Some("step") => return Ok(Command::StepIn),
+                Some("n") | Some("next") => return Ok(Command::StepOver),
+                Some("f") | Some("finish") => return Ok(Command::StepOut),
+                Some("q") | Some("quit") => return Ok(Command::Abort),
+                Some("bt") | Some("backtrace") => {
+                    for (i, frame) in session.backtrace().iter().enumerate() {
+                        let name = frame.name.as_ref().map(|n| &n[..]).unwrap_or("?");
+                        writeln!(self.output, "#{} {}:{} in {}", i, frame.chunk(), frame.currentline, name)?;
+                    }
+                }
+                Some("l") | Some("locals") => {
+                    for var in session.locals(level(words.next())) {
+                        writeln!(self.output, "{} = {}", var.name, var.value)?;
+                    }
+                }
+                Some("u") | Some("upvalues") => {
+                    for var in session.upvalues(level(words.next())) {
+                        writeln!(self.output, "{} = {}", var.name, var.value)?;
+                    }
+                }
+                Some("p") | Some("print") => {
+                    let expr = words.collect::<Vec<_>>().join(" ");
+                    match session.evaluate(0, &expr) {
+                        Ok(val) => writeln!(self.output, "{}", val)?,
+                        Err(e) => writeln!(self.output, "error: {}", e)?,
+                    }
+                }
+                Some("b") | Some("break") => match words.next().map(Breakpoint::from_str) {
+                    Some(Ok(bp)) => session.add_breakpoint(bp),
+                    Some(Err(e)) => writeln!(self.output, "{}", e)?,
+                    None => for bp in session.breakpoints() {
+                        writeln!(self.output, "{}", bp)?;
+                    }
+                },
+                Some("d") | Some("delete") => match words.next().map(Breakpoint::from_str) {
+                    Some(Ok(bp)) => { session.remove_breakpoint(&bp); }
+                    Some(Err(e)) => writeln!(self.output, "{}", e)?,
+                    None => writeln!(self.output, "usage: delete chunk:line")?,
+                },
+                None => {}
+                Some(_) => {
+                    writeln!(self.output, "c(ontinue) s(tep) n(ext) f(inish) q(uit) bt l(ocals) [level] \
+                                           u(pvalues) [level] p(rint) expr b(reak) [chunk:line] d(elete) chunk:line")?;
+                }
+            }
+        }
+    }
+}
+
+impl<R, W> Frontend for TerminalFrontend<R, W>
+        where R: BufRead,
+              W: Write {
+    fn paused(&mut self, session: &mut Session) -> Command {
+        self.prompt(session).unwrap_or(Command::Abort)
+    }
+}
+
+#[test]
+fn breakpoints_and_stepping() {
+    let ctx = Context::new();
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    let l = log.clone();
+    let mut commands = vec![Command::StepIn, Command::StepOver, Command::StepOver, Command::StepOut, Command::Continue].into_iter();
+    let debugger = Debugger::new(move |session: &mut Session| {
+        let locals: Vec<String> = session.locals(0).into_iter()
+            .filter(|v| v.kind != "function")
+            .map(|v| format!("{}={}", v.name, v.value))
+            .collect();
+        l.borrow_mut().push((session.line(), locals.join(" ")));
+
+        commands.next().unwrap()
+    });
+    debugger.add_breakpoint("test.lua:2".parse().unwrap());
+    debugger.attach(&ctx);
+
+    ctx.eval_chunk("local function add(a, b)\n\
+                    local c = a + b\n\
+                    return c\n\
+                    end\n\
+                    local x = add(1, 2)\n\
+                    local y = add(x, 3)\n\
+                    local z = y", "@test.lua").unwrap();
+    debugger.detach(&ctx);
+
+    assert_eq!(*log.borrow(), vec![
+        (2, "a=1 b=2".to_string()),
+        (3, "a=1 b=2 c=3".to_string()),
+        (6, "x=3".to_string()),
+        (2, "a=3 b=3".to_string()),
+        (7, "x=3 y=6".to_string()),
+    ]);
+}
+
+#[test]
+fn frontend_runs_code() {
+    let ctx = Context::new();
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    let l = log.clone();
+    let debugger = Debugger::new(move |session: &mut Session| {
+        // the call runs over the breakpoint without pausing again
+        l.borrow_mut().push(session.evaluate(0, "add(a, 10)").unwrap());
+        session.remove_breakpoint(&Breakpoint::new("test.lua", 2));
+        Command::Continue
+    });
+    debugger.add_breakpoint(Breakpoint::new("test.lua", 2));
+    debugger.attach(&ctx);
+
+    ctx.eval_chunk("function add(a, b)\n\
+                    return a + b\n\
+                    end\n\
+                    add(1, 2)\n\
+                    add(3, 4)", "@test.lua").unwrap();
+    debugger.detach(&ctx);
+
+    assert_eq!(*log.borrow(), vec!["11".to_string()]);
+    assert!(debugger.state.borrow().breakpoints.is_empty());
+}
+
+#[test]
+fn terminal() {
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let ctx = Context::new();
+    let input = io::Cursor::new("l\nu\nb test.lua:4\nn\nl\np m + n\nbt\nq\n".as_bytes().to_vec());
+    let output = Rc::new(RefCell::new(Vec::new()));
+
+    let debugger = Debugger::new(TerminalFrontend::new(input, Output(output.clone())));
+    debugger.add_breakpoint(Breakpoint::new("test.lua", 3));
+    debugger.attach(&ctx);
+
+    let err = ctx.eval_chunk("local n = 10\n\
+                              local function f()\n\
+                              local m = n * 2\n\
+                              return m\n\
+                              end\n\
+                              local r = f()\n\
+                              return r", "@test.lua").unwrap_err();
+    debugger.detach(&ctx);
+
+    let output = String::from_utf8(output.borrow().clone()).unwrap();
+    assert_eq!(format!("{}", err), "aborted by debugger");
+    assert!(output.starts_with("breakpoint test.lua:3\n"));
+    assert!(output.contains("n = 10\n"));
+    assert!(output.contains("m = 20\n"));
+    assert!(output.contains("(flu) 30\n"));
+    assert!(output.contains("#0 test.lua:4 in f\n"));
+    assert!(output.contains("#1 test.lua:6 in ?\n"));
+}
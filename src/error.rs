@@ -0,0 +1,285 @@
+use Context;
+use LuaRef;
+use Table;
+use UserData;
+use UserDataRef;
+use ffi;
+
+use stack::Read;
+use stack::Push;
+
+use libc;
+
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::mem;
+use std::ptr;
+
+#[derive(Debug)]
+pub enum LuaError<'a> {
+    // the value passed to `error`, kept alive in the registry
+    Runtime(LuaRef<'a>),
+    Syntax(String),
+    Memory,
+    // storing under a `nil` or NaN key
+    InvalidKey(&'static str),
+}
+
+impl<'a> LuaError<'a> {
+    // pops the error value left by a failed `lua_pcall` or `lua_load`
+    pub fn pop(ctx: &'a Context, status: i32) -> Self {
+        match status {
+            ffi::LUA_ERRSYNTAX => LuaError::Syntax(ctx.pop::<String>()),
+            ffi::LUA_ERRMEM => {
+                ctx.pop_discard(1);
+                LuaError::Memory
+            }
+            ffi::LUA_ERRRUN |
+            ffi::LUA_ERRERR => LuaError::Runtime(ctx.pop::<LuaRef>()),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn value(&self) -> Option<&LuaRef<'a>> {
+        match self {
+            LuaError::Runtime(r) => Some(r),
+            _ => None
+        }
+    }
+
+    pub fn table(&self) -> Option<Table<'a>> {
+        self.value().and_then(|r| {
+            let ctx = r.context();
+            r.push(ctx);
+
+            match Table::check(ctx, -1) {
+                true => Some(ctx.pop::<Table>()),
+                false => {
+                    ctx.pop_discard(1);
+                    None
+                }
+            }
+        })
+    }
+
+    // the userdata passed to `error`, if it holds a `T`; panics like
+    // `Context::userdata` would fail if it is mutably borrowed
+    pub fn userdata<T: UserData>(&self) -> Option<UserDataRef<'a, T>> {
+        self.value().and_then(|r| {
+            let ctx = r.context();
+            r.push(ctx);
+
+            match <UserDataRef<T>>::check(ctx, -1) {
+                true => Some(ctx.pop::<UserDataRef<T>>()),
+                false => {
+                    ctx.pop_discard(1);
+                    None
+                }
+            }
+        })
+    }
+
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+        where E: Error + 'static
+    {
+        self.rust_error().and_then(|e| e.downcast_ref::<E>())
+    }
+
+    pub fn rust_error(&self) -> Option<&(dyn Error + 'static)> {
+        self.value().and_then(|r| {
+            let ctx = r.context();
+            r.push(ctx);
+
+            // the userdata stays referenced by `r` for as long as `self` lives
+            let err = unsafe { to_rust_error(ctx, -1) };
+            ctx.pop_discard(1);
+            err
+        })
+    }
+}
+
+impl<'a> fmt::Display for LuaError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaError::Syntax(msg) => write!(f, "syntax error: {}", msg),
+            &LuaError::Memory => write!(f, "memory allocation error"),
+            &LuaError::InvalidKey(key) => write!(f, "table index is {}", key),
+            LuaError::Runtime(r) => {
+                if let Some(err) = self.rust_error() {
+                    return write!(f, "{}", err);
+                }
+
+                let ctx = r.context();
+                r.push(ctx);
+
+                let ret = unsafe {
+                    match ffi::lua_type(ctx.handle, -1) {
+                        ffi::LUA_TSTRING |
+                        ffi::LUA_TNUMBER => write!(f, "{}", String::read(ctx, -1)),
+                        t => {
+                            let name = CStr::from_ptr(ffi::lua_typename(ctx.handle, t));
+                            write!(f, "error object is a {} value", name.to_string_lossy())
+                        }
+                    }
+                };
+                ctx.pop_discard(1);
+                ret
+            }
+        }
+    }
+}
+
+// pushes the value a runtime error was raised with, or the message of any
+// other error, so a callback can raise it again
+impl<'a> Push for LuaError<'a> {
+    fn push(&self, ctx: &Context) {
+        match *self {
+            LuaError::Runtime(ref r) => r.push(ctx),
+            ref other => other.to_string().push(ctx),
+        }
+    }
+}
+
+impl<'a> Error for LuaError<'a> {
+    fn description(&self) -> &str {
+        match *self {
+            LuaError::Runtime(..) => "runtime error",
+            LuaError::Syntax(..) => "syntax error",
+            LuaError::Memory => "memory allocation error",
+            LuaError::InvalidKey(..) => "invalid table key",
+        }
+    }
+}
+
+// a Rust error is boxed inside a full userdata so it can travel through Lua
+pub unsafe fn push_rust_error(ctx: &Context, err: Box<dyn Error + 'static>) {
+    let ud = ffi::lua_newuserdata(ctx.handle, mem::size_of::<Box<dyn Error>>() as libc::size_t);
+    ptr::write(ud as *mut Box<dyn Error>, err);
+
+    if ffi::luaL_newmetatable(ctx.handle, c_str!("flu.RustError")) != 0 {
+        ffi::lua_pushcfunction(ctx.handle, rust_error_gc);
+        ffi::lua_setfield(ctx.handle, -2, c_str!("__gc"));
+        ffi::lua_pushcfunction(ctx.handle, rust_error_tostring);
+        ffi::lua_setfield(ctx.handle, -2, c_str!("__tostring"));
+    }
+
+    ffi::lua_setmetatable(ctx.handle, -2);
+}
+
+unsafe fn to_rust_error<'b>(ctx: &Context, idx: i32) -> Option<&'b (dyn Error + 'static)> {
+    let ud = ffi::lua_touserdata(ctx.handle, idx);
+    if ud.is_null() || ffi::lua_getmetatable(ctx.handle, idx) == 0 {
+        return None;
+    }
+
+    ffi::luaL_getmetatable(ctx.handle, c_str!("flu.RustError"));
+    let same = ffi::lua_rawequal(ctx.handle, -1, -2) != 0;
+    ctx.pop_discard(2);
+
+    match same {
+        true => Some(&**(ud as *const Box<dyn Error>)),
+        false => None
+    }
+}
+
+unsafe extern "C" fn rust_error_gc(state: *mut ffi::lua_State) -> libc::c_int {
+    let ud = ffi::lua_touserdata(state, 1);
+    ptr::drop_in_place(ud as *mut Box<dyn Error>);
+    0
+}
+
+unsafe extern "C" fn rust_error_tostring(state: *mut ffi::lua_State) -> libc::c_int {
+    let msg = {
+        let ud = ffi::lua_touserdata(state, 1);
+        format!("{}", &**(ud as *const Box<dyn Error>))
+    };
+    ffi::lua_pushlstring(state, msg.as_ptr() as _, msg.len());
+    1
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+struct TestError(i32);
+
+#[cfg(test)]
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "test error {}", self.0)
+    }
+}
+
+#[cfg(test)]
+impl Error for TestError {}
+
+#[test]
+fn table_error() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    let err = ctx.eval("error({ code = 42 })").unwrap_err();
+
+    assert_eq!(ctx.size(), 0);
+    assert_eq!(err.table().unwrap().get::<i32, _>("code"), 42);
+    assert_eq!(format!("{}", err), "error object is a table value");
+}
+
+#[test]
+fn userdata_error() {
+    use UserDataRegistry;
+
+    struct Code(i32);
+
+    impl UserData for Code {
+        const NAME: &'static str = "Code";
+
+        fn register(registry: &mut UserDataRegistry<Self>) {
+            registry.function("new", |ctx| {
+                let code = ctx.pop::<i32>();
+                ctx.push_userdata(Code(code));
+                1
+            });
+        }
+    }
+
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+    ctx.register::<Code>();
+
+    let err = ctx.eval("error(Code.new(42))").unwrap_err();
+    assert_eq!(err.userdata::<Code>().unwrap().0, 42);
+    assert!(err.table().is_none());
+
+    let err = ctx.eval("error({})").unwrap_err();
+    assert!(err.userdata::<Code>().is_none());
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn syntax_error() {
+    let ctx = Context::new();
+
+    match ctx.eval("local = 5") {
+        Err(LuaError::Syntax(..)) => {}
+        e => panic!("expected syntax error, got {:?}", e)
+    }
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn rust_error() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.set("fail", |ctx: &mut Context| {
+        let code = ctx.pop::<i32>();
+        ctx.raise(TestError(code))
+    });
+
+    ctx.eval("local ok, e = pcall(fail, 3) msg = tostring(e)").unwrap();
+    assert_eq!(ctx.get::<String>("msg"), "test error 3");
+
+    let err = ctx.eval("fail(7)").unwrap_err();
+    assert_eq!(err.downcast_ref::<TestError>(), Some(&TestError(7)));
+    assert_eq!(format!("{}", err), "test error 7");
+}
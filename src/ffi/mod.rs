@@ -1,6 +1,7 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![allow(improper_ctypes)]
+#![allow(clippy::missing_safety_doc)]
 
 use libc::{c_int, c_char, c_void, c_double, size_t, ptrdiff_t};
 
@@ -25,6 +26,26 @@ pub const LUA_ERRERR: c_int =    5;
 
 pub const LUA_MULTRET: c_int = -1;
 
+pub const LUA_GCSTOP: c_int =       0;
+pub const LUA_GCRESTART: c_int =    1;
+pub const LUA_GCCOLLECT: c_int =    2;
+pub const LUA_GCCOUNT: c_int =      3;
+pub const LUA_GCCOUNTB: c_int =     4;
+pub const LUA_GCSTEP: c_int =       5;
+pub const LUA_GCSETPAUSE: c_int =   6;
+pub const LUA_GCSETSTEPMUL: c_int = 7;
+
+pub const LUA_HOOKCALL: c_int =    0;
+pub const LUA_HOOKRET: c_int =     1;
+pub const LUA_HOOKLINE: c_int =    2;
+pub const LUA_HOOKCOUNT: c_int =   3;
+pub const LUA_HOOKTAILRET: c_int = 4;
+
+pub const LUA_MASKCALL: c_int =  1 << LUA_HOOKCALL;
+pub const LUA_MASKRET: c_int =   1 << LUA_HOOKRET;
+pub const LUA_MASKLINE: c_int =  1 << LUA_HOOKLINE;
+pub const LUA_MASKCOUNT: c_int = 1 << LUA_HOOKCOUNT;
+
 pub const LUA_TNONE: c_int = -1;
 pub const LUA_TNIL: c_int = 0;
 pub const LUA_TBOOLEAN: c_int = 1;
@@ -47,16 +68,16 @@ pub struct ZIO;
 
 #[repr(C)]
 pub struct lua_Debug {
-    event: c_int,
-    name: *const c_char,
-    namewhat: *const c_char,
-    what: *const c_char,
-    source: *const c_char,
-    currentline: c_int,
-    nups: c_int,
-    linedefined: c_int,
-    lastlinedefined: c_int,
-    short_src: [c_char; 60],
+    pub event: c_int,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub currentline: c_int,
+    pub nups: c_int,
+    pub linedefined: c_int,
+    pub lastlinedefined: c_int,
+    pub short_src: [c_char; 60],
     i_ci: c_int,
 }
 
@@ -176,8 +197,8 @@ extern "C" {
     pub fn lua_upvalueid(L: *mut lua_State, fidx: c_int, n: c_int) -> *const c_void;
     pub fn lua_upvaluejoin(L: *mut lua_State, fidx1: c_int, n1: c_int, fidx2: c_int, n2: c_int);
 
-    pub fn lua_sethook(L: *mut lua_State, func: lua_Hook, mask: c_int, count: c_int) -> c_int;
-    pub fn lua_gethook(L: *mut lua_State) -> lua_Hook;
+    pub fn lua_sethook(L: *mut lua_State, func: Option<lua_Hook>, mask: c_int, count: c_int) -> c_int;
+    pub fn lua_gethook(L: *mut lua_State) -> Option<lua_Hook>;
     pub fn lua_gethookmask(L: *mut lua_State) -> c_int;
     pub fn lua_gethookcount(L: *mut lua_State) -> c_int;
 
@@ -193,8 +214,10 @@ extern "C" {
     pub fn luaopen_package(L: *mut lua_State) -> c_int;
     pub fn luaL_openlibs(L: *mut lua_State);
 
+    pub fn luaL_getmetafield(L: *mut lua_State, obj: c_int, e: *const c_char) -> c_int;
     pub fn luaL_newmetatable(L: *mut lua_State, s: *const c_char) -> c_int;
     pub fn luaL_loadstring(L: *mut lua_State, s: *const c_char) -> c_int;
+    pub fn luaL_loadbuffer(L: *mut lua_State, buff: *const c_char, sz: size_t, name: *const c_char) -> c_int;
 
     pub fn luaL_ref(L: *mut lua_State, t: c_int) -> c_int;
     pub fn luaL_unref(L: *mut lua_State, t: c_int, tref: c_int);
@@ -291,6 +314,11 @@ pub unsafe fn lua_tostring(L: *mut lua_State, i: c_int) -> *const c_char {
     lua_tolstring(L, i, ptr::null_mut())
 }
 
+#[inline(always)]
+pub unsafe fn luaL_getmetatable(L: *mut lua_State, n: *const c_char) {
+    lua_getfield(L, LUA_REGISTRYINDEX, n);
+}
+
 #[inline(always)]
 pub unsafe fn luaL_dostring(L: *mut lua_State, s: *const c_char) -> bool {
     luaL_loadstring(L, s) != 0 || lua_pcall(L, 0, LUA_MULTRET, 0) != 0
@@ -1,8 +1,11 @@
 use Context;
+use LuaError;
 use LuaRef;
-use Table;
+use LuaValue;
 use ffi;
-use nil;
+
+use error;
+use prototype::Prototype;
 
 use stack::Read;
 use stack::Push;
@@ -10,18 +13,35 @@ use stack::Size;
 
 use libc;
 
+use std::any::Any;
+use std::error::Error;
+use std::ffi::CStr;
 use std::ptr;
 use std::mem;
-use std::marker::PhantomData;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionInfo {
+    // "Lua", "C" or "main"
+    pub what: String,
+    pub source: String,
+    pub short_src: String,
+    pub linedefined: i32,
+    pub lastlinedefined: i32,
+    pub nups: i32,
+    // C functions take any number of arguments, so they report 0 fixed
+    // parameters and are always vararg
+    pub numparams: u8,
+    pub is_vararg: bool,
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Function<'a> {
-    ctx: &'a Context,
-    ptr: LuaRef<'a>
+    pub(crate) ctx: &'a Context,
+    pub(crate) ptr: LuaRef<'a>
 }
 
 impl<'a> Function<'a> {
-    pub fn call<T: Push + Size, R: Read<'a> + Size>(&self, args: T) -> Result<R, &'a str> {
+    pub fn call<T: Push + Size, R: Read<'a> + Size>(&self, args: T) -> Result<R, LuaError<'a>> {
         self.ptr.push(self.ctx);
         self.ctx.push(args);
 
@@ -30,12 +50,78 @@ impl<'a> Function<'a> {
 
             match ret {
                 0 => Ok(R::read(self.ctx, -1)),
-                ffi::LUA_ERRRUN |
-                ffi::LUA_ERRMEM |
-                ffi::LUA_ERRERR => Err(self.ctx.pop::<&str>()),
-                _ => unreachable!()
+                err => Err(LuaError::pop(self.ctx, err))
+            }
+        }
+    }
+
+    pub fn info(&self) -> FunctionInfo {
+        self.ptr.push(self.ctx);
+        let proto = Prototype::dump(self.ctx, -1);
+
+        unsafe {
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+            ffi::lua_getinfo(self.ctx.handle, c_str!(">Su"), &mut ar);
+
+            let string = |s: *const libc::c_char| CStr::from_ptr(s).to_string_lossy().into_owned();
+
+            FunctionInfo {
+                what: string(ar.what),
+                source: string(ar.source),
+                short_src: string(ar.short_src.as_ptr()),
+                linedefined: ar.linedefined,
+                lastlinedefined: ar.lastlinedefined,
+                nups: ar.nups,
+                numparams: proto.as_ref().map(|p| p.numparams).unwrap_or(0),
+                is_vararg: proto.as_ref().map(|p| p.is_vararg).unwrap_or(true),
+            }
+        }
+    }
+
+    // names and values of the upvalues, in order; C functions have no names
+    pub fn upvalues(&self) -> Vec<(String, LuaValue<'a>)> {
+        let mut upvalues = Vec::new();
+        self.ptr.push(self.ctx);
+
+        unsafe {
+            let mut n = 1;
+            loop {
+                let name = ffi::lua_getupvalue(self.ctx.handle, -1, n);
+                if name.is_null() {
+                    break;
+                }
+
+                let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+                Vec::push(&mut upvalues, (name, self.ctx.pop::<LuaValue>()));
+                n += 1;
             }
         }
+
+        self.ctx.pop_discard(1);
+        upvalues
+    }
+
+    // replaces upvalue `n` (starting at 1), returning its name or `None` if
+    // there is no such upvalue
+    pub fn set_upvalue<T>(&self, n: i32, val: T) -> Option<String>
+        where T: Push
+    {
+        self.ptr.push(self.ctx);
+        self.ctx.push(val);
+
+        let name = unsafe {
+            let name = ffi::lua_setupvalue(self.ctx.handle, -2, n);
+            match name.is_null() {
+                true => {
+                    self.ctx.pop_discard(1);
+                    None
+                }
+                false => Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+            }
+        };
+
+        self.ctx.pop_discard(1);
+        name
     }
 }
 
@@ -45,13 +131,9 @@ impl<'a> Function<'a> {
 
 impl<'a> Read<'a> for Function<'a> {
     fn read(ctx: &'a Context, idx: i32) -> Self {
-        unsafe {
-            let func: LuaRef<'a> = ctx.remove(idx);
-
-            Function {
-                ctx: ctx,
-                ptr: func
-            }
+        Function {
+            ctx,
+            ptr: LuaRef::read(ctx, idx)
         }
     }
 
@@ -62,36 +144,164 @@ impl<'a> Read<'a> for Function<'a> {
     }
 }
 
+impl<'a> Push for Function<'a> {
+    fn push(&self, ctx: &Context) {
+        self.ptr.push(ctx)
+    }
+}
+
 impl<'a> Size for Function<'a> {
     fn size() -> i32 {
         LuaRef::size()
     }
 }
 
-impl<F> Push for F
-        where for<'a> F: FnMut(&'a mut Context) -> i32 {
+pub trait CallbackReturn {
+    fn ret(self, ctx: &Context) -> i32;
+}
+
+impl CallbackReturn for i32 {
+    fn ret(self, _: &Context) -> i32 {
+        self
+    }
+}
+
+// Strings are raised as Lua strings; any other error is boxed with
+// `Context::raise`, so `LuaError::downcast_ref` can get it back.
+impl<T, E> CallbackReturn for Result<T, E>
+        where T: Push + Size,
+              E: Into<Box<dyn Error>> + 'static {
+    fn ret(self, ctx: &Context) -> i32 {
+        match self {
+            Ok(val) => {
+                ctx.push(val);
+                T::size()
+            }
+            Err(err) => {
+                let any = &err as &dyn Any;
+                if let Some(msg) = any.downcast_ref::<String>() {
+                    return ctx.error(msg.as_str());
+                }
+                if let Some(msg) = any.downcast_ref::<&'static str>() {
+                    return ctx.error(*msg);
+                }
+
+                unsafe {
+                    error::push_rust_error(ctx, err.into());
+                }
+                ctx.raised.set(true);
+                0
+            }
+        }
+    }
+}
+
+// `push` only borrows the closure, so Lua gets its own clone, which is
+// dropped when the function is collected
+impl<F, R> Push for F
+        where for<'a> F: FnMut(&'a mut Context) -> R + Clone + 'static,
+              R: CallbackReturn {
     fn push(&self, ctx: &Context) {
         unsafe {
-            let wrapper = fn_wrapper::<F>;
-            let func: &mut F = mem::transmute(ffi::lua_newuserdata(ctx.handle, mem::size_of::<F>() as libc::size_t));
-            ptr::copy(&self as &F, func, 1);
+            push_closure(ctx, self.clone(), fn_wrapper::<F, R>);
+        }
+    }
+}
+
+// A callback that fails with a `LuaError` borrowed from its own context, e.g.
+// one returned by a nested `Function::call`. The error value is raised as is,
+// so a table or Rust error passed through keeps its identity.
+pub struct TryCallback<F>(F);
+
+// the closure's signature is only inferred when it is passed straight to a
+// function with this bound
+pub fn try_callback<F, T>(f: F) -> TryCallback<F>
+        where for<'a> F: FnMut(&'a mut Context) -> Result<T, LuaError<'a>> + Clone + 'static,
+              T: Push + Size {
+    TryCallback(f)
+}
 
-            ffi::lua_pushcclosure(ctx.handle, wrapper, 1);
+impl<F, T> Push for TryCallback<F>
+        where for<'a> F: FnMut(&'a mut Context) -> Result<T, LuaError<'a>> + Clone + 'static,
+              T: Push + Size {
+    fn push(&self, ctx: &Context) {
+        unsafe {
+            push_closure(ctx, self.0.clone(), try_fn_wrapper::<F, T>);
         }
     }
 }
 
-unsafe extern "C" fn fn_wrapper<F>(L: *mut ffi::lua_State) -> libc::c_int
-        where for<'a> F: FnMut(&'a mut Context) -> i32 {
-    let mut ctx = Context::from_state_weak(L);
-    let func: &mut F = mem::transmute(ffi::lua_touserdata(L, ffi::lua_upvalueindex(1)));
+// Pushes `wrapper` with a userdata owning `func` as its upvalue. The box keeps
+// `func` aligned whatever it captures.
+unsafe fn push_closure<F>(ctx: &Context, func: F, wrapper: ffi::lua_CFunction) {
+    let ud = ffi::lua_newuserdata(ctx.handle, mem::size_of::<Box<F>>() as libc::size_t);
+    ptr::write(ud as *mut Box<F>, Box::new(func));
+
+    ffi::lua_createtable(ctx.handle, 0, 1);
+    ffi::lua_pushcfunction(ctx.handle, closure_gc::<F>);
+    ffi::lua_setfield(ctx.handle, -2, c_str!("__gc"));
+    ffi::lua_setmetatable(ctx.handle, -2);
 
-    func(&mut ctx) as libc::c_int
+    ffi::lua_pushcclosure(ctx.handle, wrapper, 1);
+}
+
+unsafe extern "C" fn closure_gc<F>(state: *mut ffi::lua_State) -> libc::c_int {
+    ptr::drop_in_place(ffi::lua_touserdata(state, 1) as *mut Box<F>);
+    0
+}
+
+unsafe extern "C" fn fn_wrapper<F, R>(state: *mut ffi::lua_State) -> libc::c_int
+        where for<'a> F: FnMut(&'a mut Context) -> R,
+              R: CallbackReturn {
+    let (ret, raised) = {
+        let mut ctx = Context::from_state_weak(state);
+        let func = &mut **(ffi::lua_touserdata(state, ffi::lua_upvalueindex(1)) as *mut Box<F>);
+
+        let ret = func(&mut ctx).ret(&ctx);
+        (ret, ctx.raised.get())
+    };
+
+    // `lua_error` never returns, so only raise once everything above is dropped
+    match raised {
+        true => ffi::lua_error(state),
+        false => ret as libc::c_int
+    }
+}
+
+unsafe extern "C" fn try_fn_wrapper<F, T>(state: *mut ffi::lua_State) -> libc::c_int
+        where for<'a> F: FnMut(&'a mut Context) -> Result<T, LuaError<'a>>,
+              T: Push + Size {
+    let (ret, raised) = {
+        let mut ctx = Context::from_state_weak(state);
+        let func = &mut **(ffi::lua_touserdata(state, ffi::lua_upvalueindex(1)) as *mut Box<F>);
+
+        // the error borrows `ctx`, so it is pushed through its own reference
+        let val = match func(&mut ctx) {
+            Ok(val) => Some(val),
+            Err(err) => {
+                err.push(&Context::from_state_weak(state));
+                None
+            }
+        };
+
+        match val {
+            Some(val) => {
+                ctx.push(val);
+                (T::size(), ctx.raised.get())
+            }
+            None => (0, true)
+        }
+    };
+
+    match raised {
+        true => ffi::lua_error(state),
+        false => ret as libc::c_int
+    }
 }
 
 #[test]
 fn simple() {
-    let mut ctx = Context::new();
+    let ctx = Context::new();
 
     let func = {
         ctx.eval("return function(a) return a * a end").ok();
@@ -103,7 +313,7 @@ fn simple() {
 
 #[test]
 fn rust_fn() {
-    let mut ctx = Context::new();
+    let ctx = Context::new();
 
     ctx.set("foo", |ctx: &mut Context| {
         let a = ctx.pop::<i32>();
@@ -117,7 +327,9 @@ fn rust_fn() {
 
 #[test]
 fn rust_fn_2() {
-    let mut ctx = Context::new();
+    use Table;
+
+    let ctx = Context::new();
 
     let table = Table::new(&ctx);
     table.set("foo", |ctx: &mut Context| {
@@ -137,6 +349,136 @@ fn rust_fn_2() {
 }
 
 
+#[test]
+fn rust_fn_result() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.set("half", |ctx: &mut Context| {
+        let n = ctx.pop::<i32>();
+        match n % 2 {
+            0 => Ok(n / 2),
+            _ => Err(format!("{} is odd", n))
+        }
+    });
+
+    ctx.eval("a = half(10) ok, msg = pcall(half, 3)").unwrap();
+
+    assert_eq!(ctx.get::<i32>("a"), 5);
+    assert!(!ctx.get::<bool>("ok"));
+    assert_eq!(ctx.get::<String>("msg"), "3 is odd");
+
+    let func = ctx.get::<Function>("half");
+    assert_eq!(format!("{}", func.call::<i32, i32>(5).unwrap_err()), "5 is odd");
+}
+
+#[test]
+fn rust_fn_captures() {
+    use std::rc::Rc;
+
+    let count = Rc::new(());
+    let ctx = Context::new();
+
+    {
+        let name = String::from("captured");
+        let count = count.clone();
+        ctx.set("name", move |_: &mut Context| {
+            let _ = &count;
+            Ok::<_, String>(name.clone())
+        });
+    }
+
+    // Lua owns its own copy of the closure and drops it with the state
+    ctx.eval("a = name() b = name()").unwrap();
+    assert_eq!(ctx.get::<String>("a"), "captured");
+    assert_eq!(ctx.get::<String>("b"), "captured");
+    assert_eq!(Rc::strong_count(&count), 2);
+
+    drop(ctx);
+    assert_eq!(Rc::strong_count(&count), 1);
+}
+
+#[test]
+fn rust_fn_error() {
+    use std::num::ParseIntError;
+
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    // Rust errors are raised as themselves rather than as their message
+    ctx.set("parse", |ctx: &mut Context| {
+        ctx.pop::<String>().parse::<i32>()
+    });
+    assert_eq!(ctx.get::<Function>("parse").call::<_, i32>("12").unwrap(), 12);
+    ctx.pop_discard(1);
+
+    let err = ctx.eval("parse('x')").unwrap_err();
+    assert!(err.downcast_ref::<ParseIntError>().is_some());
+    ctx.eval("local ok, e = pcall(parse, 'x') msg = tostring(e)").unwrap();
+    assert_eq!(ctx.get::<String>("msg"), "invalid digit found in string");
+
+    // and errors from nested calls keep their value
+    ctx.set("twice", try_callback(|ctx: &mut Context| {
+        let f = ctx.pop::<Function>();
+        f.call::<i32, Option<i32>>(1)?;
+        f.call::<i32, Option<i32>>(2)
+    }));
+    let err = ctx.eval("twice(function() error({ code = 42 }) end)").unwrap_err();
+    assert_eq!(err.table().unwrap().get::<i32, _>("code"), 42);
+
+    ctx.eval("n = 0 twice(function() n = n + 1 end)").unwrap();
+    assert_eq!(ctx.get::<i32>("n"), 2);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn upvalues() {
+    let ctx = Context::new();
+
+    let func = {
+        ctx.eval("local limit = 10\n\
+                  return function(a) if a > limit then return limit end return a end").unwrap();
+        ctx.pop::<Function>()
+    };
+
+    assert_eq!(func.upvalues(), vec![("limit".to_string(), LuaValue::Number(10f64))]);
+    assert_eq!(func.call::<i32, i32>(50).unwrap(), 10);
+
+    assert_eq!(func.set_upvalue(1, 20), Some("limit".to_string()));
+    assert_eq!(func.set_upvalue(2, 20), None);
+    assert_eq!(func.call::<i32, i32>(50).unwrap(), 20);
+}
+
+#[test]
+fn info() {
+    let ctx = Context::new();
+
+    let func = {
+        ctx.eval_chunk("local x = 1\n\
+                        return function(a, b)\n\
+                        return a + b + x\n\
+                        end", "@handlers.lua").unwrap();
+        ctx.pop::<Function>()
+    };
+
+    assert_eq!(func.info(), FunctionInfo {
+        what: "Lua".to_string(),
+        source: "@handlers.lua".to_string(),
+        short_src: "handlers.lua".to_string(),
+        linedefined: 2,
+        lastlinedefined: 4,
+        nups: 1,
+        numparams: 2,
+        is_vararg: false,
+    });
+
+    ctx.set("native", |_: &mut Context| 0);
+    let native = ctx.get::<Function>("native").info();
+
+    assert_eq!(native.what, "C");
+    assert_eq!((native.numparams, native.is_vararg), (0, true));
+    assert_eq!(ctx.size(), 0);
+}
 
 /*
 #[test]
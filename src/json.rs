@@ -0,0 +1,547 @@
+// JSON text to and from Lua values:
+//
+//     let text = json::encode(&value)?;
+//     let text = Encoder::new().empty_table(EmptyTable::Array).encode(&value)?;
+//     let value = json::decode(&ctx, &text)?;
+//
+// Tables whose keys are exactly `1..n` become arrays, other tables objects
+// with sorted keys; number keys are written as strings. `null` decodes to
+// nil, so it disappears from objects and leaves holes in arrays. `open` adds
+// `json.encode` and `json.decode` for scripts.
+
+use Context;
+use LuaValue;
+use Table;
+use ffi;
+
+use codec::{self, Walk};
+use stack::Push;
+use value::exact_number;
+
+use std::collections::HashSet;
+use std::str;
+
+pub use codec::{CodecError, Position};
+
+// deeper documents are rejected rather than risking the stack
+pub const MAX_DEPTH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyTable {
+    // `{}`
+    Object,
+    // `[]`
+    Array,
+}
+
+// JSON has no literals for NaN and the infinities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonFinite {
+    Error,
+    Null,
+    // `NaN`, `Infinity` and `-Infinity`, which many parsers accept
+    Literal,
+}
+
+pub type JsonError = CodecError;
+
+pub struct Encoder {
+    empty_table: EmptyTable,
+    non_finite: NonFinite,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Encoder::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder { empty_table: EmptyTable::Object, non_finite: NonFinite::Error }
+    }
+
+    pub fn empty_table(mut self, empty: EmptyTable) -> Self {
+        self.empty_table = empty;
+        self
+    }
+
+    pub fn non_finite(mut self, policy: NonFinite) -> Self {
+        self.non_finite = policy;
+        self
+    }
+
+    pub fn encode(&self, value: &LuaValue) -> Result<String, JsonError> {
+        let mut out = String::new();
+        self.value(&mut out, value, &mut Walk::new(MAX_DEPTH))?;
+        Ok(out)
+    }
+
+    fn value(&self, out: &mut String, value: &LuaValue, walk: &mut Walk) -> Result<(), JsonError> {
+        match *value {
+            LuaValue::Nil |
+            LuaValue::None => out.push_str("null"),
+            LuaValue::Bool(b) => out.push_str(&b.to_string()),
+            LuaValue::Number(n) => out.push_str(&self.number(n, walk)?),
+            LuaValue::String(ref s) => string(out, s),
+            LuaValue::Bytes(..) => return Err(walk.error("cannot encode a string that isn't UTF-8")),
+            LuaValue::Table(ref t) => self.table(out, t, walk)?,
+            ref other => return Err(walk.error(&format!("cannot encode a {} value", other.type_name()))),
+        }
+        Ok(())
+    }
+
+    fn number(&self, n: f64, walk: &Walk) -> Result<String, JsonError> {
+        if n.is_finite() {
+            return Ok(number(n));
+        }
+
+        match self.non_finite {
+            NonFinite::Error => Err(walk.error(&format!("cannot encode {}", number(n)))),
+            NonFinite::Null => Ok("null".to_string()),
+            NonFinite::Literal => Ok(number(n)),
+        }
+    }
+
+    fn table(&self, out: &mut String, table: &Table, walk: &mut Walk) -> Result<(), JsonError> {
+        walk.table(table, self.empty_table == EmptyTable::Array, |walk, entries, is_array| {
+            out.push(if is_array { '[' } else { '{' });
+
+            // number keys are written as strings, so they can collide with a
+            // string key; numbers sort first
+            let mut numbers = HashSet::new();
+
+            for (i, (k, v)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+
+                match *k {
+                    LuaValue::Number(..) if is_array => {}
+                    LuaValue::Number(n) if n.is_finite() => {
+                        string(out, &number(n));
+                        out.push(':');
+                        numbers.insert(number(n));
+                    }
+                    LuaValue::String(ref s) if numbers.contains(s) => {
+                        return Err(walk.error(&format!("key {} is both a number and a string", s)));
+                    }
+                    LuaValue::String(ref s) => {
+                        string(out, s);
+                        out.push(':');
+                    }
+                    ref other => return Err(walk.error(&format!("cannot encode a {} key", other.type_name())))
+                }
+
+                walk.key(k, |walk| self.value(out, v, walk))?;
+            }
+
+            out.push(if is_array { ']' } else { '}' });
+            Ok(())
+        })
+    }
+}
+
+// integers without a fraction, everything else as the shortest exact form
+fn number(n: f64) -> String {
+    exact_number(n, ["NaN", "Infinity", "-Infinity"])
+}
+
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+// `Encoder::new().encode(value)`
+pub fn encode(value: &LuaValue) -> Result<String, JsonError> {
+    Encoder::new().encode(value)
+}
+
+pub fn decode<'a>(ctx: &'a Context, text: &str) -> Result<LuaValue<'a>, JsonError> {
+    decode_push(ctx, text.as_bytes())?;
+    Ok(ctx.pop::<LuaValue>())
+}
+
+fn decode_push(ctx: &Context, text: &[u8]) -> Result<(), JsonError> {
+    let mut parser = Parser { ctx, src: text, pos: 0, depth: 0 };
+
+    codec::decode_push(ctx, || {
+        parser.value()?;
+        parser.whitespace();
+        match parser.pos < parser.src.len() {
+            true => Err(parser.error("unexpected data after the value")),
+            false => Ok(())
+        }
+    })
+}
+
+struct Parser<'a, 'b> {
+    ctx: &'a Context,
+    src: &'b [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn error(&self, message: &str) -> JsonError {
+        let before = &self.src[..self.pos.min(self.src.len())];
+        let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+        let start = before.iter().rposition(|&b| b == b'\n').map(|i| i + 1).unwrap_or(0);
+        let column = String::from_utf8_lossy(&before[start..]).chars().count() + 1;
+
+        JsonError::Decode { position: Position::Text { line, column }, message: message.to_string() }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).cloned()
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        self.whitespace();
+        match self.peek() {
+            Some(b) if b == c => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.unexpected(&format!("'{}'", c as char)))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> JsonError {
+        match self.peek() {
+            None => self.error(&format!("expected {}, found the end of the input", expected)),
+            Some(_) => {
+                let c = str::from_utf8(&self.src[self.pos..]).ok().and_then(|s| s.chars().next()).unwrap_or('?');
+                self.error(&format!("expected {}, found '{}'", expected, c))
+            }
+        }
+    }
+
+    // pushes exactly one value
+    fn value(&mut self) -> Result<(), JsonError> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'{') => self.nested(Parser::object),
+            Some(b'[') => self.nested(Parser::array),
+            Some(b'"') => {
+                let s = self.string()?;
+                unsafe {
+                    ffi::lua_pushlstring(self.ctx.handle, s.as_ptr() as *const _, s.len());
+                }
+                Ok(())
+            }
+            Some(b'-') | Some(b'0'..=b'9') => {
+                let n = self.number()?;
+                n.push(self.ctx);
+                Ok(())
+            }
+            Some(b't') => self.literal("true", |ctx| true.push(ctx)),
+            Some(b'f') => self.literal("false", |ctx| false.push(ctx)),
+            Some(b'n') => self.literal("null", |ctx| unsafe { ffi::lua_pushnil(ctx.handle) }),
+            _ => Err(self.unexpected("a value"))
+        }
+    }
+
+    fn nested(&mut self, f: fn(&mut Self) -> Result<(), JsonError>) -> Result<(), JsonError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+
+        if !codec::push_table(self.ctx, 0, 0) {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        self.pos += 1;
+
+        let ret = f(self);
+        self.depth -= 1;
+        ret
+    }
+
+    fn object(&mut self) -> Result<(), JsonError> {
+        self.whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(());
+        }
+
+        loop {
+            self.whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.unexpected("a string key"));
+            }
+            let key = self.string()?;
+            unsafe {
+                ffi::lua_pushlstring(self.ctx.handle, key.as_ptr() as *const _, key.len());
+            }
+
+            self.expect(b':')?;
+            self.value()?;
+            unsafe {
+                ffi::lua_rawset(self.ctx.handle, -3);
+            }
+
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.unexpected("',' or '}'"))
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<(), JsonError> {
+        self.whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(());
+        }
+
+        let mut i = 1;
+        loop {
+            self.value()?;
+            unsafe {
+                ffi::lua_rawseti(self.ctx.handle, -2, i);
+            }
+            i += 1;
+
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.unexpected("',' or ']'"))
+            }
+        }
+    }
+
+    fn literal<F: Fn(&Context)>(&mut self, word: &str, push: F) -> Result<(), JsonError> {
+        match self.src[self.pos..].starts_with(word.as_bytes()) {
+            true => {
+                self.pos += word.len();
+                push(self.ctx);
+                Ok(())
+            }
+            false => Err(self.unexpected("a value"))
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn number(&mut self) -> Result<f64, JsonError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => {
+                self.digits();
+            }
+            _ => return Err(self.unexpected("a digit"))
+        }
+
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if self.digits() == 0 {
+                return Err(self.unexpected("a digit"));
+            }
+        }
+
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return Err(self.unexpected("a digit"));
+            }
+        }
+
+        // the grammar above only lets through text `parse` accepts
+        Ok(str::from_utf8(&self.src[start..self.pos]).unwrap().parse().unwrap())
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.src.get(self.pos..self.pos + 4)
+            .and_then(|d| str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok());
+
+        match digits {
+            Some(n) => {
+                self.pos += 4;
+                Ok(n)
+            }
+            None => Err(self.error("expected four hex digits"))
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, JsonError> {
+        self.pos += 1;
+        let mut out = Vec::new();
+
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\x08',
+                        Some(b'f') => '\x0c',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let escape = self.pos - 2;
+                            let mut n = self.hex4()?;
+
+                            // a surrogate pair, written as two escapes
+                            if (0xd800..0xdc00).contains(&n) && self.src[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if (0xdc00..0xe000).contains(&low) {
+                                    n = 0x10000 + ((n - 0xd800) << 10) + (low - 0xdc00);
+                                }
+                            }
+
+                            match ::std::char::from_u32(n) {
+                                Some(c) => {
+                                    let mut buf = [0; 4];
+                                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                                    continue;
+                                }
+                                None => {
+                                    self.pos = escape;
+                                    return Err(self.error("invalid unicode escape"));
+                                }
+                            }
+                        }
+                        _ => return Err(self.error("invalid escape"))
+                    };
+                    out.extend_from_slice(c.to_string().as_bytes());
+                    self.pos += 1;
+                }
+                Some(b) if b < b' ' => return Err(self.error("control character in string")),
+                Some(b) => {
+                    Vec::push(&mut out, b);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
+
+// Sets the global `json` to a table with `encode(value)` and `decode(text)`,
+// which raise errors instead of returning them.
+pub fn open(ctx: &Context) {
+    codec::open(ctx, "json", |value| encode(value).map(String::into_bytes), decode_push);
+}
+
+#[test]
+fn json_encode() {
+    let ctx = Context::new();
+
+    ctx.eval("return { list = { 1, 2.5, 'three', true }, name = 'q\"\\n\\1', [3] = { a = {} }, empty = {} }").unwrap();
+    let value = ctx.pop::<LuaValue>();
+    assert_eq!(encode(&value).unwrap(), "{\"3\":{\"a\":{}},\"empty\":{},\"list\":[1,2.5,\"three\",true],\"name\":\"q\\\"\\n\\u0001\"}");
+
+    let arrays = Encoder::new().empty_table(EmptyTable::Array).encode(&value).unwrap();
+    assert_eq!(arrays, "{\"3\":{\"a\":[]},\"empty\":[],\"list\":[1,2.5,\"three\",true],\"name\":\"q\\\"\\n\\u0001\"}");
+
+    ctx.eval("return { stats = { hp = 0/0 } }").unwrap();
+    let value = ctx.pop::<LuaValue>();
+    assert_eq!(encode(&value).unwrap_err().to_string(), "cannot encode NaN at stats.hp");
+    assert_eq!(Encoder::new().non_finite(NonFinite::Null).encode(&value).unwrap(), "{\"stats\":{\"hp\":null}}");
+    assert_eq!(Encoder::new().non_finite(NonFinite::Literal).encode(&value).unwrap(), "{\"stats\":{\"hp\":NaN}}");
+
+    ctx.eval("local t = { items = { 1, {} } } t.items[2].back = t return t").unwrap();
+    let err = encode(&ctx.pop::<LuaValue>()).unwrap_err();
+    assert_eq!(err.to_string(), "cannot encode a table that contains itself at items[2].back");
+
+    ctx.eval("return { [true] = 1 }").unwrap();
+    assert_eq!(encode(&ctx.pop::<LuaValue>()).unwrap_err().to_string(), "cannot encode a boolean key");
+
+    ctx.eval("return { ['on-hit'] = { 1, 0/0 } }").unwrap();
+    assert_eq!(encode(&ctx.pop::<LuaValue>()).unwrap_err().to_string(), "cannot encode NaN at [\"on-hit\"][2]");
+
+    ctx.eval("return { list = { [1] = 'a', ['1'] = 'b' } }").unwrap();
+    assert_eq!(encode(&ctx.pop::<LuaValue>()).unwrap_err().to_string(), "key 1 is both a number and a string at list");
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn json_decode() {
+    let ctx = Context::new();
+
+    let value = decode(&ctx, " {\"a\": [1, -2.5e1, null, \"\\u00e9\\ud83d\\ude00\\/\"], \"b\": {\"c\": false}} ").unwrap();
+    assert_eq!(encode(&value).unwrap(), "{\"a\":{\"1\":1,\"2\":-25,\"4\":\"é😀/\"},\"b\":{\"c\":false}}");
+
+    let err = decode(&ctx, "{\"a\": 1,\n \"b\" 2}").unwrap_err();
+    assert_eq!(err.to_string(), "expected ':', found '2' at line 2 column 6");
+
+    let err = decode(&ctx, "[01]").unwrap_err();
+    assert_eq!(err.to_string(), "expected ',' or ']', found '1' at line 1 column 3");
+
+    assert_eq!(decode(&ctx, "\"abc").unwrap_err().to_string(), "unterminated string at line 1 column 5");
+    assert_eq!(decode(&ctx, "[1] x").unwrap_err().to_string(), "unexpected data after the value at line 1 column 5");
+
+    let deep = format!("{}{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1));
+    assert_eq!(decode(&ctx, &deep).unwrap_err().to_string(), format!("nested too deeply at line 1 column {}", MAX_DEPTH + 1));
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn json_library() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+    open(&ctx);
+
+    ctx.eval("local t = json.decode('{\"x\": [1, 2, {\"y\": \"z\"}]}')\n\
+              return json.encode(t.x), t.x[3].y").unwrap();
+    assert_eq!(ctx.pop::<String>(), "z");
+    assert_eq!(ctx.pop::<String>(), "[1,2,{\"y\":\"z\"}]");
+
+    ctx.eval("local ok, err = pcall(json.decode, '[1,') return err").unwrap();
+    assert_eq!(ctx.pop::<String>(), "expected a value, found the end of the input at line 1 column 4");
+
+    ctx.eval("local ok, err = pcall(json.encode, { f = print }) return err").unwrap();
+    assert_eq!(ctx.pop::<String>(), "cannot encode a function value at f");
+    assert_eq!(ctx.size(), 0);
+}
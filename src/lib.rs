@@ -2,41 +2,71 @@
 
 extern crate libc;
 
-macro_rules! assert_enum {
-    (@as_expr $e:expr) => {$e};
-    (@as_pat $p:pat) => {$p};
-    ($left:expr, $($right:tt)*) => (
-        {
-            match &($left) {
-                assert_enum!(@as_pat &$($right)*(..)) => {},
-                _ => {
-                    panic!("assertion failed: `(if let left = right(..))` \
-                           (left: `{:?}`, right: `{:?}`)",
-                           $left,
-                           stringify!($($right)*)
-                           )
-                }
-            }
-        }
-    )
-}
+#[cfg(feature = "dap")]
+#[macro_use]
+extern crate serde_json;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "derive")]
+extern crate flu_derive;
+#[cfg(feature = "derive")]
+pub use flu_derive::*;
 
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
 pub mod ffi;
 pub mod stack;
 
 pub mod collections;
 
+#[cfg(feature = "dap")]
+pub mod dap;
+
+pub mod persist;
+pub mod json;
+pub mod msgpack;
+
+mod codec;
 mod context;
+mod coverage;
+mod debug;
+mod debugger;
+mod error;
 mod value;
+mod owned;
+mod pretty;
 mod borrow;
 mod function;
+mod profiler;
+mod prototype;
+mod thread;
+mod userdata;
+#[cfg(feature = "serde")]
+mod serialize;
 
 pub use context::*;
+pub use coverage::*;
+pub use debug::*;
+pub use debugger::*;
+pub use error::LuaError;
 pub use collections::*;
 pub use value::*;
+pub use owned::*;
+pub use pretty::*;
 pub use borrow::*;
 pub use function::*;
+pub use profiler::*;
+pub use thread::*;
+pub use userdata::*;
+#[cfg(feature = "serde")]
+pub use serialize::*;
 
+#[allow(non_camel_case_types)]
 pub struct nil;
 
 #[macro_export]
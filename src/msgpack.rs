@@ -0,0 +1,435 @@
+// MessagePack to and from Lua values, for moving script state between
+// processes:
+//
+//     let bytes = msgpack::encode(&value)?;
+//     let value = msgpack::decode(&ctx, &bytes)?;
+//
+// Numbers that hold an integer are written as the smallest integer type,
+// others as float 64. Strings are written as `str` when they are UTF-8 and as
+// `bin` otherwise; both decode to Lua strings. Tables whose keys are exactly
+// `1..n` become arrays, other tables maps with sorted keys. `open` adds
+// `msgpack.encode` and `msgpack.decode` for scripts.
+
+use Context;
+use LuaValue;
+use Table;
+use ffi;
+
+use codec::{self, Walk};
+use stack::Push;
+
+pub use codec::{CodecError, Position};
+
+// how deeply nested `decode` lets arrays and maps be, and `encode` tables
+pub const DEFAULT_MAX_DEPTH: usize = 200;
+
+pub type MsgpackError = CodecError;
+
+// the most entries a decoded table is sized for before it starts filling
+const MAX_PREALLOC: usize = 16;
+
+pub fn encode(value: &LuaValue) -> Result<Vec<u8>, MsgpackError> {
+    let mut out = Vec::new();
+    encode_value(&mut out, value, &mut Walk::new(DEFAULT_MAX_DEPTH))?;
+    Ok(out)
+}
+
+fn encode_value(out: &mut Vec<u8>, value: &LuaValue, walk: &mut Walk) -> Result<(), MsgpackError> {
+    match *value {
+        LuaValue::Nil |
+        LuaValue::None => out.push(0xc0),
+        LuaValue::Bool(false) => out.push(0xc2),
+        LuaValue::Bool(true) => out.push(0xc3),
+        LuaValue::Number(n) => number(out, n),
+        LuaValue::String(ref s) => {
+            header(out, s.len(), Some((0xa0, 32)), &[0xd9, 0xda, 0xdb], walk)?;
+            out.extend_from_slice(s.as_bytes());
+        }
+        LuaValue::Bytes(ref b) => {
+            header(out, b.len(), None, &[0xc4, 0xc5, 0xc6], walk)?;
+            out.extend_from_slice(b);
+        }
+        LuaValue::Table(ref t) => encode_table(out, t, walk)?,
+        ref other => return Err(walk.error(&format!("cannot encode a {} value", other.type_name()))),
+    }
+    Ok(())
+}
+
+// an empty table is written as an empty map
+fn encode_table(out: &mut Vec<u8>, table: &Table, walk: &mut Walk) -> Result<(), MsgpackError> {
+    walk.table(table, false, |walk, entries, is_array| {
+        match is_array {
+            true => header(out, entries.len(), Some((0x90, 16)), &[0xdc, 0xdd], walk)?,
+            false => header(out, entries.len(), Some((0x80, 16)), &[0xde, 0xdf], walk)?
+        }
+
+        for (k, v) in &entries {
+            walk.key(k, |walk| {
+                if !is_array {
+                    encode_value(out, k, walk)?;
+                }
+                encode_value(out, v, walk)
+            })?;
+        }
+        Ok(())
+    })
+}
+
+// the type byte and length for strings, binary, arrays and maps: `fix` is
+// the short form and the length it must stay under, `sized` the 8 bit form,
+// if there is one, then the 16 and 32 bit forms; longer values are errors
+fn header(out: &mut Vec<u8>, len: usize, fix: Option<(u8, usize)>, sized: &[u8], walk: &Walk) -> Result<(), MsgpackError> {
+    let n = sized.len();
+    match fix {
+        Some((prefix, max)) if len < max => out.push(prefix | len as u8),
+        _ if n == 3 && len < 0x100 => out.extend_from_slice(&[sized[0], len as u8]),
+        _ if len < 0x10000 => {
+            out.push(sized[n - 2]);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ if len <= 0xffff_ffff => {
+            out.push(sized[n - 1]);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        _ => return Err(walk.error(&format!("cannot encode a length of {}, the limit is 2^32 - 1", len)))
+    }
+    Ok(())
+}
+
+fn number(out: &mut Vec<u8>, n: f64) {
+    // -0 has to stay a float to keep its sign
+    let integer = n == n.trunc() && (-9.223372036854776e18..1.8446744073709552e19).contains(&n) &&
+        !(n == 0.0 && n.is_sign_negative());
+
+    if !integer {
+        out.push(0xcb);
+        out.extend_from_slice(&n.to_bits().to_be_bytes());
+    } else if n >= 0.0 {
+        let n = n as u64;
+        match n {
+            0..=0x7f => out.push(n as u8),
+            0x80..=0xff => out.extend_from_slice(&[0xcc, n as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            0x10000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend_from_slice(&(n as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    } else {
+        let n = n as i64;
+        match n {
+            -32..=-1 => out.push(n as u8),
+            -0x80..=-33 => out.extend_from_slice(&[0xd0, n as u8]),
+            -0x8000..=-0x81 => {
+                out.push(0xd1);
+                out.extend_from_slice(&(n as i16).to_be_bytes());
+            }
+            -0x8000_0000..=-0x8001 => {
+                out.push(0xd2);
+                out.extend_from_slice(&(n as i32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xd3);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    }
+}
+
+// `decode_with_depth(ctx, bytes, DEFAULT_MAX_DEPTH)`
+pub fn decode<'a>(ctx: &'a Context, bytes: &[u8]) -> Result<LuaValue<'a>, MsgpackError> {
+    decode_with_depth(ctx, bytes, DEFAULT_MAX_DEPTH)
+}
+
+pub fn decode_with_depth<'a>(ctx: &'a Context, bytes: &[u8], max_depth: usize) -> Result<LuaValue<'a>, MsgpackError> {
+    decode_push(ctx, bytes, max_depth)?;
+    Ok(ctx.pop::<LuaValue>())
+}
+
+fn decode_push(ctx: &Context, bytes: &[u8], max_depth: usize) -> Result<(), MsgpackError> {
+    let mut decoder = Decoder { ctx, src: bytes, pos: 0, depth: 0, max_depth };
+
+    codec::decode_push(ctx, || {
+        decoder.value()?;
+        match decoder.pos < bytes.len() {
+            true => Err(decoder.error("unexpected data after the value")),
+            false => Ok(())
+        }
+    })
+}
+
+struct Decoder<'a, 'b> {
+    ctx: &'a Context,
+    src: &'b [u8],
+    pos: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a, 'b> Decoder<'a, 'b> {
+    fn error(&self, message: &str) -> MsgpackError {
+        MsgpackError::Decode { position: Position::Byte(self.pos), message: message.to_string() }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'b [u8], MsgpackError> {
+        if self.src.len() - self.pos < n {
+            return Err(self.error("unexpected end of the input"));
+        }
+        let src = self.src;
+        self.pos += n;
+        Ok(&src[self.pos - n..self.pos])
+    }
+
+    fn uint(&mut self, size: usize) -> Result<u64, MsgpackError> {
+        Ok(self.take(size)?.iter().fold(0, |n, &b| (n << 8) | b as u64))
+    }
+
+    // sign-extends the big endian bytes
+    fn int(&mut self, size: usize) -> Result<i64, MsgpackError> {
+        let shift = 64 - size * 8;
+        Ok(((self.uint(size)? << shift) as i64) >> shift)
+    }
+
+    // pushes exactly one value
+    fn value(&mut self) -> Result<(), MsgpackError> {
+        let start = self.pos;
+        let b = self.take(1)?[0];
+
+        let n = match b {
+            0x00..=0x7f => b as f64,
+            0xe0..=0xff => b as i8 as f64,
+            0xcc..=0xcf => self.uint(1 << (b - 0xcc))? as f64,
+            0xd0..=0xd3 => self.int(1 << (b - 0xd0))? as f64,
+            0xca => f32::from_bits(self.uint(4)? as u32) as f64,
+            0xcb => f64::from_bits(self.uint(8)?),
+            0xc0 => {
+                unsafe { ffi::lua_pushnil(self.ctx.handle) };
+                return Ok(());
+            }
+            0xc2 | 0xc3 => {
+                (b == 0xc3).push(self.ctx);
+                return Ok(());
+            }
+            0xa0..=0xbf => return self.string((b & 0x1f) as usize),
+            0xd9 | 0xc4 => {
+                let len = self.uint(1)? as usize;
+                return self.string(len);
+            }
+            0xda | 0xc5 => {
+                let len = self.uint(2)? as usize;
+                return self.string(len);
+            }
+            0xdb | 0xc6 => {
+                let len = self.uint(4)? as usize;
+                return self.string(len);
+            }
+            0x90..=0x9f => return self.nested((b & 0x0f) as usize, false),
+            0x80..=0x8f => return self.nested((b & 0x0f) as usize, true),
+            0xdc..=0xdf => {
+                // 16 bit lengths for 0xdc and 0xde, 32 bit for 0xdd and 0xdf
+                let len = self.uint(if b % 2 == 0 { 2 } else { 4 })? as usize;
+                return self.nested(len, b >= 0xde);
+            }
+            _ => {
+                self.pos = start;
+                return Err(self.error(&format!("unsupported type 0x{:02x}", b)));
+            }
+        };
+
+        n.push(self.ctx);
+        Ok(())
+    }
+
+    fn string(&mut self, len: usize) -> Result<(), MsgpackError> {
+        let bytes = self.take(len)?;
+        unsafe {
+            ffi::lua_pushlstring(self.ctx.handle, bytes.as_ptr() as *const _, bytes.len());
+        }
+        Ok(())
+    }
+
+    fn nested(&mut self, len: usize, map: bool) -> Result<(), MsgpackError> {
+        if self.depth >= self.max_depth {
+            return Err(self.error("nested too deeply"));
+        }
+
+        // every element takes at least a byte, so a hostile length can't
+        // make us allocate more than the input could fill
+        let values = if map { len.saturating_mul(2) } else { len };
+        if values > self.src.len() - self.pos {
+            return Err(self.error("length is longer than the input"));
+        }
+
+        // Lua indexes arrays with an `int`
+        if len > i32::MAX as usize {
+            return Err(self.error("length is too long for a Lua table"));
+        }
+
+        // Every nested header can claim the rest of the input, so only small
+        // tables are sized up front and larger ones grow as they fill.
+        let prealloc = len.min(MAX_PREALLOC) as i32;
+        let pushed = match map {
+            true => codec::push_table(self.ctx, 0, prealloc),
+            false => codec::push_table(self.ctx, prealloc, 0)
+        };
+        if !pushed {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+
+        for i in 0..len {
+            if map {
+                let key = self.pos;
+                self.value()?;
+
+                let bad_key = unsafe {
+                    ffi::lua_isnil(self.ctx.handle, -1) ||
+                        (ffi::lua_type(self.ctx.handle, -1) == ffi::LUA_TNUMBER &&
+                         ffi::lua_tonumber(self.ctx.handle, -1).is_nan())
+                };
+                if bad_key {
+                    self.pos = key;
+                    return Err(self.error("map key can't be nil or NaN"));
+                }
+
+                self.value()?;
+                unsafe { ffi::lua_rawset(self.ctx.handle, -3) };
+            } else {
+                self.value()?;
+                unsafe { ffi::lua_rawseti(self.ctx.handle, -2, i as i32 + 1) };
+            }
+        }
+
+        self.depth -= 1;
+        Ok(())
+    }
+}
+
+// Sets the global `msgpack` to a table with `encode(value)`, which returns a
+// string of bytes, and `decode(bytes)`; both raise errors.
+pub fn open(ctx: &Context) {
+    codec::open(ctx, "msgpack", encode, |ctx, bytes| decode_push(ctx, bytes, DEFAULT_MAX_DEPTH));
+}
+
+#[test]
+fn msgpack_encode() {
+    let ctx = Context::new();
+
+    let numbers = [
+        (0.0, vec![0x00]), (127.0, vec![0x7f]), (128.0, vec![0xcc, 0x80]), (65536.0, vec![0xce, 0, 1, 0, 0]),
+        (-1.0, vec![0xff]), (-33.0, vec![0xd0, 0xdf]), (-129.0, vec![0xd1, 0xff, 0x7f]),
+        (1.5, vec![0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]), (-0.0, vec![0xcb, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+    ];
+    for &(n, ref bytes) in &numbers {
+        assert_eq!(&encode(&LuaValue::Number(n)).unwrap(), bytes, "{}", n);
+    }
+
+    ctx.eval("return { 'a', false, { x = '\\255' }, n = 300 }").unwrap();
+    let value = ctx.pop::<LuaValue>();
+    assert_eq!(encode(&value).unwrap(), [
+        0x84, 0x01, 0xa1, b'a', 0x02, 0xc2, 0x03, 0x81, 0xa1, b'x', 0xc4, 0x01, 0xff,
+        0xa1, b'n', 0xcd, 0x01, 0x2c,
+    ]);
+
+    let long = LuaValue::String("x".repeat(40));
+    assert_eq!(encode(&long).unwrap()[..2], [0xd9, 40]);
+
+    ctx.eval("local t = { list = { 1, 2 } } t.list[3] = t return t").unwrap();
+    let err = encode(&ctx.pop::<LuaValue>()).unwrap_err();
+    assert_eq!(err.to_string(), "cannot encode a table that contains itself at list[3]");
+
+    ctx.eval("return { {}, { 1 } }").unwrap();
+    assert_eq!(encode(&ctx.pop::<LuaValue>()).unwrap(), [0x92, 0x80, 0x91, 0x01]);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn msgpack_long_header() {
+    let mut out = Vec::new();
+    let walk = Walk::new(DEFAULT_MAX_DEPTH);
+
+    header(&mut out, 0xffff_ffff, None, &[0xc4, 0xc5, 0xc6], &walk).unwrap();
+    assert_eq!(out, [0xc6, 0xff, 0xff, 0xff, 0xff]);
+
+    let err = header(&mut out, 0x1_0000_0000, None, &[0xc4, 0xc5, 0xc6], &walk).unwrap_err();
+    assert_eq!(err.to_string(), "cannot encode a length of 4294967296, the limit is 2^32 - 1");
+}
+
+#[test]
+fn msgpack_decode() {
+    let ctx = Context::new();
+
+    ctx.eval("return { 'a', 2.5, -70000, { deep = { true } }, ['\\0bin'] = '\\1\\2', [1e20] = 'big' }").unwrap();
+    let value = ctx.pop::<LuaValue>();
+    let bytes = encode(&value).unwrap();
+    assert_eq!(encode(&decode(&ctx, &bytes).unwrap()).unwrap(), bytes);
+
+    assert_eq!(decode(&ctx, &[0xca, 0x3f, 0xc0, 0, 0]).unwrap(), LuaValue::Number(1.5));
+    assert_eq!(decode(&ctx, &[0xd3, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]).unwrap(), LuaValue::Number(-2.0));
+
+    let err = decode(&ctx, &[0x92, 0x01, 0xcd, 0x01]).unwrap_err();
+    assert_eq!(err.to_string(), "unexpected end of the input at byte 3");
+
+    let err = decode(&ctx, &[0xdd, 0xff, 0xff, 0xff, 0xff, 0x01]).unwrap_err();
+    assert_eq!(err.to_string(), "length is longer than the input at byte 5");
+
+    let err = decode(&ctx, &[0x81, 0xc0, 0x01]).unwrap_err();
+    assert_eq!(err.to_string(), "map key can't be nil or NaN at byte 1");
+
+    assert_eq!(decode(&ctx, &[0xc1]).unwrap_err().to_string(), "unsupported type 0xc1 at byte 0");
+    assert_eq!(decode(&ctx, &[0xc0, 0xc0]).unwrap_err().to_string(), "unexpected data after the value at byte 1");
+
+    let err = decode_with_depth(&ctx, &[0x91, 0x91, 0x91, 0x90], 3).unwrap_err();
+    assert_eq!(err.to_string(), "nested too deeply at byte 4");
+    assert!(decode_with_depth(&ctx, &[0x91, 0x91, 0x90], 3).is_ok());
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn msgpack_hostile_lengths() {
+    let ctx = Context::new();
+
+    // every header claims about the whole input, which must not be
+    // allocated up front at each level
+    let mut bytes = Vec::new();
+    for _ in 0..150 {
+        Vec::push(&mut bytes, 0xdd);
+        bytes.extend_from_slice(&((1u32 << 16) - 1000).to_be_bytes());
+    }
+    bytes.resize(1 << 16, 0xc0);
+
+    let before = unsafe { ffi::lua_gc(ctx.handle, ffi::LUA_GCCOUNT, 0) };
+    assert!(decode(&ctx, &bytes).is_err());
+    let after = unsafe { ffi::lua_gc(ctx.handle, ffi::LUA_GCCOUNT, 0) };
+    assert!(after - before < 1024, "decoding used {} KiB", after - before);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn msgpack_library() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+    open(&ctx);
+
+    ctx.eval("local s = msgpack.encode({ 1, 'two', { three = 3 } })\n\
+              local t = msgpack.decode(s)\n\
+              return #s, t[2], t[3].three").unwrap();
+    assert_eq!(ctx.pop::<i32>(), 3);
+    assert_eq!(ctx.pop::<String>(), "two");
+    assert_eq!(ctx.pop::<i32>(), 14);
+
+    ctx.eval("local ok, err = pcall(msgpack.decode, '\\146\\1') return err").unwrap();
+    assert_eq!(ctx.pop::<String>(), "length is longer than the input at byte 1");
+
+    ctx.eval("local ok, err = pcall(msgpack.decode, {}) return err").unwrap();
+    assert_eq!(ctx.pop::<String>(), "bad argument #1 to 'decode' (string expected, got table)");
+    assert_eq!(ctx.size(), 0);
+}
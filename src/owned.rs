@@ -0,0 +1,378 @@
+use Context;
+use LuaValue;
+use Table;
+use ffi;
+
+use stack::Push;
+use stack::Size;
+use stack::read::key_segment;
+
+use libc;
+
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::slice;
+
+// deeper tables are rejected rather than risking the Rust and Lua stacks
+const MAX_DEPTH: usize = 1000;
+
+// A deep copy of a Lua value that doesn't borrow the `Context`, so it can be
+// stored, sent to another thread or pushed into a different state.
+#[derive(Clone, Debug)]
+pub enum OwnedValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    // Lua strings are byte strings and aren't required to be UTF-8
+    String(Vec<u8>),
+    // entries in `next` order; the order is ignored when comparing tables.
+    // Keys can't be nil or NaN, pushing a table with one panics.
+    Table(Vec<(OwnedValue, OwnedValue)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OwnedError {
+    // the table at `path` is the same table as its ancestor at `target`
+    Cycle { path: String, target: String },
+    // functions, userdata and threads only exist inside their state
+    Unsupported { path: String, found: String },
+    // the table at `path` is nested more than 1000 deep, or the Lua
+    // stack can't grow enough to copy it
+    TooDeep { path: String },
+}
+
+impl fmt::Display for OwnedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OwnedError::Cycle { path, target } => {
+                match target.is_empty() {
+                    true => write!(f, "table at {} refers back to the root table", display_path(path)),
+                    false => write!(f, "table at {} refers back to its ancestor at {}", display_path(path), display_path(target))
+                }
+            }
+            OwnedError::Unsupported { path, found } => {
+                match path.is_empty() {
+                    true => write!(f, "cannot take ownership of a {} value", found),
+                    false => write!(f, "cannot take ownership of a {} value at {}", found, display_path(path))
+                }
+            }
+            OwnedError::TooDeep { path } => write!(f, "table at {} is nested too deeply", display_path(path)),
+        }
+    }
+}
+
+impl Error for OwnedError {
+    fn description(&self) -> &str {
+        match *self {
+            OwnedError::Cycle { .. } => "table cycle",
+            OwnedError::Unsupported { .. } => "value can't be owned",
+            OwnedError::TooDeep { .. } => "tables nested too deeply",
+        }
+    }
+}
+
+fn display_path(path: &str) -> &str {
+    path.trim_start_matches('.')
+}
+
+impl OwnedValue {
+    // copies the value at `idx`, recursing into tables
+    pub fn from_stack(ctx: &Context, idx: i32) -> Result<OwnedValue, OwnedError> {
+        let top = ctx.size();
+        let idx = match idx < 0 && idx > ffi::LUA_REGISTRYINDEX {
+            true => top + idx + 1,
+            false => idx
+        };
+
+        let mut path = String::new();
+        let ret = copy(ctx, idx, &mut path, &mut Vec::new());
+
+        // a failed copy can leave keys and values of the tables it was in
+        unsafe {
+            ffi::lua_settop(ctx.handle, top);
+        }
+        ret
+    }
+
+    pub fn from_value(value: &LuaValue) -> Result<OwnedValue, OwnedError> {
+        match value {
+            &LuaValue::Nil |
+            &LuaValue::None => Ok(OwnedValue::Nil),
+            &LuaValue::Bool(b) => Ok(OwnedValue::Bool(b)),
+            &LuaValue::Number(n) => Ok(OwnedValue::Number(n)),
+            LuaValue::String(s) => Ok(OwnedValue::String(s.as_bytes().to_vec())),
+            LuaValue::Bytes(b) => Ok(OwnedValue::String(b.clone())),
+            LuaValue::Table(t) => OwnedValue::from_table(t),
+            other => Err(OwnedError::Unsupported { path: String::new(), found: other.type_name().to_string() }),
+        }
+    }
+
+    pub fn from_table(table: &Table) -> Result<OwnedValue, OwnedError> {
+        table.ptr.push(table.ctx);
+        let ret = OwnedValue::from_stack(table.ctx, -1);
+        table.ctx.pop_discard(1);
+        ret
+    }
+
+    // the string as UTF-8, if it is a valid UTF-8 string
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            OwnedValue::String(s) => ::std::str::from_utf8(s).ok(),
+            _ => None
+        }
+    }
+
+    // looks up `key` in a table, comparing keys by value
+    pub fn get(&self, key: &OwnedValue) -> Option<&OwnedValue> {
+        match self {
+            OwnedValue::Table(entries) => entries.iter().find(|e| e.0 == *key).map(|e| &e.1),
+            _ => None
+        }
+    }
+}
+
+// `ancestors` holds each table being copied along with the length of `path`
+// at that table
+unsafe fn copy_table(ctx: &Context, idx: i32, path: &mut String, ancestors: &mut Vec<(*const libc::c_void, usize)>)
+    -> Result<OwnedValue, OwnedError>
+{
+    let ptr = ffi::lua_topointer(ctx.handle, idx);
+    if let Some(&(_, len)) = ancestors.iter().find(|a| a.0 == ptr) {
+        return Err(OwnedError::Cycle { path: path.clone(), target: path[..len].to_string() });
+    }
+    ancestors.push((ptr, path.len()));
+
+    // the key, the value and whatever a nested table pushes on top
+    if ancestors.len() > MAX_DEPTH || ffi::lua_checkstack(ctx.handle, 3) == 0 {
+        return Err(OwnedError::TooDeep { path: path.clone() });
+    }
+
+    let mut entries = Vec::new();
+    ffi::lua_pushnil(ctx.handle);
+    while ffi::lua_next(ctx.handle, idx) != 0 {
+        let len = path.len();
+        path.push_str(&key_segment(ctx, -2));
+
+        let top = ctx.size();
+        let key = copy(ctx, top - 1, path, ancestors)?;
+        let value = copy(ctx, top, path, ancestors)?;
+        Vec::push(&mut entries, (key, value));
+
+        path.truncate(len);
+        ctx.pop_discard(1);
+    }
+
+    ancestors.pop();
+    Ok(OwnedValue::Table(entries))
+}
+
+fn copy(ctx: &Context, idx: i32, path: &mut String, ancestors: &mut Vec<(*const libc::c_void, usize)>)
+    -> Result<OwnedValue, OwnedError>
+{
+    unsafe {
+        match ffi::lua_type(ctx.handle, idx) {
+            ffi::LUA_TNONE |
+            ffi::LUA_TNIL => Ok(OwnedValue::Nil),
+            ffi::LUA_TBOOLEAN => Ok(OwnedValue::Bool(ffi::lua_toboolean(ctx.handle, idx) != 0)),
+            ffi::LUA_TNUMBER => Ok(OwnedValue::Number(ffi::lua_tonumber(ctx.handle, idx))),
+            ffi::LUA_TSTRING => {
+                let mut len = 0;
+                let s = ffi::lua_tolstring(ctx.handle, idx, &mut len);
+                Ok(OwnedValue::String(slice::from_raw_parts(s as *const u8, len).to_vec()))
+            }
+            ffi::LUA_TTABLE => copy_table(ctx, idx, path, ancestors),
+            t => {
+                let name = CStr::from_ptr(ffi::lua_typename(ctx.handle, t));
+                Err(OwnedError::Unsupported { path: path.clone(), found: name.to_string_lossy().into_owned() })
+            }
+        }
+    }
+}
+
+// # Panics
+//
+// Panics if a table has a nil or NaN key, which Lua tables can't hold, or if
+// the Lua stack can't grow enough for how deeply the tables are nested.
+// Values copied out of Lua never do either, and nothing is pushed when it
+// panics.
+impl Push for OwnedValue {
+    fn push(&self, ctx: &Context) {
+        let depth = self.check_keys();
+
+        // each table being filled holds its slot and one for a key, plus
+        // the key and value of the innermost entry
+        let slots = depth.saturating_mul(2).saturating_add(2).min(i32::MAX as usize);
+        unsafe {
+            assert!(ffi::lua_checkstack(ctx.handle, slots as i32) != 0, "OwnedValue is nested too deeply to push");
+        }
+
+        self.push_checked(ctx);
+    }
+}
+
+impl OwnedValue {
+    // how deeply tables are nested, panicking on a key `lua_rawset` would
+    // raise for
+    fn check_keys(&self) -> usize {
+        match self {
+            OwnedValue::Table(entries) => {
+                let mut depth = 0;
+                for (k, v) in entries {
+                    match *k {
+                        OwnedValue::Nil => panic!("OwnedValue table has a nil key"),
+                        OwnedValue::Number(n) if n.is_nan() => panic!("OwnedValue table has a NaN key"),
+                        _ => {}
+                    }
+                    depth = depth.max(k.check_keys()).max(v.check_keys());
+                }
+                depth + 1
+            }
+            _ => 0
+        }
+    }
+
+    fn push_checked(&self, ctx: &Context) {
+        unsafe {
+            match self {
+                &OwnedValue::Nil => ffi::lua_pushnil(ctx.handle),
+                &OwnedValue::Bool(b) => ffi::lua_pushboolean(ctx.handle, b as i32),
+                &OwnedValue::Number(n) => ffi::lua_pushnumber(ctx.handle, n),
+                OwnedValue::String(s) => ffi::lua_pushlstring(ctx.handle, s.as_ptr() as *const _, s.len()),
+                OwnedValue::Table(entries) => {
+                    ffi::lua_createtable(ctx.handle, 0, entries.len() as i32);
+                    for (k, v) in entries {
+                        k.push_checked(ctx);
+                        v.push_checked(ctx);
+                        ffi::lua_rawset(ctx.handle, -3);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for OwnedValue {
+    fn eq(&self, other: &OwnedValue) -> bool {
+        match (self, other) {
+            (&OwnedValue::Nil, &OwnedValue::Nil) => true,
+            (&OwnedValue::Bool(a), &OwnedValue::Bool(b)) => a == b,
+            (&OwnedValue::Number(a), &OwnedValue::Number(b)) => a == b,
+            (OwnedValue::String(a), OwnedValue::String(b)) => a == b,
+            (OwnedValue::Table(a), OwnedValue::Table(b)) => {
+                a.len() == b.len() && a.iter().all(|(k, v)| other.get(k) == Some(v))
+            }
+            _ => false
+        }
+    }
+}
+
+impl Size for OwnedValue {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl From<bool> for OwnedValue {
+    fn from(b: bool) -> Self {
+        OwnedValue::Bool(b)
+    }
+}
+
+impl From<f64> for OwnedValue {
+    fn from(n: f64) -> Self {
+        OwnedValue::Number(n)
+    }
+}
+
+impl<'a> From<&'a str> for OwnedValue {
+    fn from(s: &'a str) -> Self {
+        OwnedValue::String(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for OwnedValue {
+    fn from(s: String) -> Self {
+        OwnedValue::String(s.into_bytes())
+    }
+}
+
+#[test]
+fn owned_round_trip() {
+    let value = {
+        let ctx = Context::new();
+        ctx.eval("return { 1, 2, name = 'rat', stats = { hp = 3, alive = true }, ['\\255'] = 'bytes' }").unwrap();
+        let value = OwnedValue::from_stack(&ctx, -1).unwrap();
+        ctx.pop_discard(1);
+        assert_eq!(ctx.size(), 0);
+        value
+    };
+
+    assert_eq!(value.get(&"name".into()).and_then(|v| v.as_str()), Some("rat"));
+    assert_eq!(value.get(&2.0.into()), Some(&OwnedValue::Number(2.0)));
+    assert_eq!(value.get(&OwnedValue::String(vec![255])), Some(&"bytes".into()));
+
+    let ctx = Context::new();
+    ctx.set("t", value.clone());
+    ctx.eval("return t[1] + t[2] + t.stats.hp, t.stats.alive, t['\\255']").unwrap();
+    assert_eq!(ctx.pop::<String>(), "bytes");
+    assert!(ctx.pop::<bool>());
+    assert_eq!(ctx.pop::<i32>(), 6);
+
+    ctx.eval("return t").unwrap();
+    assert_eq!(OwnedValue::from_stack(&ctx, -1), Ok(value));
+}
+
+#[test]
+fn owned_errors() {
+    let ctx = Context::new();
+
+    ctx.eval("local t = { a = { b = {} } } t.a.b.c = t.a return t").unwrap();
+    let err = OwnedValue::from_stack(&ctx, -1).unwrap_err();
+    assert_eq!(err, OwnedError::Cycle { path: ".a.b.c".to_string(), target: ".a".to_string() });
+    assert_eq!(err.to_string(), "table at a.b.c refers back to its ancestor at a");
+    assert_eq!(ctx.size(), 1);
+
+    ctx.eval("local t = {} t[1] = { t } return t").unwrap();
+    let err = OwnedValue::from_stack(&ctx, -1).unwrap_err();
+    assert_eq!(err.to_string(), "table at [1][1] refers back to the root table");
+
+    // a table reachable twice without a cycle is copied twice
+    ctx.eval("local shared = { 1 } return { shared, shared }").unwrap();
+    assert!(OwnedValue::from_stack(&ctx, -1).is_ok());
+
+    ctx.eval("return { handlers = { ['on-hit'] = function() end } }").unwrap();
+    let table = ctx.pop::<Table>();
+    let err = OwnedValue::from_table(&table).unwrap_err();
+    assert_eq!(err.to_string(), "cannot take ownership of a function value at handlers[\"on-hit\"]");
+
+    ctx.pop_discard(3);
+    assert_eq!(ctx.size(), 0);
+
+    ctx.eval("local t = {} for i = 1, 2000 do t = { t } end return t").unwrap();
+    match OwnedValue::from_stack(&ctx, -1) {
+        Err(OwnedError::TooDeep { ref path }) => assert_eq!(path, &"[1]".repeat(MAX_DEPTH)),
+        other => panic!("expected TooDeep, got {:?}", other.map(|_| ()))
+    }
+    ctx.pop_discard(1);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn owned_invalid_keys() {
+    use std::panic::{self, AssertUnwindSafe};
+
+    let ctx = Context::new();
+
+    let nested = |key: OwnedValue| {
+        OwnedValue::Table(vec![("inner".into(), OwnedValue::Table(vec![(key, 1.0.into())]))])
+    };
+    let push = |value: OwnedValue| {
+        let err = panic::catch_unwind(AssertUnwindSafe(|| ctx.push(value))).unwrap_err();
+        *err.downcast_ref::<&str>().unwrap()
+    };
+
+    assert_eq!(push(nested(OwnedValue::Nil)), "OwnedValue table has a nil key");
+    assert_eq!(push(nested(OwnedValue::Number(f64::NAN))), "OwnedValue table has a NaN key");
+    assert_eq!(ctx.size(), 0);
+}
@@ -0,0 +1,462 @@
+// Saves tables as Lua source and loads them back without running code.
+//
+//     let src = persist::to_lua_source(&save)?;
+//     let save = persist::load_data(&ctx, &src)?;
+//
+// Tables referenced more than once, including through cycles, are created up
+// front in a `refs` local and filled in afterwards, so the loaded graph has
+// the same shape. Metatables aren't saved.
+
+use Context;
+use LuaValue;
+use Table;
+use ffi;
+
+use pretty::{compare_keys, is_name, quote, table_ptr};
+use prototype::Prototype;
+use stack::read::value_segment;
+use value::exact_number;
+
+use libc;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::ptr;
+
+// enough for data files of several megabytes
+pub const DEFAULT_INSTRUCTION_LIMIT: i32 = 10_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PersistError {
+    // a function, userdata or thread, which can't be written as data
+    Unsupported { path: String, found: String },
+    Syntax(String),
+    Runtime(String),
+    // the data file tried to call a function
+    Call,
+    // the data file does something other than build tables, e.g. loops,
+    // concatenates or uses globals
+    Code { line: i32 },
+    InstructionLimit,
+    // `load_data_with_limit` was given a limit below 1
+    InvalidLimit(i32),
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PersistError::Unsupported { path, found } => {
+                match path.is_empty() {
+                    true => write!(f, "cannot persist a {} value", found),
+                    false => write!(f, "cannot persist a {} value at {}", found, path.trim_start_matches('.'))
+                }
+            }
+            PersistError::Syntax(msg) => write!(f, "syntax error: {}", msg),
+            PersistError::Runtime(msg) => write!(f, "{}", msg),
+            &PersistError::Call => write!(f, "data files can't call functions"),
+            &PersistError::Code { line } => write!(f, "data files can only build tables, line {} runs other code", line),
+            &PersistError::InstructionLimit => write!(f, "data file exceeded the instruction limit"),
+            &PersistError::InvalidLimit(limit) => write!(f, "the instruction limit must be positive, got {}", limit),
+        }
+    }
+}
+
+impl Error for PersistError {
+    fn description(&self) -> &str {
+        match *self {
+            PersistError::Unsupported { .. } => "value can't be persisted",
+            PersistError::Syntax(..) => "syntax error",
+            PersistError::Runtime(..) => "runtime error",
+            PersistError::Call => "function call in data file",
+            PersistError::Code { .. } => "code in data file",
+            PersistError::InstructionLimit => "instruction limit exceeded",
+            PersistError::InvalidLimit(..) => "invalid instruction limit",
+        }
+    }
+}
+
+struct Node<'a> {
+    entries: Vec<(LuaValue<'a>, LuaValue<'a>)>,
+    // how many times the table is referenced, counting the root once
+    refs: usize,
+}
+
+struct Graph<'a> {
+    nodes: Vec<Node<'a>>,
+    index: HashMap<*const libc::c_void, usize>,
+    // the slot in `refs` of each shared node
+    shared: HashMap<usize, usize>,
+}
+
+impl<'a> Graph<'a> {
+    fn visit(&mut self, value: &LuaValue<'a>, path: &mut String) -> Result<(), PersistError> {
+        match value {
+            LuaValue::Table(t) => self.visit_table(t, path),
+            &LuaValue::Nil |
+            &LuaValue::None |
+            &LuaValue::Bool(..) |
+            &LuaValue::Number(..) |
+            &LuaValue::String(..) |
+            &LuaValue::Bytes(..) => Ok(()),
+            other => Err(PersistError::Unsupported { path: path.clone(), found: other.type_name().to_string() })
+        }
+    }
+
+    fn visit_table(&mut self, table: &Table<'a>, path: &mut String) -> Result<(), PersistError> {
+        let ptr = table_ptr(table);
+        if let Some(&node) = self.index.get(&ptr) {
+            self.nodes[node].refs += 1;
+            return Ok(());
+        }
+
+        // registered before the children, which may refer back to it
+        let node = self.nodes.len();
+        self.index.insert(ptr, node);
+        self.nodes.push(Node { entries: Vec::new(), refs: 1 });
+
+        let mut entries = table.entries();
+        entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+
+        for (k, v) in &entries {
+            let len = path.len();
+            path.push_str(&value_segment(k));
+            self.visit(k, path)?;
+            self.visit(v, path)?;
+            path.truncate(len);
+        }
+
+        self.nodes[node].entries = entries;
+        Ok(())
+    }
+
+    fn expr(&self, out: &mut String, value: &LuaValue, depth: usize) {
+        match value {
+            &LuaValue::Bool(b) => out.push_str(&b.to_string()),
+            &LuaValue::Number(n) => out.push_str(&number(n)),
+            LuaValue::String(s) => quote(out, s.as_bytes()),
+            LuaValue::Bytes(b) => quote(out, b),
+            LuaValue::Table(t) => self.table(out, t, depth),
+            _ => out.push_str("nil"),
+        }
+    }
+
+    // a reference to a shared table, or its constructor
+    fn table(&self, out: &mut String, table: &Table, depth: usize) {
+        let node = self.index[&table_ptr(table)];
+        match self.shared.get(&node) {
+            Some(slot) => out.push_str(&format!("refs[{}]", slot)),
+            None => self.constructor(out, node, depth)
+        }
+    }
+
+    fn constructor(&self, out: &mut String, node: usize, depth: usize) {
+        let entries = &self.nodes[node].entries;
+        if entries.is_empty() {
+            out.push_str("{}");
+            return;
+        }
+
+        out.push_str("{\n");
+
+        let mut next = 1.0;
+        for (k, v) in entries {
+            indent(out, depth + 1);
+            match k {
+                &LuaValue::Number(n) if n == next => next += 1.0,
+                LuaValue::String(s) if is_name(s) => {
+                    out.push_str(s);
+                    out.push_str(" = ");
+                }
+                _ => {
+                    out.push('[');
+                    self.expr(out, k, depth + 1);
+                    out.push_str("] = ");
+                }
+            }
+            self.expr(out, v, depth + 1);
+            out.push_str(",\n");
+        }
+
+        indent(out, depth);
+        out.push('}');
+    }
+}
+
+fn indent(out: &mut String, depth: usize) {
+    out.extend((0..depth * 2).map(|_| ' '));
+}
+
+// there are no literals for these
+fn number(n: f64) -> String {
+    exact_number(n, ["0/0", "1/0", "-1/0"])
+}
+
+// Writes a chunk that rebuilds `table` when loaded with `load_data`. Fails
+// on functions, userdata and threads.
+pub fn to_lua_source(table: &Table) -> Result<String, PersistError> {
+    let mut graph = Graph { nodes: Vec::new(), index: HashMap::new(), shared: HashMap::new() };
+    graph.visit_table(table, &mut String::new())?;
+
+    let mut slot = 0;
+    for (i, node) in graph.nodes.iter().enumerate() {
+        if node.refs > 1 {
+            slot += 1;
+            graph.shared.insert(i, slot);
+        }
+    }
+
+    let mut out = String::new();
+    if slot > 0 {
+        out.push_str("local refs = {}\n");
+        for i in 1..slot + 1 {
+            out.push_str(&format!("refs[{}] = {{}}\n", i));
+        }
+
+        let mut shared: Vec<(usize, usize)> = graph.shared.iter().map(|(&node, &slot)| (slot, node)).collect();
+        shared.sort();
+
+        for &(slot, node) in &shared {
+            for (k, v) in &graph.nodes[node].entries {
+                out.push_str(&format!("refs[{}]", slot));
+                match k {
+                    LuaValue::String(s) if is_name(s) => {
+                        out.push('.');
+                        out.push_str(s);
+                    }
+                    _ => {
+                        out.push('[');
+                        graph.expr(&mut out, k, 0);
+                        out.push(']');
+                    }
+                }
+                out.push_str(" = ");
+                graph.expr(&mut out, v, 0);
+                out.push('\n');
+            }
+        }
+    }
+
+    out.push_str("return ");
+    graph.table(&mut out, table, 0);
+    out.push('\n');
+    Ok(out)
+}
+
+pub fn load_data<'a>(ctx: &'a Context, src: &str) -> Result<LuaValue<'a>, PersistError> {
+    load_data_with_limit(ctx, src, DEFAULT_INSTRUCTION_LIMIT)
+}
+
+// Runs `src` in an empty environment on a separate thread, so hooks set on
+// `ctx` are left alone. The chunk may only build tables out of constants:
+// calling functions, jumping, concatenating and using globals are errors, as
+// is running more than `limit` VM instructions. That keeps the time and
+// memory needed to load it in proportion to its size. `limit` must be at
+// least 1.
+pub fn load_data_with_limit<'a>(ctx: &'a Context, src: &str, limit: i32) -> Result<LuaValue<'a>, PersistError> {
+    if limit <= 0 {
+        return Err(PersistError::InvalidLimit(limit));
+    }
+
+    // precompiled chunks can crash the VM
+    if src.starts_with('\x1b') {
+        return Err(PersistError::Syntax("binary chunks can't be loaded as data".to_string()));
+    }
+
+    unsafe {
+        let thread = ffi::lua_newthread(ctx.handle);
+
+        let ret = match ffi::luaL_loadbuffer(thread, src.as_ptr() as *const _, src.len(), c_str!("=data")) {
+            0 => check_data(thread).and_then(|()| {
+                ffi::lua_newtable(thread);
+                ffi::lua_setfenv(thread, -2);
+                ffi::lua_sethook(thread, Some(data_hook), ffi::LUA_MASKCALL | ffi::LUA_MASKCOUNT, limit);
+
+                match ffi::lua_pcall(thread, 0, 1, 0) {
+                    0 => {
+                        ffi::lua_xmove(thread, ctx.handle, 1);
+                        Ok(ctx.remove::<LuaValue>(-1))
+                    }
+                    _ => Err(match ffi::lua_touserdata(thread, -1) as *const u8 {
+                        p if ptr::eq(p, &CALL) => PersistError::Call,
+                        p if ptr::eq(p, &LIMIT) => PersistError::InstructionLimit,
+                        _ => PersistError::Runtime(message(thread))
+                    })
+                }
+            }),
+            ffi::LUA_ERRSYNTAX => Err(PersistError::Syntax(message(thread))),
+            _ => Err(PersistError::Runtime(message(thread)))
+        };
+
+        // the thread, and with it anything left on its stack
+        ctx.pop_discard(1);
+        ret
+    }
+}
+
+// the Lua 5.1 opcodes `check_data` looks at
+const OP_MOVE: u32 = 0;
+const OP_LOADK: u32 = 1;
+const OP_LOADBOOL: u32 = 2;
+const OP_LOADNIL: u32 = 3;
+const OP_GETTABLE: u32 = 6;
+const OP_SETTABLE: u32 = 9;
+const OP_NEWTABLE: u32 = 10;
+const OP_SELF: u32 = 11;
+const OP_DIV: u32 = 15;
+const OP_UNM: u32 = 18;
+const OP_CALL: u32 = 28;
+const OP_TAILCALL: u32 = 29;
+const OP_RETURN: u32 = 30;
+const OP_SETLIST: u32 = 34;
+
+// Accepts the instructions `to_lua_source` output compiles to: constants,
+// `refs` locals, table constructors and lookups, and the division and
+// negation in `1/0`, `-1/0` and `0/0`. Without jumps every instruction runs
+// at most once.
+unsafe fn check_data(thread: *mut ffi::lua_State) -> Result<(), PersistError> {
+    let proto = Prototype::dump(&Context::from_state_weak(thread), -1)
+        .ok_or_else(|| PersistError::Runtime("cannot inspect the data chunk".to_string()))?;
+
+    let mut pc = 0;
+    while pc < proto.code.len() {
+        let op = proto.code[pc];
+        match op & 0x3f {
+            OP_CALL | OP_TAILCALL | OP_SELF => return Err(PersistError::Call),
+            // with C = 0 the next word holds the block number, not an instruction
+            OP_SETLIST if (op >> 14) & 0x1ff == 0 => pc += 1,
+            OP_MOVE | OP_LOADK | OP_LOADBOOL | OP_LOADNIL | OP_GETTABLE | OP_SETTABLE |
+            OP_NEWTABLE | OP_DIV | OP_UNM | OP_RETURN | OP_SETLIST => {}
+            _ => return Err(PersistError::Code { line: proto.lineinfo.get(pc).cloned().unwrap_or(0) })
+        }
+        pc += 1;
+    }
+    Ok(())
+}
+
+unsafe fn message(thread: *mut ffi::lua_State) -> String {
+    let msg = ffi::lua_tolstring(thread, -1, ptr::null_mut());
+    match msg.is_null() {
+        true => "error object is not a string".to_string(),
+        false => CStr::from_ptr(msg).to_string_lossy().into_owned()
+    }
+}
+
+// raised as light userdata so they can't be confused with a script's errors
+static CALL: u8 = 0;
+static LIMIT: u8 = 0;
+
+unsafe extern "C" fn data_hook(state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+    let marker = match (*ar).event {
+        ffi::LUA_HOOKCOUNT => &LIMIT,
+        ffi::LUA_HOOKCALL => {
+            ffi::lua_getinfo(state, c_str!("S"), ar);
+            // the data chunk itself
+            if CStr::from_ptr((*ar).what).to_bytes() == b"main" {
+                return;
+            }
+            &CALL
+        }
+        _ => return
+    };
+
+    ffi::lua_pushlightuserdata(state, marker as *const u8 as *mut libc::c_void);
+    ffi::lua_error(state);
+}
+
+#[test]
+fn persist_source() {
+    let ctx = Context::new();
+
+    ctx.eval("return { 'a', 2, 0.1, name = 'rat', ['end'] = 1/0, nested = { ok = true, [false] = -0.5 } }").unwrap();
+    let src = to_lua_source(&ctx.pop::<Table>()).unwrap();
+
+    assert_eq!(src, "return {\n\
+                     \x20 \"a\",\n\
+                     \x20 2,\n\
+                     \x20 0.1,\n\
+                     \x20 [\"end\"] = 1/0,\n\
+                     \x20 name = \"rat\",\n\
+                     \x20 nested = {\n\
+                     \x20   ok = true,\n\
+                     \x20   [false] = -0.5,\n\
+                     \x20 },\n\
+                     }\n");
+
+    let loaded = load_data(&ctx, &src).unwrap();
+    match loaded {
+        LuaValue::Table(ref t) => assert_eq!(to_lua_source(t).unwrap(), src),
+        _ => panic!("expected a table")
+    }
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn persist_graph() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.eval("local a = { name = 'a' }\n\
+              local b = { name = 'b', a = a }\n\
+              a.b = b\n\
+              local root = { a, b, list = { a, a } }\n\
+              root.root = root\n\
+              return root").unwrap();
+    let src = to_lua_source(&ctx.pop::<Table>()).unwrap();
+
+    let loaded = load_data(&ctx, &src).unwrap();
+    ctx.set("t", loaded);
+    ctx.eval("return t[1].b == t[2] and t[2].a == t[1] and t.list[2] == t[1] and t.root == t and t[2].name == 'b'").unwrap();
+    assert!(ctx.pop::<bool>());
+
+    ctx.eval("return { list = { 1, { handler = print } } }").unwrap();
+    let err = to_lua_source(&ctx.pop::<Table>()).unwrap_err();
+    assert_eq!(err.to_string(), "cannot persist a function value at list[2].handler");
+}
+
+#[test]
+fn persist_load_rules() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    assert_eq!(load_data(&ctx, "return ('x'):rep(100)").unwrap_err(), PersistError::Call);
+    assert_eq!(load_data(&ctx, "return { ({}).x() }").unwrap_err(), PersistError::Call);
+    assert_eq!(load_data_with_limit(&ctx, "return { 1, 2, 3, 4, 5 }", 2).unwrap_err(), PersistError::InstructionLimit);
+    assert_eq!(load_data(&ctx, "return { {}, 1 } .. 'x'").unwrap_err().to_string(),
+               "data files can only build tables, line 1 runs other code");
+
+    // loops, concatenation, globals and closures could use unbounded time or
+    // memory, or reach outside the data
+    assert_eq!(load_data(&ctx, "local t = {}\nwhile true do end\nreturn t").unwrap_err(), PersistError::Code { line: 2 });
+    assert_eq!(load_data(&ctx, "local s = 'ab'\ns = s .. s\nreturn { s }").unwrap_err(), PersistError::Code { line: 2 });
+    assert_eq!(load_data(&ctx, "x = 2 return { x }").unwrap_err(), PersistError::Code { line: 1 });
+    assert_eq!(load_data(&ctx, "return { f = function() end }").unwrap_err(), PersistError::Code { line: 1 });
+    assert_eq!(ctx.get::<Option<i32>>("x"), None);
+
+    // constructors with more elements than fit a single SETLIST
+    let big = format!("return {{ {} }}", vec!["1"; 30000].join(", "));
+    match load_data(&ctx, &big) {
+        Ok(LuaValue::Table(ref t)) => assert_eq!(t.len(), 30000),
+        other => panic!("expected a table, got {:?}", other)
+    }
+
+    match load_data(&ctx, "return {") {
+        Err(PersistError::Syntax(..)) => {}
+        other => panic!("expected a syntax error, got {:?}", other)
+    }
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn persist_invalid_limit() {
+    let ctx = Context::new();
+
+    for &limit in &[0, -1] {
+        let err = load_data_with_limit(&ctx, "return {}", limit).unwrap_err();
+        assert_eq!(err, PersistError::InvalidLimit(limit));
+        assert_eq!(err.to_string(), format!("the instruction limit must be positive, got {}", limit));
+    }
+    assert_eq!(ctx.size(), 0);
+}
+
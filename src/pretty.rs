@@ -0,0 +1,255 @@
+use LuaValue;
+use Table;
+use ffi;
+
+use stack::Push;
+use stack::read::is_identifier;
+use value::format_number;
+
+use libc;
+
+use std::cmp::Ordering;
+
+const KEYWORDS: [&str; 21] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// Renders a value as Lua source, for logging or snapshot tests:
+//
+//     let s = Pretty::new().indent(4).max_depth(2).print(&value);
+//
+// Tables become constructors with the sequence first and the other keys
+// sorted. Two markers aren't valid Lua: a table inside itself prints as
+// `<cycle>`, and functions, userdata and threads print as `<function: 0x...>`.
+// Tables deeper than `max_depth` print as `{...}`.
+pub struct Pretty {
+    indent: usize,
+    max_depth: Option<usize>,
+}
+
+impl Default for Pretty {
+    fn default() -> Self {
+        Pretty::new()
+    }
+}
+
+impl Pretty {
+    pub fn new() -> Self {
+        Pretty { indent: 2, max_depth: None }
+    }
+
+    pub fn indent(mut self, spaces: usize) -> Self {
+        self.indent = spaces;
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn print(&self, value: &LuaValue) -> String {
+        let mut out = String::new();
+        self.value(&mut out, value, 0, &mut Vec::new());
+        out
+    }
+
+    fn value(&self, out: &mut String, value: &LuaValue, depth: usize, ancestors: &mut Vec<*const libc::c_void>) {
+        match value {
+            &LuaValue::Nil |
+            &LuaValue::None => out.push_str("nil"),
+            &LuaValue::Bool(b) => out.push_str(&b.to_string()),
+            &LuaValue::Number(n) => out.push_str(&number(n)),
+            LuaValue::String(s) => quote(out, s.as_bytes()),
+            LuaValue::Bytes(b) => quote(out, b),
+            LuaValue::Table(t) => self.table(out, t, depth, ancestors),
+            other => out.push_str(&format!("<{}>", other)),
+        }
+    }
+
+    fn table(&self, out: &mut String, table: &Table, depth: usize, ancestors: &mut Vec<*const libc::c_void>) {
+        let ptr = table_ptr(table);
+        if ancestors.contains(&ptr) {
+            out.push_str("<cycle>");
+            return;
+        }
+
+        let mut entries = table.entries();
+        if entries.is_empty() {
+            out.push_str("{}");
+            return;
+        }
+        if self.max_depth.map(|max| depth >= max).unwrap_or(false) {
+            out.push_str("{...}");
+            return;
+        }
+
+        // the sequence goes first, even before zero and negative keys
+        entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+        let start = entries.iter().position(|e| e.0 == LuaValue::Number(1.0)).unwrap_or(0);
+        let len = entries[start..].iter().enumerate()
+            .take_while(|&(i, e)| e.0 == LuaValue::Number(i as f64 + 1.0))
+            .count();
+        entries[..start + len].rotate_right(len);
+
+        ancestors.push(ptr);
+        out.push_str("{\n");
+
+        // keys 1, 2, 3... are left implicit
+        let mut next = 1.0;
+        for (k, v) in &entries {
+            pad(out, (depth + 1) * self.indent);
+
+            match k {
+                &LuaValue::Number(n) if n == next => next += 1.0,
+                LuaValue::String(s) if is_name(s) => {
+                    out.push_str(s);
+                    out.push_str(" = ");
+                }
+                _ => {
+                    out.push('[');
+                    self.value(out, k, depth + 1, ancestors);
+                    out.push_str("] = ");
+                }
+            }
+
+            self.value(out, v, depth + 1, ancestors);
+            out.push_str(",\n");
+        }
+
+        pad(out, depth * self.indent);
+        out.push('}');
+        ancestors.pop();
+    }
+}
+
+// `Pretty::new().print(value)`
+pub fn pretty(value: &LuaValue) -> String {
+    Pretty::new().print(value)
+}
+
+pub(crate) fn table_ptr(table: &Table) -> *const libc::c_void {
+    table.ptr.push(table.ctx);
+    let ptr = unsafe { ffi::lua_topointer(table.ctx.handle, -1) };
+    table.ctx.pop_discard(1);
+    ptr
+}
+
+fn pad(out: &mut String, n: usize) {
+    out.extend((0..n).map(|_| ' '));
+}
+
+pub(crate) fn is_name(s: &str) -> bool {
+    is_identifier(s) && !KEYWORDS.contains(&s)
+}
+
+// there are no literals for these
+fn number(n: f64) -> String {
+    match n {
+        n if n.is_nan() => "0/0".to_string(),
+        n if n.is_infinite() && n > 0.0 => "1/0".to_string(),
+        n if n.is_infinite() => "-1/0".to_string(),
+        n => format_number(n)
+    }
+}
+
+pub(crate) fn quote(out: &mut String, bytes: &[u8]) {
+    out.push('"');
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                // always three digits, so a digit after it can't be read as part of it
+                c if c < ' ' || c == '\x7f' => out.push_str(&format!("\\{:03}", c as u32)),
+                c => out.push(c)
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\{:03}", b));
+        }
+    }
+    out.push('"');
+}
+
+// numbers, then strings, then everything else
+pub(crate) fn compare_keys(a: &LuaValue, b: &LuaValue) -> Ordering {
+    fn rank(v: &LuaValue) -> u8 {
+        match v {
+            &LuaValue::Number(..) => 0,
+            &LuaValue::String(..) |
+            &LuaValue::Bytes(..) => 1,
+            &LuaValue::Bool(..) => 2,
+            _ => 3
+        }
+    }
+
+    match (a, b) {
+        (&LuaValue::Number(x), &LuaValue::Number(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        (&LuaValue::Bool(x), &LuaValue::Bool(y)) => x.cmp(&y),
+        _ => match (a.as_bytes(), b.as_bytes()) {
+            (Some(x), Some(y)) => x.cmp(y),
+            _ => rank(a).cmp(&rank(b)).then_with(|| a.to_string().cmp(&b.to_string()))
+        }
+    }
+}
+
+#[test]
+fn pretty_tables() {
+    use Context;
+
+    let ctx = Context::new();
+
+    ctx.eval("return { 'a', 'b', [4] = 'd', z = 1, name = 'rat', ['end'] = true, ['two words'] = 2.5,\n\
+              [-1] = 0/0, [false] = 1/0, nested = { 1, { x = -1/0 } }, empty = {}, text = 'q\"\\\\\\n\\0001\\255' }").unwrap();
+    let value = ctx.pop::<LuaValue>();
+
+    let expected = "{\n\
+                    \x20 \"a\",\n\
+                    \x20 \"b\",\n\
+                    \x20 [-1] = 0/0,\n\
+                    \x20 [4] = \"d\",\n\
+                    \x20 empty = {},\n\
+                    \x20 [\"end\"] = true,\n\
+                    \x20 name = \"rat\",\n\
+                    \x20 nested = {\n\
+                    \x20   1,\n\
+                    \x20   {\n\
+                    \x20     x = -1/0,\n\
+                    \x20   },\n\
+                    \x20 },\n\
+                    \x20 text = \"q\\\"\\\\\\n\\0001\\255\",\n\
+                    \x20 [\"two words\"] = 2.5,\n\
+                    \x20 z = 1,\n\
+                    \x20 [false] = 1/0,\n\
+                    }";
+    assert_eq!(pretty(&value), expected);
+
+    // the output reads back as the same table
+    ctx.eval(&format!("return {}", expected)).unwrap();
+    assert_eq!(pretty(&ctx.pop::<LuaValue>()), expected);
+
+    assert_eq!(Pretty::new().indent(0).max_depth(1).print(&value).lines().nth(8), Some("nested = {...},"));
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn pretty_cycles() {
+    use Context;
+
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.eval("local t = { f = print } t.self = t return t").unwrap();
+    let printed = pretty(&ctx.pop::<LuaValue>());
+    let mut lines = printed.lines();
+
+    assert_eq!(lines.next(), Some("{"));
+    assert!(lines.next().unwrap().starts_with("  f = <function: 0x"));
+    assert_eq!(lines.next(), Some("  self = <cycle>,"));
+    assert_eq!(lines.next(), Some("}"));
+}
@@ -0,0 +1,314 @@
+use Context;
+use DebugInfo;
+use HookEvent;
+use HookMask;
+use ffi;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfilerMode {
+    // times every call and return
+    Instrument,
+    // walks the stack every `n` VM instructions, `n` must be positive
+    Sample(i32),
+}
+
+// `inclusive` and `exclusive` are in nanoseconds when instrumenting and in
+// samples when sampling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+struct Frame {
+    name: String,
+    // the number of active Lua frames while it runs
+    depth: i32,
+    start: Instant,
+    children: Duration,
+}
+
+#[derive(Default)]
+struct ProfileData {
+    stack: Vec<Frame>,
+    functions: HashMap<String, FunctionStats>,
+    folded: HashMap<String, u64>,
+}
+
+impl ProfileData {
+    fn stats(&mut self, name: &str) -> &mut FunctionStats {
+        self.functions.entry(name.to_string()).or_insert_with(|| FunctionStats {
+            name: name.to_string(),
+            calls: 0,
+            inclusive: 0,
+            exclusive: 0,
+        })
+    }
+
+    fn enter(&mut self, name: String, depth: i32) {
+        self.unwind(depth - 1);
+        self.stats(&name).calls += 1;
+        self.stack.push(Frame { name, depth, start: Instant::now(), children: Duration::new(0, 0) });
+    }
+
+    fn leave(&mut self, depth: i32) {
+        self.unwind(depth);
+        if self.stack.last().map(|f| f.depth) == Some(depth) {
+            self.pop();
+        }
+    }
+
+    // An error unwinding into `pcall` runs no return hooks, and a tail call
+    // replaces its caller, so frames deeper than the real stack are gone.
+    fn unwind(&mut self, depth: i32) {
+        while self.stack.last().map(|f| f.depth > depth).unwrap_or(false) {
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        let frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return
+        };
+
+        let inclusive = frame.start.elapsed();
+        let exclusive = inclusive - frame.children.min(inclusive);
+
+        let path = self.path(Some(&frame.name));
+        *self.folded.entry(path).or_insert(0) += exclusive.as_micros() as u64;
+
+        // recursive calls would otherwise count their time more than once
+        if !self.stack.iter().any(|f| f.name == frame.name) {
+            self.stats(&frame.name).inclusive += inclusive.as_nanos() as u64;
+        }
+        self.stats(&frame.name).exclusive += exclusive.as_nanos() as u64;
+
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children += inclusive;
+        }
+    }
+
+    fn sample(&mut self, stack: Vec<String>) {
+        if stack.is_empty() {
+            return;
+        }
+
+        let mut seen: Vec<&String> = Vec::new();
+        for name in &stack {
+            if !seen.contains(&name) {
+                seen.push(name);
+            }
+        }
+        for name in seen {
+            self.stats(name).inclusive += 1;
+        }
+        self.stats(stack.last().unwrap()).exclusive += 1;
+
+        *self.folded.entry(stack.join(";")).or_insert(0) += 1;
+    }
+
+    fn path(&self, leaf: Option<&String>) -> String {
+        let names: Vec<&str> = self.stack.iter()
+            .map(|f| &f.name[..])
+            .chain(leaf.map(|l| &l[..]))
+            .collect();
+        names.join(";")
+    }
+}
+
+// the number of active frames, level 0 being the function the hook is for
+fn depth(ctx: &Context) -> i32 {
+    unsafe {
+        let mut ar: ffi::lua_Debug = mem::zeroed();
+        let mut level = 0;
+        while ffi::lua_getstack(ctx.handle, level, &mut ar) != 0 {
+            level += 1;
+        }
+        level
+    }
+}
+
+fn frame_name(info: &DebugInfo) -> String {
+    let name = match (&info.what[..], &info.name) {
+        ("main", _) => "main chunk",
+        (_, Some(name)) => &name[..],
+        (_, &None) => "?",
+    };
+
+    // `;` separates frames in folded stacks
+    format!("{}:{} {}", info.short_src, info.linedefined, name).replace(';', ",")
+}
+
+// Collects per-function timings through the debug hook. Only one hook can be
+// installed per state, so a running profiler replaces any other hook.
+pub struct Profiler {
+    mode: ProfilerMode,
+    data: Rc<RefCell<ProfileData>>,
+}
+
+impl Profiler {
+    pub fn new(mode: ProfilerMode) -> Self {
+        Profiler {
+            mode,
+            data: Rc::new(RefCell::new(ProfileData::default())),
+        }
+    }
+
+    pub fn start(&self, ctx: &Context) {
+        let data = self.data.clone();
+
+        match self.mode {
+            ProfilerMode::Instrument => {
+                ctx.set_hook(HookMask::CALL | HookMask::RETURN, 0, move |ctx, event| {
+                    let depth = depth(ctx);
+                    match event.kind() {
+                        HookEvent::Call => data.borrow_mut().enter(frame_name(&event.info()), depth),
+                        HookEvent::Return => data.borrow_mut().leave(depth),
+                        // the tail calls were already replaced on entry
+                        HookEvent::TailReturn => data.borrow_mut().unwind(depth),
+                        _ => {}
+                    }
+                });
+            }
+            ProfilerMode::Sample(count) => {
+                ctx.set_hook(HookMask::COUNT, count, move |ctx, _| {
+                    let mut stack = Vec::new();
+                    let mut level = 0;
+                    while let Some(info) = ctx.stack_info(level) {
+                        stack.push(frame_name(&info));
+                        level += 1;
+                    }
+                    stack.reverse();
+
+                    data.borrow_mut().sample(stack);
+                });
+            }
+        }
+    }
+
+    pub fn stop(&self, ctx: &Context) {
+        ctx.remove_hook();
+
+        self.data.borrow_mut().unwind(0);
+    }
+
+    pub fn reset(&self) {
+        *self.data.borrow_mut() = ProfileData::default();
+    }
+
+    // sorted by exclusive cost, most expensive first
+    pub fn functions(&self) -> Vec<FunctionStats> {
+        let mut functions: Vec<FunctionStats> = self.data.borrow().functions.values().cloned().collect();
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then_with(|| a.name.cmp(&b.name)));
+        functions
+    }
+
+    // one `frame;frame;frame weight` line per stack, as consumed by
+    // flamegraph.pl and inferno; weights are microseconds or samples
+    pub fn folded(&self) -> String {
+        let data = self.data.borrow();
+        let mut stacks: Vec<(&String, &u64)> = data.folded.iter().collect();
+        stacks.sort();
+
+        let mut out = String::new();
+        for (stack, weight) in stacks {
+            writeln!(out, "{} {}", stack, weight).unwrap();
+        }
+        out
+    }
+
+    pub fn report(&self) -> String {
+        let unit = match self.mode {
+            ProfilerMode::Instrument => "us",
+            ProfilerMode::Sample(..) => "samples",
+        };
+        let scale = match self.mode {
+            ProfilerMode::Instrument => 1000,
+            ProfilerMode::Sample(..) => 1,
+        };
+
+        let mut out = String::new();
+        writeln!(out, "{:>12} {:>12} {:>8}  function ({})", "exclusive", "inclusive", "calls", unit).unwrap();
+        for f in self.functions() {
+            writeln!(out, "{:>12} {:>12} {:>8}  {}", f.exclusive / scale, f.inclusive / scale, f.calls, f.name).unwrap();
+        }
+        out
+    }
+}
+
+#[test]
+fn instrument() {
+    let ctx = Context::new();
+    let profiler = Profiler::new(ProfilerMode::Instrument);
+
+    profiler.start(&ctx);
+    ctx.eval("local function foo() local x = 0 for i = 1, 100 do x = x + i end return x end\n\
+              local function bar() foo() foo() foo() end\n\
+              bar()").unwrap();
+    profiler.stop(&ctx);
+
+    let functions = profiler.functions();
+    let foo = functions.iter().find(|f| f.name.ends_with(":1 foo")).unwrap();
+    let bar = functions.iter().find(|f| f.name.ends_with(":2 bar")).unwrap();
+
+    assert_eq!(foo.calls, 3);
+    assert_eq!(bar.calls, 1);
+    assert!(bar.inclusive >= foo.inclusive);
+
+    let folded = profiler.folded();
+    assert!(folded.lines().any(|l| l.contains("main chunk;") && l.contains(":2 bar;") && l.contains(":1 foo ")));
+    assert!(profiler.report().lines().count() > 3);
+}
+
+#[test]
+fn instrument_errors() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+    let profiler = Profiler::new(ProfilerMode::Instrument);
+
+    // no return hooks run for `fail` and `outer` when `error` unwinds them;
+    // called through `pcall`, `outer` has no name
+    profiler.start(&ctx);
+    ctx.eval("local function fail() error('x') end\n\
+              local function outer() fail() end\n\
+              local function run() for i = 1, 3 do pcall(outer) end end\n\
+              run()").unwrap();
+    profiler.stop(&ctx);
+
+    let functions = profiler.functions();
+    let calls = |suffix: &str| functions.iter().find(|f| f.name.ends_with(suffix)).unwrap().calls;
+    assert_eq!(calls(":2 ?"), 3);
+    assert_eq!(calls(":1 fail"), 3);
+    assert_eq!(calls(":3 run"), 1);
+
+    // later calls aren't nested under the unwound frames
+    let folded = profiler.folded();
+    assert!(folded.lines().any(|l| l.contains(":3 run;") && l.contains("pcall;") && l.contains(":2 ? ")));
+    assert!(folded.lines().all(|l| l.matches(":2 ?").count() <= 1), "{}", folded);
+}
+
+#[test]
+fn sample() {
+    let ctx = Context::new();
+    let profiler = Profiler::new(ProfilerMode::Sample(100));
+
+    profiler.start(&ctx);
+    ctx.eval("local function spin() local x = 0 for i = 1, 100000 do x = x + i end return x end\n\
+              spin()").unwrap();
+    profiler.stop(&ctx);
+
+    let functions = profiler.functions();
+    assert!(functions[0].name.ends_with(":1 spin"));
+    assert!(functions[0].exclusive > 100);
+    assert!(profiler.folded().lines().all(|l| l.starts_with("[string")));
+}
@@ -0,0 +1,227 @@
+use Context;
+use ffi;
+
+use libc;
+
+use std::slice;
+
+// The function prototype tree of a Lua closure, recovered by parsing the
+// output of `lua_dump` since the API has no other way to reach nested
+// prototypes or their line info.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prototype {
+    pub source: Option<String>,
+    pub linedefined: i32,
+    pub lastlinedefined: i32,
+    pub nups: u8,
+    pub numparams: u8,
+    pub is_vararg: bool,
+    pub code: Vec<u32>,
+    // the source line of every instruction
+    pub lineinfo: Vec<i32>,
+    pub protos: Vec<Prototype>,
+}
+
+impl Prototype {
+    // dumps the Lua function at `idx`, returns `None` for C functions
+    pub fn dump(ctx: &Context, idx: i32) -> Option<Prototype> {
+        let mut buf: Vec<u8> = Vec::new();
+
+        unsafe {
+            if ffi::lua_type(ctx.handle, idx) != ffi::LUA_TFUNCTION || ffi::lua_iscfunction(ctx.handle, idx) != 0 {
+                return None;
+            }
+
+            ffi::lua_pushvalue(ctx.handle, idx);
+            let ret = ffi::lua_dump(ctx.handle, writer, &mut buf as *mut Vec<u8> as *mut libc::c_void);
+            ctx.pop_discard(1);
+
+            if ret != 0 {
+                return None;
+            }
+        }
+
+        Prototype::parse(&buf)
+    }
+
+    pub fn parse(chunk: &[u8]) -> Option<Prototype> {
+        let mut r = Reader { buf: chunk, int: 0, size_t: 0, number: 0, instruction: 0 };
+
+        if r.bytes(4)? != b"\x1bLua" || r.byte()? != 0x51 || r.byte()? != 0 {
+            return None;
+        }
+        // only chunks dumped by this process are read, so they are native endian
+        r.byte()?;
+        r.int = r.byte()? as usize;
+        r.size_t = r.byte()? as usize;
+        r.instruction = r.byte()? as usize;
+        r.number = r.byte()? as usize;
+        r.byte()?;
+
+        r.function(None)
+    }
+
+    // The set of lines holding code in this function and all nested
+    // functions. The `return` the compiler appends to every function is left
+    // out, it would mark the `end` line even when it can't be reached.
+    pub fn active_lines(&self) -> Vec<i32> {
+        let explicit = self.lineinfo.len().saturating_sub(1);
+        let mut lines = self.lineinfo[..explicit].to_vec();
+        for proto in &self.protos {
+            lines.extend(proto.active_lines());
+        }
+
+        lines.sort();
+        lines.dedup();
+        lines
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    int: usize,
+    size_t: usize,
+    number: usize,
+    instruction: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn uint(&mut self, size: usize) -> Option<u64> {
+        let bytes = self.bytes(size)?;
+        let mut raw = [0u8; 8];
+        match cfg!(target_endian = "little") {
+            true => raw[..size].copy_from_slice(bytes),
+            false => raw[8 - size..].copy_from_slice(bytes),
+        }
+
+        Some(match cfg!(target_endian = "little") {
+            true => u64::from_le_bytes(raw),
+            false => u64::from_be_bytes(raw),
+        })
+    }
+
+    fn int(&mut self) -> Option<i32> {
+        let size = self.int;
+        self.uint(size).map(|v| v as i32)
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        let n = self.int()?;
+        match n >= 0 {
+            true => Some(n as usize),
+            false => None
+        }
+    }
+
+    fn string(&mut self) -> Option<Option<String>> {
+        let size = self.size_t;
+        let len = self.uint(size)? as usize;
+
+        match len {
+            0 => Some(None),
+            // the stored length includes the trailing '\0'
+            _ => self.bytes(len).map(|s| Some(String::from_utf8_lossy(&s[..len - 1]).into_owned()))
+        }
+    }
+
+    fn function(&mut self, parent: Option<&String>) -> Option<Prototype> {
+        let source = self.string()?.or_else(|| parent.cloned());
+        let linedefined = self.int()?;
+        let lastlinedefined = self.int()?;
+        let nups = self.byte()?;
+        let numparams = self.byte()?;
+        let is_vararg = self.byte()? != 0;
+        self.byte()?;
+
+        let mut code = Vec::new();
+        for _ in 0..self.count()? {
+            let size = self.instruction;
+            code.push(self.uint(size)? as u32);
+        }
+
+        for _ in 0..self.count()? {
+            match self.byte()? as i32 {
+                ffi::LUA_TNIL => {}
+                ffi::LUA_TBOOLEAN => { self.byte()?; }
+                ffi::LUA_TNUMBER => { let n = self.number; self.bytes(n)?; }
+                ffi::LUA_TSTRING => { self.string()?; }
+                _ => return None
+            }
+        }
+
+        let mut protos = Vec::new();
+        for _ in 0..self.count()? {
+            protos.push(self.function(source.as_ref())?);
+        }
+
+        let mut lineinfo = Vec::new();
+        for _ in 0..self.count()? {
+            lineinfo.push(self.int()?);
+        }
+
+        for _ in 0..self.count()? {
+            self.string()?;
+            self.int()?;
+            self.int()?;
+        }
+        for _ in 0..self.count()? {
+            self.string()?;
+        }
+
+        Some(Prototype {
+            source,
+            linedefined,
+            lastlinedefined,
+            nups,
+            numparams,
+            is_vararg,
+            code,
+            lineinfo,
+            protos,
+        })
+    }
+}
+
+unsafe extern "C" fn writer(_: *mut ffi::lua_State, p: *const libc::c_void, sz: libc::size_t, ud: *mut libc::c_void) -> libc::c_int {
+    let buf: &mut Vec<u8> = &mut *(ud as *mut std::vec::Vec<u8>);
+    buf.extend_from_slice(slice::from_raw_parts(p as *const u8, sz));
+    0
+}
+
+#[test]
+fn dump_function() {
+    let ctx = Context::new();
+
+    ctx.eval("return function(a, b, ...)\n\
+              local function inner(c)\n\
+              return c\n\
+              end\n\
+              return inner(a)\n\
+              end").unwrap();
+
+    let proto = Prototype::dump(&ctx, -1).unwrap();
+    ctx.pop_discard(1);
+
+    assert_eq!(proto.linedefined, 1);
+    assert_eq!(proto.lastlinedefined, 6);
+    assert_eq!(proto.numparams, 2);
+    assert!(proto.is_vararg);
+    assert_eq!(proto.protos.len(), 1);
+    assert_eq!(proto.protos[0].numparams, 1);
+    assert_eq!(proto.protos[0].source, proto.source);
+    assert_eq!(proto.active_lines(), vec![3, 4, 5]);
+}
@@ -0,0 +1,838 @@
+use Context;
+use LuaValue;
+use Table;
+use ffi;
+
+use collections::invalid_key;
+use stack::read::{field_segment, value_segment};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use std::error::Error;
+use std::fmt;
+use std::vec;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerdeError {
+    // where in the table the error happened, e.g. `enemies[3].stats.hp`
+    pub path: String,
+    pub message: String,
+}
+
+impl SerdeError {
+    fn new<T: fmt::Display>(msg: T) -> Self {
+        SerdeError { path: String::new(), message: msg.to_string() }
+    }
+
+    fn expected(expected: &str, found: &LuaValue) -> Self {
+        SerdeError::new(format!("expected {}, got {}", expected, type_name(found)))
+    }
+
+    fn at(mut self, segment: &str) -> Self {
+        self.path.insert_str(0, segment);
+        self
+    }
+}
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.path.trim_start_matches('.'), self.message)
+        }
+    }
+}
+
+impl Error for SerdeError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::new(msg)
+    }
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::new(msg)
+    }
+}
+
+fn type_name(value: &LuaValue) -> &'static str {
+    match value {
+        &LuaValue::None => "nil",
+        other => other.type_name()
+    }
+}
+
+// Converts any `Serialize` value into a Lua value. Structs and maps become
+// tables, sequences become array tables, enums are externally tagged
+// (`"Unit"` or `{ Variant = value }`) and `None`/unit become nil. A nil can't
+// be a sequence element, so `None` inside a `Vec` or tuple is an error.
+pub fn to_lua<'a, T>(ctx: &'a Context, value: &T) -> Result<LuaValue<'a>, SerdeError>
+    where T: Serialize + ?Sized
+{
+    let top = ctx.size();
+
+    match value.serialize(Serializer { ctx }) {
+        Ok(()) => Ok(ctx.pop::<LuaValue>()),
+        Err(e) => {
+            unsafe {
+                ffi::lua_settop(ctx.handle, top);
+            }
+            Err(e)
+        }
+    }
+}
+
+pub fn from_lua<'a, T>(value: LuaValue<'a>) -> Result<T, SerdeError>
+    where T: DeserializeOwned
+{
+    T::deserialize(Deserializer { value })
+}
+
+// pushes the serialized value onto the stack
+struct Serializer<'a> {
+    ctx: &'a Context,
+}
+
+impl<'a> Serializer<'a> {
+    fn push_table(&self, narr: usize, nrec: usize) {
+        unsafe {
+            ffi::lua_createtable(self.ctx.handle, narr as i32, nrec as i32);
+        }
+    }
+
+    // starts `{ variant = ... }`, leaving the outer table and the key pushed
+    fn push_variant(&self, variant: &str) {
+        self.push_table(0, 1);
+        self.ctx.push(variant);
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    type SerializeSeq = SerializeTable<'a>;
+    type SerializeTuple = SerializeTable<'a>;
+    type SerializeTupleStruct = SerializeTable<'a>;
+    type SerializeTupleVariant = SerializeTable<'a>;
+    type SerializeMap = SerializeTable<'a>;
+    type SerializeStruct = SerializeTable<'a>;
+    type SerializeStructVariant = SerializeTable<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerdeError> {
+        self.ctx.push(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerdeError> {
+        self.ctx.push(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerdeError> {
+        self.serialize_str(&v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerdeError> {
+        self.ctx.push(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerdeError> {
+        unsafe {
+            ffi::lua_pushlstring(self.ctx.handle, v.as_ptr() as _, v.len() as _);
+        }
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), SerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerdeError> {
+        unsafe {
+            ffi::lua_pushnil(self.ctx.handle);
+        }
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), SerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<(), SerdeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, variant: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.push_variant(variant);
+        value.serialize(Serializer { ctx: self.ctx })?;
+        unsafe {
+            ffi::lua_rawset(self.ctx.handle, -3);
+        }
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeTable<'a>, SerdeError> {
+        self.push_table(len.unwrap_or(0), 0);
+        Ok(SerializeTable { ctx: self.ctx, len: 0, variant: false })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeTable<'a>, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<SerializeTable<'a>, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, len: usize) -> Result<SerializeTable<'a>, SerdeError> {
+        self.push_variant(variant);
+        self.push_table(len, 0);
+        Ok(SerializeTable { ctx: self.ctx, len: 0, variant: true })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeTable<'a>, SerdeError> {
+        self.push_table(0, len.unwrap_or(0));
+        Ok(SerializeTable { ctx: self.ctx, len: 0, variant: false })
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<SerializeTable<'a>, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, len: usize) -> Result<SerializeTable<'a>, SerdeError> {
+        self.push_variant(variant);
+        self.push_table(0, len);
+        Ok(SerializeTable { ctx: self.ctx, len: 0, variant: true })
+    }
+}
+
+// fills the table on top of the stack; `variant` means it's the payload of
+// a `{ variant = ... }` table sitting below it
+struct SerializeTable<'a> {
+    ctx: &'a Context,
+    len: i32,
+    variant: bool,
+}
+
+impl<'a> SerializeTable<'a> {
+    // a nil element would end the sequence early, so `None` and `()` are
+    // rejected instead of leaving a hole
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.len += 1;
+        value.serialize(Serializer { ctx: self.ctx }).map_err(|e| e.at(&format!("[{}]", self.len)))?;
+        unsafe {
+            if ffi::lua_isnil(self.ctx.handle, -1) {
+                return Err(SerdeError::new("nil can't be stored in a sequence").at(&format!("[{}]", self.len)));
+            }
+            ffi::lua_rawseti(self.ctx.handle, -2, self.len);
+        }
+        Ok(())
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), SerdeError> {
+        self.ctx.push(key);
+        value.serialize(Serializer { ctx: self.ctx })?;
+        unsafe {
+            ffi::lua_rawset(self.ctx.handle, -3);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), SerdeError> {
+        if self.variant {
+            unsafe {
+                ffi::lua_rawset(self.ctx.handle, -3);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for SerializeTable<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for SerializeTable<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for SerializeTable<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for SerializeTable<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for SerializeTable<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        key.serialize(Serializer { ctx: self.ctx })?;
+
+        if let Some(key) = invalid_key(self.ctx, -1) {
+            return Err(SerdeError::new(format!("table index is {}", key)));
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(Serializer { ctx: self.ctx })?;
+        unsafe {
+            ffi::lua_rawset(self.ctx.handle, -3);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for SerializeTable<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for SerializeTable<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        self.finish()
+    }
+}
+
+struct Deserializer<'a> {
+    value: LuaValue<'a>,
+}
+
+impl<'a> Deserializer<'a> {
+    fn integer(&self) -> Result<f64, SerdeError> {
+        match self.value {
+            LuaValue::Number(n) if n.fract() == 0.0 => Ok(n),
+            LuaValue::Number(..) => Err(SerdeError::new("expected integer, got number")),
+            ref other => Err(SerdeError::expected("number", other))
+        }
+    }
+
+    // a table whose keys are exactly `1..n`
+    fn is_sequence(table: &Table) -> bool {
+        let len = table.len();
+        len > 0 && table.pairs::<LuaValue, LuaValue>().count() == len
+    }
+}
+
+// `$ty` is the requested type and `$wide` the type its visitor takes; numbers
+// outside the range of `$ty` are rejected instead of wrapping
+macro_rules! deserialize_integer {
+    ($method:ident, $visit:ident, $wide:ident, $ty:ident) => (
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+            let n = self.integer()?;
+            match n >= $ty::MIN as f64 && n < $ty::MAX as f64 + 1.0 {
+                true => visitor.$visit(n as $ty as $wide),
+                false => {
+                    let unexpected = match n < 0.0 {
+                        true => de::Unexpected::Signed(n as i64),
+                        false => de::Unexpected::Unsigned(n as u64)
+                    };
+                    Err(de::Error::invalid_value(unexpected, &stringify!($ty)))
+                }
+            }
+        }
+    )
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::Nil |
+            LuaValue::None => visitor.visit_unit(),
+            LuaValue::Bool(b) => visitor.visit_bool(b),
+            LuaValue::Number(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => visitor.visit_i64(n as i64),
+            LuaValue::Number(n) => visitor.visit_f64(n),
+            LuaValue::String(s) => visitor.visit_string(s),
+            LuaValue::Bytes(b) => visitor.visit_byte_buf(b),
+            LuaValue::Table(ref t) if Deserializer::is_sequence(t) => self.deserialize_seq(visitor),
+            LuaValue::Table(..) => self.deserialize_map(visitor),
+            ref other => Err(SerdeError::new(format!("can't deserialize a {}", other.type_name()))),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::Bool(b) => visitor.visit_bool(b),
+            ref other => Err(SerdeError::expected("boolean", other))
+        }
+    }
+
+    deserialize_integer!(deserialize_i8, visit_i64, i64, i8);
+    deserialize_integer!(deserialize_i16, visit_i64, i64, i16);
+    deserialize_integer!(deserialize_i32, visit_i64, i64, i32);
+    deserialize_integer!(deserialize_i64, visit_i64, i64, i64);
+    deserialize_integer!(deserialize_u8, visit_u64, u64, u8);
+    deserialize_integer!(deserialize_u16, visit_u64, u64, u16);
+    deserialize_integer!(deserialize_u32, visit_u64, u64, u32);
+    deserialize_integer!(deserialize_u64, visit_u64, u64, u64);
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::Number(n) => visitor.visit_f64(n),
+            ref other => Err(SerdeError::expected("number", other))
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::String(s) => visitor.visit_string(s),
+            ref other => Err(SerdeError::expected("string", other))
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::String(s) => visitor.visit_byte_buf(s.into_bytes()),
+            LuaValue::Bytes(b) => visitor.visit_byte_buf(b),
+            ref other => Err(SerdeError::expected("string", other))
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::Nil |
+            LuaValue::None => visitor.visit_none(),
+            _ => visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::Nil |
+            LuaValue::None => visitor.visit_unit(),
+            ref other => Err(SerdeError::expected("nil", other))
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::Table(table) => {
+                let len = table.len();
+                visitor.visit_seq(SeqAccess { table, idx: 0, len })
+            }
+            ref other => Err(SerdeError::expected("table", other))
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _: &'static str, _: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::Table(table) => {
+                let pairs = table.entries();
+                visitor.visit_map(MapAccess { pairs: pairs.into_iter(), value: None })
+            }
+            ref other => Err(SerdeError::expected("table", other))
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        match self.value {
+            LuaValue::String(s) => visitor.visit_enum(EnumAccess { variant: s, value: None }),
+            LuaValue::Table(table) => {
+                let mut pairs = table.entries();
+                match (pairs.pop(), pairs.is_empty()) {
+                    (Some((LuaValue::String(s), value)), true) => {
+                        visitor.visit_enum(EnumAccess { variant: s, value: Some(value) })
+                    }
+                    _ => Err(SerdeError::new("expected a table with a single variant key"))
+                }
+            }
+            ref other => Err(SerdeError::expected("string or table", other))
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqAccess<'a> {
+    table: Table<'a>,
+    idx: usize,
+    len: usize,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for SeqAccess<'a> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerdeError> {
+        if self.idx == self.len {
+            return Ok(None);
+        }
+
+        self.idx += 1;
+        let value = self.table.raw_get::<LuaValue, _>(self.idx);
+        seed.deserialize(Deserializer { value })
+            .map(Some)
+            .map_err(|e| e.at(&format!("[{}]", self.idx)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.idx)
+    }
+}
+
+struct MapAccess<'a> {
+    pairs: vec::IntoIter<(LuaValue<'a>, LuaValue<'a>)>,
+    // the pending value and the path segment of its key
+    value: Option<(LuaValue<'a>, String)>,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for MapAccess<'a> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerdeError> {
+        match self.pairs.next() {
+            Some((key, value)) => {
+                let segment = value_segment(&key);
+                let key = seed.deserialize(Deserializer { value: key }).map_err(|e| e.at(&segment))?;
+                self.value = Some((value, segment));
+                Ok(Some(key))
+            }
+            None => Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerdeError> {
+        let (value, segment) = self.value.take().expect("next_value_seed called before next_key_seed");
+        seed.deserialize(Deserializer { value }).map_err(|e| e.at(&segment))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.pairs.len())
+    }
+}
+
+struct EnumAccess<'a> {
+    variant: String,
+    value: Option<LuaValue<'a>>,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = SerdeError;
+    type Variant = VariantAccess<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantAccess<'a>), SerdeError> {
+        let segment = field_segment(&self.variant);
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, VariantAccess { value: self.value, segment }))
+    }
+}
+
+struct VariantAccess<'a> {
+    value: Option<LuaValue<'a>>,
+    segment: String,
+}
+
+impl<'a> VariantAccess<'a> {
+    fn payload(self) -> Result<(Deserializer<'a>, String), SerdeError> {
+        match self.value {
+            Some(value) => Ok((Deserializer { value }, self.segment)),
+            None => Err(SerdeError::new("expected a table with a single variant key, got string"))
+        }
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for VariantAccess<'a> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        match self.value {
+            None |
+            Some(LuaValue::Nil) => Ok(()),
+            Some(ref other) => Err(SerdeError::expected("nil", other).at(&self.segment))
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+        let (de, segment) = self.payload()?;
+        seed.deserialize(de).map_err(|e| e.at(&segment))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        let (de, segment) = self.payload()?;
+        de::Deserializer::deserialize_seq(de, visitor).map_err(|e| e.at(&segment))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        let (de, segment) = self.payload()?;
+        de::Deserializer::deserialize_map(de, visitor).map_err(|e| e.at(&segment))
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Stats {
+    hp: i32,
+    speed: f64,
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Kind {
+    Melee,
+    Ranged(f64),
+    Caster { school: String, mana: u32 },
+    Pair(i32, i32),
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Enemy {
+    name: String,
+    stats: Stats,
+    kind: Kind,
+    loot: Option<Vec<String>>,
+}
+
+#[test]
+fn round_trip() {
+    use stack::Push;
+
+    let ctx = Context::new();
+    unsafe {
+        ffi::luaL_openlibs(ctx.handle);
+    }
+
+    let enemies = vec![
+        Enemy { name: "rat".into(), stats: Stats { hp: 3, speed: 1.5 }, kind: Kind::Melee, loot: None },
+        Enemy { name: "archer".into(), stats: Stats { hp: 10, speed: 1.0 }, kind: Kind::Ranged(12.5), loot: Some(vec!["bow".into()]) },
+        Enemy { name: "mage".into(), stats: Stats { hp: 8, speed: 0.5 }, kind: Kind::Caster { school: "fire".into(), mana: 40 }, loot: None },
+        Enemy { name: "twins".into(), stats: Stats { hp: 2, speed: 2.0 }, kind: Kind::Pair(1, 2), loot: Some(vec![]) },
+    ];
+
+    let value = to_lua(&ctx, &enemies).unwrap();
+    assert_eq!(ctx.size(), 0);
+
+    match value {
+        LuaValue::Table(ref t) => unsafe {
+            t.ptr.push(&ctx);
+            ffi::lua_setfield(ctx.handle, ffi::LUA_GLOBALSINDEX, c_str!("enemies"));
+        },
+        _ => panic!("expected a table")
+    }
+    ctx.eval("assert(enemies[2].kind.Ranged == 12.5)\n\
+              assert(enemies[3].kind.Caster.school == 'fire')\n\
+              assert(enemies[1].kind == 'Melee')\n\
+              assert(enemies[1].loot == nil)").unwrap();
+
+    assert_eq!(from_lua::<Vec<Enemy>>(value).unwrap(), enemies);
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn error_path() {
+    let ctx = Context::new();
+
+    ctx.eval("return { config = { enemies = {\n\
+              { name = 'a', stats = { hp = 1, speed = 1 }, kind = 'Melee' },\n\
+              { name = 'b', stats = { hp = 1, speed = 1 }, kind = 'Melee' },\n\
+              { name = 'c', stats = { hp = 'lots', speed = 1 }, kind = 'Melee' },\n\
+              } } }").unwrap();
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        #[allow(dead_code)]
+        enemies: Vec<Enemy>,
+    }
+
+    let err = from_lua::<::std::collections::HashMap<String, Config>>(ctx.pop::<LuaValue>()).unwrap_err();
+    assert_eq!(err.to_string(), "config.enemies[3].stats.hp: expected number, got string");
+
+    ctx.eval("return { kind = { Ranged = 'far' } }").unwrap();
+    #[derive(Debug, Deserialize)]
+    struct Holder {
+        #[allow(dead_code)]
+        kind: Kind,
+    }
+    let err = from_lua::<Holder>(ctx.pop::<LuaValue>()).unwrap_err();
+    assert_eq!(err.to_string(), "kind.Ranged: expected number, got string");
+
+    ctx.eval("return { ['max-hp'] = 300 }").unwrap();
+    let value = ctx.pop::<LuaValue>();
+    let err = from_lua::<::std::collections::BTreeMap<String, u8>>(value).unwrap_err();
+    assert_eq!(err.to_string(), "[\"max-hp\"]: invalid value: integer `300`, expected u8");
+
+    ctx.eval("return { level = -1 }").unwrap();
+    let err = from_lua::<::std::collections::BTreeMap<String, u32>>(ctx.pop::<LuaValue>()).unwrap_err();
+    assert_eq!(err.to_string(), "level: invalid value: integer `-1`, expected u32");
+
+    ctx.eval("return { 4294967296 }").unwrap();
+    let err = from_lua::<Vec<i32>>(ctx.pop::<LuaValue>()).unwrap_err();
+    assert_eq!(err.to_string(), "[1]: invalid value: integer `4294967296`, expected i32");
+}
+
+#[test]
+fn nil_in_sequence() {
+    let ctx = Context::new();
+
+    let err = to_lua(&ctx, &vec![Some(1), None, Some(3)]).unwrap_err();
+    assert_eq!(err.to_string(), "[2]: nil can't be stored in a sequence");
+    assert_eq!(ctx.size(), 0);
+
+    let err = to_lua(&ctx, &vec![vec![Some(1)], vec![None]]).unwrap_err();
+    assert_eq!(err.to_string(), "[2][1]: nil can't be stored in a sequence");
+
+    // optional struct fields are left out of the table instead
+    #[derive(Serialize)]
+    struct Loot {
+        item: Option<String>,
+    }
+    let value = to_lua(&ctx, &vec![Loot { item: None }]).unwrap();
+    ctx.set("loot", value);
+    ctx.eval("return #loot, loot[1].item").unwrap();
+    assert_eq!(ctx.pop::<Option<String>>(), None);
+    assert_eq!(ctx.pop::<i32>(), 1);
+}
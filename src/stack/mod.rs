@@ -4,5 +4,5 @@ pub mod read;
 pub mod size;
 
 pub use self::push::Push;
-pub use self::read::Read;
+pub use self::read::{Read, ReadError};
 pub use self::size::Size;
@@ -1,10 +1,13 @@
 
 use Context;
-use LuaRef;
 use ffi;
 use nil;
 
+use collections::LuaIndex;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::CString;
+use std::hash::Hash;
 
 pub trait Push {
     fn push(&self, ctx: &Context);
@@ -44,6 +47,8 @@ macro_rules! integer_push {
 integer_push!(i8);
 integer_push!(i16);
 integer_push!(i32);
+integer_push!(i64);
+integer_push!(isize);
 
 integer_push!(u8);
 integer_push!(u16);
@@ -62,7 +67,12 @@ macro_rules! number_push {
 number_push!(f32);
 number_push!(f64);
 
-impl<'a> Push for &'a str {
+// `lua_Integer` is signed, so these would wrap to negative numbers above
+// `i64::MAX`
+number_push!(u64);
+number_push!(usize);
+
+impl Push for &str {
     fn push(&self, ctx: &Context) {
         unsafe {
             ffi::lua_pushlstring(ctx.handle, self.as_ptr() as *const i8, self.len());
@@ -82,7 +92,7 @@ impl Push for String {
 impl<T> Push for Option<T> where T: Push {
     fn push(&self, ctx: &Context) {
         match self {
-            &Some(ref p) => {
+            Some(p) => {
                 p.push(ctx)
             }
             &None => {
@@ -94,6 +104,90 @@ impl<T> Push for Option<T> where T: Push {
     }
 }
 
+fn push_seq<'b, T, I>(ctx: &Context, len: usize, items: I)
+    where T: Push + 'b,
+          I: Iterator<Item = &'b T>
+{
+    unsafe {
+        ffi::lua_createtable(ctx.handle, len as i32, 0);
+    }
+
+    for (i, v) in items.enumerate() {
+        v.push(ctx);
+        unsafe {
+            ffi::lua_rawseti(ctx.handle, -2, i as i32 + 1);
+        }
+    }
+}
+
+fn push_map<'b, K, V, I>(ctx: &Context, len: usize, items: I)
+    where K: Push + 'b,
+          V: Push + 'b,
+          I: Iterator<Item = (&'b K, &'b V)>
+{
+    unsafe {
+        ffi::lua_createtable(ctx.handle, 0, len as i32);
+    }
+
+    for (k, v) in items {
+        v.push(ctx);
+        if let Err(err) = k.raw_set(ctx, -2) {
+            ctx.pop_discard(1);
+            panic!("{}", err);
+        }
+    }
+}
+
+// sets become `{k = true}`
+fn push_set<'b, T, I>(ctx: &Context, len: usize, items: I)
+    where T: Push + 'b,
+          I: Iterator<Item = &'b T>
+{
+    push_map(ctx, len, items.map(|k| (k, &true)));
+}
+
+impl<T> Push for Vec<T> where T: Push {
+    fn push(&self, ctx: &Context) {
+        push_seq(ctx, self.len(), self.iter())
+    }
+}
+
+impl<T> Push for &[T] where T: Push {
+    fn push(&self, ctx: &Context) {
+        push_seq(ctx, self.len(), self.iter())
+    }
+}
+
+impl<T, const N: usize> Push for [T; N] where T: Push {
+    fn push(&self, ctx: &Context) {
+        push_seq(ctx, N, self.iter())
+    }
+}
+
+impl<K, V> Push for HashMap<K, V> where K: Push + Eq + Hash, V: Push {
+    fn push(&self, ctx: &Context) {
+        push_map(ctx, self.len(), self.iter())
+    }
+}
+
+impl<K, V> Push for BTreeMap<K, V> where K: Push + Ord, V: Push {
+    fn push(&self, ctx: &Context) {
+        push_map(ctx, self.len(), self.iter())
+    }
+}
+
+impl<T> Push for HashSet<T> where T: Push + Eq + Hash {
+    fn push(&self, ctx: &Context) {
+        push_set(ctx, self.len(), self.iter())
+    }
+}
+
+impl<T> Push for BTreeSet<T> where T: Push + Ord {
+    fn push(&self, ctx: &Context) {
+        push_set(ctx, self.len(), self.iter())
+    }
+}
+
 macro_rules! tuple_push {
     ($($name:ident)+) => (
         impl<$($name: Push),*> Push for ($($name,)*) {
@@ -119,3 +213,13 @@ tuple_push!(A B C D E F G H I J);
 tuple_push!(A B C D E F G H I J K);
 tuple_push!(A B C D E F G H I J K L);
 
+
+#[test]
+fn push_large_unsigned() {
+    let ctx = Context::new();
+
+    ctx.push(u64::MAX);
+    assert_eq!(ctx.pop::<f64>(), 18446744073709551615.0);
+    ctx.push(1usize << 63);
+    assert_eq!(ctx.pop::<f64>(), 9223372036854775808.0);
+}
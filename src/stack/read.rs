@@ -1,16 +1,151 @@
 use Context;
 use LuaValue;
-use LuaRef;
 use ffi;
-use nil;
 
+use std::any;
+use std::array;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::hash::Hash;
 use std::slice;
 use std::str;
-use std::mem;
 
 pub trait Read<'a> {
     fn read(ctx: &'a Context, idx: i32) -> Self;
     fn check(ctx: &'a Context, idx: i32) -> bool;
+
+    // like `check`, but explains which nested value doesn't match
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        match Self::check(ctx, idx) {
+            true => Ok(()),
+            false => Err(ReadError::new::<Self>(ctx, idx))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadError {
+    // where the mismatch is inside a nested value, e.g. `[2].name`
+    pub path: String,
+    pub expected: String,
+    pub found: String,
+}
+
+impl ReadError {
+    pub fn new<T: ?Sized>(ctx: &Context, idx: i32) -> Self {
+        ReadError {
+            path: String::new(),
+            expected: short_type_name::<T>(),
+            found: type_name(ctx, idx),
+        }
+    }
+
+    // prefixes the path with the table key at `idx`
+    pub fn at_key(mut self, ctx: &Context, idx: i32) -> Self {
+        self.path.insert_str(0, &key_segment(ctx, idx));
+        self
+    }
+
+    pub fn at_field(mut self, name: &str) -> Self {
+        self.path.insert_str(0, &field_segment(name));
+        self
+    }
+
+    pub fn at_index(mut self, idx: usize) -> Self {
+        self.path.insert_str(0, &format!("[{}]", idx));
+        self
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "expected {}, found {}", self.expected, self.found),
+            false => write!(f, "expected {} at {}, found {}", self.expected, self.path.trim_start_matches('.'), self.found)
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn description(&self) -> &str {
+        "value has the wrong type"
+    }
+}
+
+fn type_name(ctx: &Context, idx: i32) -> String {
+    unsafe {
+        let name = CStr::from_ptr(ffi::lua_typename(ctx.handle, ffi::lua_type(ctx.handle, idx)));
+        name.to_string_lossy().into_owned()
+    }
+}
+
+// `alloc::vec::Vec<alloc::string::String>` becomes `Vec<String>`
+fn short_type_name<T: ?Sized>() -> String {
+    let mut out = String::new();
+    let mut rest = any::type_name::<T>();
+
+    while let Some(pos) = rest.find("::") {
+        out.push_str(&rest[..pos]);
+        let keep = out.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_').len();
+        out.truncate(keep);
+        rest = &rest[pos + 2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+// The path segments shared by every error that points into a table: `.name`
+// for identifiers, `["on-hit"]` for other strings and `[2]` for numbers.
+pub(crate) fn field_segment(name: &str) -> String {
+    match is_identifier(name) {
+        true => format!(".{}", name),
+        false => format!("[{:?}]", name)
+    }
+}
+
+// the path segment for the key at `idx`, without converting it in place
+pub(crate) fn key_segment(ctx: &Context, idx: i32) -> String {
+    unsafe {
+        match ffi::lua_type(ctx.handle, idx) {
+            ffi::LUA_TNUMBER => format!("[{}]", ffi::lua_tonumber(ctx.handle, idx)),
+            ffi::LUA_TSTRING => {
+                let mut len = 0;
+                let s = ffi::lua_tolstring(ctx.handle, idx, &mut len);
+                field_segment(&String::from_utf8_lossy(slice::from_raw_parts(s as *const u8, len)))
+            }
+            _ => format!("[{}]", type_name(ctx, idx))
+        }
+    }
+}
+
+// the path segment for a key that was already read
+pub(crate) fn value_segment(key: &LuaValue) -> String {
+    match *key {
+        LuaValue::Number(n) => format!("[{}]", n),
+        LuaValue::String(ref s) => field_segment(s),
+        LuaValue::Bytes(ref b) => field_segment(&String::from_utf8_lossy(b)),
+        LuaValue::None => "[nil]".to_string(),
+        ref other => format!("[{}]", other.type_name())
+    }
+}
+
+pub(crate) fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false
+    }
+}
+
+// turns a relative index into one that stays valid after pushing
+fn absolute(ctx: &Context, idx: i32) -> i32 {
+    match idx < 0 && idx > ffi::LUA_REGISTRYINDEX {
+        true => ctx.size() + idx + 1,
+        false => idx
+    }
 }
 
 impl<'a> Read<'a> for bool {
@@ -44,6 +179,7 @@ macro_rules! integer_read {
 integer_read!(i8);
 integer_read!(i16);
 integer_read!(i32);
+integer_read!(i64);
 
 macro_rules! number_read {
     ($ty:ident) => (
@@ -62,15 +198,15 @@ macro_rules! number_read {
 number_read!(f32);
 number_read!(f64);
 
-impl<'a, 'b> Read<'a> for &'b str {
+impl<'a> Read<'a> for &str {
     fn read(ctx: &'a Context, idx: i32) -> Self {
         unsafe {
             let slice = {
                 let mut size = 0;
                 let cs = ffi::lua_tolstring(ctx.handle, idx, &mut size);
-                slice::from_raw_parts(cs, size as usize)
+                slice::from_raw_parts(cs as *const u8, size)
             };
-            str::from_utf8(mem::transmute(slice)).unwrap()
+            str::from_utf8(slice).unwrap()
         }
     }
 
@@ -87,9 +223,9 @@ impl<'a> Read<'a> for String {
             let slice = {
                 let mut size = 0;
                 let cs = ffi::lua_tolstring(ctx.handle, idx, &mut size);
-                slice::from_raw_parts(cs, size as usize)
+                slice::from_raw_parts(cs as *const u8, size)
             };
-            String::from_utf8_lossy(mem::transmute(slice)).into_owned()
+            String::from_utf8_lossy(slice).into_owned()
         }
     }
 
@@ -116,6 +252,215 @@ impl<'a, T> Read<'a> for Option<T> where T: Read<'a> {
             ffi::lua_isnil(ctx.handle, idx)
         }
     }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        match unsafe { ffi::lua_isnil(ctx.handle, idx) } {
+            true => Ok(()),
+            false => T::validate(ctx, idx)
+        }
+    }
+}
+
+fn read_seq<'a, T>(ctx: &'a Context, idx: i32) -> Vec<T>
+    where T: Read<'a>
+{
+    let idx = absolute(ctx, idx);
+    let len = unsafe { ffi::lua_objlen(ctx.handle, idx) };
+
+    (1..len as i32 + 1).map(|i| {
+        unsafe {
+            ffi::lua_rawgeti(ctx.handle, idx, i);
+        }
+        let v = T::read(ctx, -1);
+        ctx.pop_discard(1);
+        v
+    }).collect()
+}
+
+fn validate_seq<'a, S, T>(ctx: &'a Context, idx: i32) -> Result<usize, ReadError>
+    where S: ?Sized,
+          T: Read<'a>
+{
+    let idx = absolute(ctx, idx);
+
+    unsafe {
+        if !ffi::lua_istable(ctx.handle, idx) {
+            return Err(ReadError::new::<S>(ctx, idx));
+        }
+
+        let len = ffi::lua_objlen(ctx.handle, idx) as usize;
+        for i in 1..len + 1 {
+            ffi::lua_rawgeti(ctx.handle, idx, i as i32);
+            let ret = T::validate(ctx, -1);
+            ctx.pop_discard(1);
+            ret.map_err(|e| e.at_index(i))?;
+        }
+
+        Ok(len)
+    }
+}
+
+// calls `f` with every key and value of the table at `idx`
+fn read_pairs<'a, K, V, F>(ctx: &'a Context, idx: i32, mut f: F)
+    where K: Read<'a>,
+          V: Read<'a>,
+          F: FnMut(K, V)
+{
+    let idx = absolute(ctx, idx);
+
+    unsafe {
+        ffi::lua_pushnil(ctx.handle);
+        while ffi::lua_next(ctx.handle, idx) != 0 {
+            // read a copy so converting the key can't confuse `lua_next`
+            ffi::lua_pushvalue(ctx.handle, -2);
+            let k = K::read(ctx, -1);
+            let v = V::read(ctx, -2);
+            ctx.pop_discard(2);
+            f(k, v);
+        }
+    }
+}
+
+fn validate_pairs<'a, S, K, V>(ctx: &'a Context, idx: i32) -> Result<(), ReadError>
+    where S: ?Sized,
+          K: Read<'a>,
+          V: Read<'a>
+{
+    let idx = absolute(ctx, idx);
+
+    unsafe {
+        if !ffi::lua_istable(ctx.handle, idx) {
+            return Err(ReadError::new::<S>(ctx, idx));
+        }
+
+        ffi::lua_pushnil(ctx.handle);
+        while ffi::lua_next(ctx.handle, idx) != 0 {
+            ffi::lua_pushvalue(ctx.handle, -2);
+            let ret = K::validate(ctx, -1)
+                .and_then(|_| V::validate(ctx, -2))
+                .map_err(|e| e.at_key(ctx, -3));
+            ctx.pop_discard(2);
+
+            if ret.is_err() {
+                ctx.pop_discard(1);
+                return ret;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl<'a, T> Read<'a> for Vec<T> where T: Read<'a> {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        read_seq(ctx, idx)
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        Self::validate(ctx, idx).is_ok()
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        validate_seq::<Self, T>(ctx, idx).map(|_| ())
+    }
+}
+
+impl<'a, T, const N: usize> Read<'a> for [T; N] where T: Read<'a> {
+    // reads `[1]` to `[N]` whatever the length, like `read` of any other
+    // mismatched value; `validate` reports the wrong length
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        let idx = absolute(ctx, idx);
+
+        array::from_fn(|i| {
+            unsafe {
+                ffi::lua_rawgeti(ctx.handle, idx, i as i32 + 1);
+            }
+            let v = T::read(ctx, -1);
+            ctx.pop_discard(1);
+            v
+        })
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        Self::validate(ctx, idx).is_ok()
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        let len = validate_seq::<Self, T>(ctx, idx)?;
+        match len == N {
+            true => Ok(()),
+            false => Err(ReadError {
+                path: String::new(),
+                expected: short_type_name::<Self>(),
+                found: format!("table of length {}", len),
+            })
+        }
+    }
+}
+
+impl<'a, K, V> Read<'a> for HashMap<K, V> where K: Read<'a> + Eq + Hash, V: Read<'a> {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        let mut map = HashMap::new();
+        read_pairs(ctx, idx, |k, v| { map.insert(k, v); });
+        map
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        Self::validate(ctx, idx).is_ok()
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        validate_pairs::<Self, K, V>(ctx, idx)
+    }
+}
+
+impl<'a, K, V> Read<'a> for BTreeMap<K, V> where K: Read<'a> + Ord, V: Read<'a> {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        let mut map = BTreeMap::new();
+        read_pairs(ctx, idx, |k, v| { map.insert(k, v); });
+        map
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        Self::validate(ctx, idx).is_ok()
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        validate_pairs::<Self, K, V>(ctx, idx)
+    }
+}
+
+// sets are read from the keys of `{k = true}`, keys mapped to `false` are left out
+impl<'a, T> Read<'a> for HashSet<T> where T: Read<'a> + Eq + Hash {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        let mut set = HashSet::new();
+        read_pairs(ctx, idx, |k, v: bool| if v { set.insert(k); });
+        set
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        Self::validate(ctx, idx).is_ok()
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        validate_pairs::<Self, T, bool>(ctx, idx)
+    }
+}
+
+impl<'a, T> Read<'a> for BTreeSet<T> where T: Read<'a> + Ord {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        let mut set = BTreeSet::new();
+        read_pairs(ctx, idx, |k, v: bool| if v { set.insert(k); });
+        set
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        Self::validate(ctx, idx).is_ok()
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        validate_pairs::<Self, T, bool>(ctx, idx)
+    }
 }
 
 /*macro_rules! tuple_read {
@@ -192,6 +537,8 @@ fn read_string() {
 
 #[test]
 fn read_optional() {
+    use nil;
+
     let ctx = Context::new();
 
     ctx.push(("Hello world!", nil));
@@ -202,3 +549,62 @@ fn read_optional() {
     /*push!(&ctx, flu::nil, 5f64, flu::nil);
     assert_eq!(ctx.pop::<(Option<f64>, Option<f64>, Option<f64>)>(), (None, Some(5f64), None));*/
 }
+
+#[test]
+fn read_collections() {
+    let ctx = Context::new();
+
+    ctx.push(vec![1, 2, 3]);
+    assert_eq!(ctx.pop::<Vec<i32>>(), vec![1, 2, 3]);
+
+    ctx.push(&[1.5, 2.5][..]);
+    assert_eq!(ctx.pop::<[f64; 2]>(), [1.5, 2.5]);
+
+    let mut map = HashMap::new();
+    map.insert("a".to_string(), 1.0);
+    map.insert("b".to_string(), 2.0);
+    ctx.push(vec![map.clone(), HashMap::new()]);
+    assert_eq!(ctx.pop::<Vec<HashMap<String, f64>>>(), vec![map, HashMap::new()]);
+
+    let mut tree = BTreeMap::new();
+    tree.insert(1, "one".to_string());
+    ctx.push(tree.clone());
+    assert_eq!(ctx.pop::<BTreeMap<i32, String>>(), tree);
+
+    let set: BTreeSet<String> = vec!["x".to_string(), "y".to_string()].into_iter().collect();
+    ctx.push(set.clone());
+    assert_eq!(ctx.pop::<BTreeSet<String>>(), set);
+
+    ctx.eval("return { a = true, b = false }").unwrap();
+    assert_eq!(ctx.pop::<HashSet<String>>().into_iter().collect::<Vec<_>>(), vec!["a".to_string()]);
+
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn read_error_path() {
+    let ctx = Context::new();
+
+    ctx.eval("return { { x = 1 }, { x = 2, [\"a key\"] = {} } }").unwrap();
+    let err = ctx.try_pop::<Vec<HashMap<String, f64>>>().unwrap_err();
+    assert_eq!(err.to_string(), "expected f64 at [2][\"a key\"], found table");
+
+    ctx.eval("return { list = { 1, 2, \"three\" } }").unwrap();
+    let err = ctx.try_pop::<HashMap<String, Vec<i32>>>().unwrap_err();
+    assert_eq!(err.to_string(), "expected i32 at list[3], found string");
+
+    ctx.push(vec![1, 2]);
+    let err = ctx.try_pop::<[i32; 3]>().unwrap_err();
+    assert_eq!(err.to_string(), "expected [i32; 3], found table of length 2");
+
+    // unchecked reads don't panic on the wrong length
+    ctx.push(vec![1, 2]);
+    assert_eq!(ctx.pop::<[i32; 3]>(), [1, 2, 0]);
+    ctx.push(vec![1, 2, 3, 4]);
+    assert_eq!(ctx.pop::<[i32; 3]>(), [1, 2, 3]);
+
+    ctx.push(5);
+    assert_eq!(ctx.try_pop::<Vec<i32>>().unwrap_err().to_string(), "expected Vec<i32>, found number");
+
+    assert_eq!(ctx.size(), 0);
+}
@@ -1,8 +1,8 @@
 
-use LuaValue;
-use LuaRef;
 use nil;
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
 pub trait Size {
     fn size() -> i32;
 }
@@ -23,13 +23,14 @@ type_size!(bool, 1);
 type_size!(i8, 1);
 type_size!(i16, 1);
 type_size!(i32, 1);
+type_size!(i64, 1);
 
 type_size!(f32, 1);
 type_size!(f64, 1);
 
 type_size!(String, 1);
 
-impl<'a> Size for &'a str {
+impl Size for &str {
     fn size() -> i32 {
         1
     }
@@ -41,6 +42,48 @@ impl<T> Size for Option<T> where T: Size {
     }
 }
 
+impl<T> Size for Vec<T> {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl<T> Size for &[T] {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl<T, const N: usize> Size for [T; N] {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl<K, V> Size for HashMap<K, V> {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl<K, V> Size for BTreeMap<K, V> {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl<T> Size for HashSet<T> {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl<T> Size for BTreeSet<T> {
+    fn size() -> i32 {
+        1
+    }
+}
+
 impl Size for () {
     fn size() -> i32 {
         0
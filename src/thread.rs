@@ -0,0 +1,107 @@
+use Context;
+use LuaRef;
+use ffi;
+
+use stack::Read;
+use stack::Push;
+use stack::Size;
+
+use std::mem;
+
+// the values returned by `coroutine.status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadStatus {
+    Running,
+    Suspended,
+    // resumed another coroutine and is waiting for it
+    Normal,
+    Dead,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Thread<'a> {
+    pub(crate) ctx: &'a Context,
+    pub(crate) ptr: LuaRef<'a>,
+}
+
+impl<'a> Thread<'a> {
+    pub fn as_ptr(&self) -> *mut ffi::lua_State {
+        self.ptr.push(self.ctx);
+        let state = unsafe { ffi::lua_tothread(self.ctx.handle, -1) };
+        self.ctx.pop_discard(1);
+        state
+    }
+
+    // same rules as `coroutine.status`
+    pub fn status(&self) -> ThreadStatus {
+        let co = self.as_ptr();
+        if co == self.ctx.handle {
+            return ThreadStatus::Running;
+        }
+
+        unsafe {
+            match ffi::lua_status(co) {
+                ffi::LUA_YIELD => ThreadStatus::Suspended,
+                0 => {
+                    let mut ar: ffi::lua_Debug = mem::zeroed();
+                    if ffi::lua_getstack(co, 0, &mut ar) > 0 {
+                        ThreadStatus::Normal
+                    } else if ffi::lua_gettop(co) == 0 {
+                        ThreadStatus::Dead
+                    } else {
+                        // not started yet, the function is still on its stack
+                        ThreadStatus::Suspended
+                    }
+                }
+                _ => ThreadStatus::Dead
+            }
+        }
+    }
+}
+
+impl<'a> Read<'a> for Thread<'a> {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        Thread {
+            ctx,
+            ptr: LuaRef::read(ctx, idx)
+        }
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        unsafe {
+            ffi::lua_type(ctx.handle, idx) == ffi::LUA_TTHREAD
+        }
+    }
+}
+
+impl<'a> Push for Thread<'a> {
+    fn push(&self, ctx: &Context) {
+        self.ptr.push(ctx)
+    }
+}
+
+impl<'a> Size for Thread<'a> {
+    fn size() -> i32 {
+        1
+    }
+}
+
+#[test]
+fn thread_status() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.eval("co = coroutine.create(function() coroutine.yield() end)").unwrap();
+    let co = ctx.get::<Thread>("co");
+    assert_eq!(co.status(), ThreadStatus::Suspended);
+
+    ctx.eval("coroutine.resume(co)").unwrap();
+    assert_eq!(co.status(), ThreadStatus::Suspended);
+
+    ctx.eval("coroutine.resume(co)").unwrap();
+    assert_eq!(co.status(), ThreadStatus::Dead);
+
+    unsafe { ffi::lua_pushthread(ctx.handle) };
+    assert_eq!(ctx.pop::<Thread>().status(), ThreadStatus::Running);
+    assert_eq!(ctx.size(), 0);
+}
@@ -0,0 +1,585 @@
+use Context;
+use LuaRef;
+use ffi;
+
+use stack::Push;
+use stack::Read;
+use stack::ReadError;
+use stack::Size;
+
+use libc;
+
+use std::any;
+use std::cell::{Cell, UnsafeCell};
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+// A plain Rust function exposed to Lua; arguments are on the stack and the
+// return value is the number of results pushed, as with `lua_CFunction`.
+pub type Callback = fn(&Context) -> i32;
+
+// A Rust type that can live inside a Lua full userdata. `#[flu::userdata]`
+// and `#[flu::methods]` implement this, but it can also be written by hand.
+pub trait UserData: Sized + 'static {
+    // the name used in error messages and for the global holding the
+    // associated functions
+    const NAME: &'static str;
+
+    fn register(registry: &mut UserDataRegistry<Self>);
+}
+
+// implemented by `#[flu::methods]` and called from the `UserData` impl
+// generated by `#[flu::userdata]`
+pub trait UserDataMethods: Sized {
+    fn methods(registry: &mut UserDataRegistry<Self>);
+}
+
+pub struct UserDataRegistry<T> {
+    methods: Vec<(&'static str, Callback)>,
+    getters: Vec<(&'static str, Callback)>,
+    setters: Vec<(&'static str, Callback)>,
+    functions: Vec<(&'static str, Callback)>,
+    _pd: PhantomData<T>,
+}
+
+impl<T> UserDataRegistry<T> {
+    // called with the userdata at index 1 and the arguments after it
+    pub fn method(&mut self, name: &'static str, f: Callback) {
+        self.methods.push((name, f));
+    }
+
+    // `ud.name`, called with the userdata at index 1
+    pub fn getter(&mut self, name: &'static str, f: Callback) {
+        self.getters.push((name, f));
+    }
+
+    // `ud.name = value`, called with the userdata at index 1 and the value at 3
+    pub fn setter(&mut self, name: &'static str, f: Callback) {
+        self.setters.push((name, f));
+    }
+
+    // stored in the global table named after the type, e.g. `Point.new`
+    pub fn function(&mut self, name: &'static str, f: Callback) {
+        self.functions.push((name, f));
+    }
+}
+
+fn registry<T: UserData>() -> UserDataRegistry<T> {
+    let mut registry = UserDataRegistry {
+        methods: Vec::new(),
+        getters: Vec::new(),
+        setters: Vec::new(),
+        functions: Vec::new(),
+        _pd: PhantomData,
+    };
+    T::register(&mut registry);
+    registry
+}
+
+// the registry key of the metatable, unique per Rust type
+fn metatable_key<T: UserData>() -> CString {
+    CString::new(format!("flu.userdata.{}", any::type_name::<T>())).unwrap()
+}
+
+unsafe fn push_callbacks(ctx: &Context, callbacks: &[(&'static str, Callback)]) {
+    ffi::lua_createtable(ctx.handle, 0, callbacks.len() as i32);
+    for &(name, f) in callbacks {
+        ctx.push(name);
+        ffi::lua_pushlightuserdata(ctx.handle, f as *mut libc::c_void);
+        ffi::lua_pushcclosure(ctx.handle, trampoline, 1);
+        ffi::lua_rawset(ctx.handle, -3);
+    }
+}
+
+// pushes the metatable for `T`, creating it on first use
+unsafe fn push_metatable<T: UserData>(ctx: &Context) {
+    let key = metatable_key::<T>();
+    if ffi::luaL_newmetatable(ctx.handle, key.as_ptr()) == 0 {
+        return;
+    }
+
+    let registry = registry::<T>();
+
+    ctx.push("__index");
+    push_callbacks(ctx, &registry.getters);
+    push_callbacks(ctx, &registry.methods);
+    ffi::lua_pushcclosure(ctx.handle, index, 2);
+    ffi::lua_rawset(ctx.handle, -3);
+
+    ctx.push("__newindex");
+    push_callbacks(ctx, &registry.setters);
+    ctx.push(T::NAME);
+    ffi::lua_pushcclosure(ctx.handle, newindex, 2);
+    ffi::lua_rawset(ctx.handle, -3);
+
+    ctx.push("__gc");
+    ffi::lua_pushcfunction(ctx.handle, gc::<T>);
+    ffi::lua_rawset(ctx.handle, -3);
+
+    ctx.push("__tostring");
+    ffi::lua_pushcfunction(ctx.handle, tostring::<T>);
+    ffi::lua_rawset(ctx.handle, -3);
+
+    // keeps scripts from swapping the metatable through `getmetatable`
+    ctx.push("__metatable");
+    ctx.push(T::NAME);
+    ffi::lua_rawset(ctx.handle, -3);
+}
+
+// What a userdata created by `push_userdata` holds. Lua hands out the same
+// value to every script reference, so borrows are tracked at runtime like
+// `RefCell` does: a positive count of shared borrows, or -1 while it is
+// borrowed mutably.
+struct UserDataCell<T> {
+    borrow: Cell<isize>,
+    value: UnsafeCell<T>,
+}
+
+// the cell inside the userdata at `idx`, or `None` if it holds something else
+unsafe fn cell<T: UserData>(ctx: &Context, idx: i32) -> Option<*const UserDataCell<T>> {
+    let ud = ffi::lua_touserdata(ctx.handle, idx) as *const UserDataCell<T>;
+    if ud.is_null() || ffi::lua_getmetatable(ctx.handle, idx) == 0 {
+        return None;
+    }
+
+    let key = metatable_key::<T>();
+    ffi::luaL_getmetatable(ctx.handle, key.as_ptr());
+    let same = ffi::lua_rawequal(ctx.handle, -1, -2) != 0;
+    ctx.pop_discard(2);
+
+    match same {
+        true => Some(ud),
+        false => None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserDataError {
+    // the value isn't a userdata of the requested type
+    Type(ReadError),
+    // a mutable borrow was requested while other borrows are alive
+    Borrowed(&'static str),
+    // any borrow was requested while a mutable one is alive
+    BorrowedMut(&'static str),
+}
+
+impl fmt::Display for UserDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UserDataError::Type(ref err) => write!(f, "{}", err),
+            UserDataError::Borrowed(name) => write!(f, "{} is already borrowed", name),
+            UserDataError::BorrowedMut(name) => write!(f, "{} is already mutably borrowed", name),
+        }
+    }
+}
+
+impl Error for UserDataError {
+    fn description(&self) -> &str {
+        match *self {
+            UserDataError::Type(..) => "userdata type mismatch",
+            UserDataError::Borrowed(..) => "userdata already borrowed",
+            UserDataError::BorrowedMut(..) => "userdata already mutably borrowed",
+        }
+    }
+}
+
+fn type_error<T: UserData>(ctx: &Context, idx: i32) -> UserDataError {
+    UserDataError::Type(ReadError { expected: T::NAME.to_string(), ..ReadError::new::<T>(ctx, idx) })
+}
+
+// A shared borrow of the `T` inside a userdata. The reference it holds keeps
+// the userdata alive, so the borrow can outlive the stack slot it came from.
+pub struct UserDataRef<'a, T> {
+    cell: *const UserDataCell<T>,
+    _anchor: LuaRef<'a>,
+}
+
+impl<'a, T> Deref for UserDataRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(*self.cell).value.get() }
+    }
+}
+
+impl<'a, T> Drop for UserDataRef<'a, T> {
+    fn drop(&mut self) {
+        let borrow = unsafe { &(*self.cell).borrow };
+        borrow.set(borrow.get() - 1);
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for UserDataRef<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// A mutable borrow of the `T` inside a userdata, see `UserDataRef`.
+pub struct UserDataRefMut<'a, T> {
+    cell: *const UserDataCell<T>,
+    _anchor: LuaRef<'a>,
+}
+
+impl<'a, T> Deref for UserDataRefMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(*self.cell).value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for UserDataRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(*self.cell).value.get() }
+    }
+}
+
+impl<'a, T> Drop for UserDataRefMut<'a, T> {
+    fn drop(&mut self) {
+        unsafe { (*self.cell).borrow.set(0) }
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for UserDataRefMut<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Context {
+    // sets the global `T::NAME` to a table of the type's associated functions
+    pub fn register<T: UserData>(&self) {
+        let registry = registry::<T>();
+
+        unsafe {
+            push_metatable::<T>(self);
+            self.pop_discard(1);
+
+            push_callbacks(self, &registry.functions);
+            let name = CString::new(T::NAME).unwrap();
+            ffi::lua_setfield(self.handle, ffi::LUA_GLOBALSINDEX, name.as_ptr());
+        }
+    }
+
+    // moves `value` into a new userdata on top of the stack
+    pub fn push_userdata<T: UserData>(&self, value: T) {
+        unsafe {
+            let size = mem::size_of::<UserDataCell<T>>() as libc::size_t;
+            let ud = ffi::lua_newuserdata(self.handle, size) as *mut UserDataCell<T>;
+            ptr::write(ud, UserDataCell { borrow: Cell::new(0), value: UnsafeCell::new(value) });
+
+            push_metatable::<T>(self);
+            ffi::lua_setmetatable(self.handle, -2);
+        }
+    }
+
+    // borrows the `T` inside the userdata at `idx`
+    pub fn userdata<T: UserData>(&self, idx: i32) -> Result<UserDataRef<'_, T>, UserDataError> {
+        let cell = match unsafe { cell::<T>(self, idx) } {
+            Some(cell) => cell,
+            None => return Err(type_error::<T>(self, idx))
+        };
+
+        let borrow = unsafe { &(*cell).borrow };
+        if borrow.get() < 0 {
+            return Err(UserDataError::BorrowedMut(T::NAME));
+        }
+        borrow.set(borrow.get() + 1);
+
+        Ok(UserDataRef { cell, _anchor: LuaRef::read(self, idx) })
+    }
+
+    pub fn userdata_mut<T: UserData>(&self, idx: i32) -> Result<UserDataRefMut<'_, T>, UserDataError> {
+        let cell = match unsafe { cell::<T>(self, idx) } {
+            Some(cell) => cell,
+            None => return Err(type_error::<T>(self, idx))
+        };
+
+        let borrow = unsafe { &(*cell).borrow };
+        match borrow.get() {
+            0 => borrow.set(-1),
+            n if n < 0 => return Err(UserDataError::BorrowedMut(T::NAME)),
+            _ => return Err(UserDataError::Borrowed(T::NAME))
+        }
+
+        Ok(UserDataRefMut { cell, _anchor: LuaRef::read(self, idx) })
+    }
+
+    // For callbacks: reads argument `idx` of `func`, or raises a
+    // "bad argument" error and returns `Err` with the value to return.
+    pub fn check_arg<'a, A>(&'a self, idx: i32, func: &str) -> Result<A, i32>
+        where A: Read<'a>
+    {
+        self.try_peek::<A>(idx).map_err(|e| {
+            self.error(format!("bad argument #{} to '{}' ({})", idx, func, e))
+        })
+    }
+
+    pub fn check_userdata<T: UserData>(&self, idx: i32, func: &str) -> Result<UserDataRef<'_, T>, i32> {
+        self.userdata::<T>(idx).map_err(|e| {
+            self.error(format!("bad argument #{} to '{}' ({})", idx, func, e))
+        })
+    }
+
+    pub fn check_userdata_mut<T: UserData>(&self, idx: i32, func: &str) -> Result<UserDataRefMut<'_, T>, i32> {
+        self.userdata_mut::<T>(idx).map_err(|e| {
+            self.error(format!("bad argument #{} to '{}' ({})", idx, func, e))
+        })
+    }
+}
+
+// reading panics if the value is already borrowed in a conflicting way
+impl<'a, T> Read<'a> for UserDataRef<'a, T> where T: UserData {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        ctx.userdata::<T>(idx).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        unsafe { cell::<T>(ctx, idx).is_some() }
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        match unsafe { cell::<T>(ctx, idx) } {
+            Some(_) => Ok(()),
+            None => Err(ReadError { expected: T::NAME.to_string(), ..ReadError::new::<T>(ctx, idx) })
+        }
+    }
+}
+
+impl<'a, T> Read<'a> for UserDataRefMut<'a, T> where T: UserData {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        ctx.userdata_mut::<T>(idx).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        <UserDataRef<T>>::check(ctx, idx)
+    }
+
+    fn validate(ctx: &'a Context, idx: i32) -> Result<(), ReadError> {
+        <UserDataRef<T>>::validate(ctx, idx)
+    }
+}
+
+impl<'a, T> Size for UserDataRef<'a, T> where T: UserData {
+    fn size() -> i32 {
+        1
+    }
+}
+
+impl<'a, T> Size for UserDataRefMut<'a, T> where T: UserData {
+    fn size() -> i32 {
+        1
+    }
+}
+
+// A full userdata of any type, including ones created by Lua libraries.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AnyUserData<'a> {
+    pub(crate) ctx: &'a Context,
+    pub(crate) ptr: LuaRef<'a>,
+}
+
+impl<'a> AnyUserData<'a> {
+    pub fn is<T: UserData>(&self) -> bool {
+        self.ptr.push(self.ctx);
+        let is = unsafe { cell::<T>(self.ctx, -1).is_some() };
+        self.ctx.pop_discard(1);
+        is
+    }
+
+    // borrows the `T` inside, see `Context::userdata`
+    pub fn get<T: UserData>(&self) -> Result<UserDataRef<'a, T>, UserDataError> {
+        self.ptr.push(self.ctx);
+        let ud = self.ctx.userdata::<T>(-1);
+        self.ctx.pop_discard(1);
+        ud
+    }
+
+    pub fn get_mut<T: UserData>(&self) -> Result<UserDataRefMut<'a, T>, UserDataError> {
+        self.ptr.push(self.ctx);
+        let ud = self.ctx.userdata_mut::<T>(-1);
+        self.ctx.pop_discard(1);
+        ud
+    }
+}
+
+impl<'a> Read<'a> for AnyUserData<'a> {
+    fn read(ctx: &'a Context, idx: i32) -> Self {
+        AnyUserData {
+            ctx,
+            ptr: LuaRef::read(ctx, idx)
+        }
+    }
+
+    fn check(ctx: &'a Context, idx: i32) -> bool {
+        unsafe {
+            ffi::lua_type(ctx.handle, idx) == ffi::LUA_TUSERDATA
+        }
+    }
+}
+
+impl<'a> Push for AnyUserData<'a> {
+    fn push(&self, ctx: &Context) {
+        self.ptr.push(ctx)
+    }
+}
+
+impl<'a> Size for AnyUserData<'a> {
+    fn size() -> i32 {
+        1
+    }
+}
+
+unsafe extern "C" fn trampoline(state: *mut ffi::lua_State) -> libc::c_int {
+    let (ret, raised) = {
+        let ctx = Context::from_state_weak(state);
+        let f: Callback = mem::transmute(ffi::lua_touserdata(state, ffi::lua_upvalueindex(1)));
+
+        let ret = f(&ctx);
+        (ret, ctx.raised.get())
+    };
+
+    // `lua_error` never returns, so only raise once everything above is dropped
+    match raised {
+        true => ffi::lua_error(state),
+        false => ret as libc::c_int
+    }
+}
+
+// upvalues: getters, methods
+unsafe extern "C" fn index(state: *mut ffi::lua_State) -> libc::c_int {
+    ffi::lua_pushvalue(state, 2);
+    ffi::lua_rawget(state, ffi::lua_upvalueindex(1));
+    if !ffi::lua_isnil(state, -1) {
+        ffi::lua_pushvalue(state, 1);
+        ffi::lua_call(state, 1, 1);
+        return 1;
+    }
+
+    ffi::lua_pushvalue(state, 2);
+    ffi::lua_rawget(state, ffi::lua_upvalueindex(2));
+    1
+}
+
+// upvalues: setters, type name
+unsafe extern "C" fn newindex(state: *mut ffi::lua_State) -> libc::c_int {
+    ffi::lua_pushvalue(state, 2);
+    ffi::lua_rawget(state, ffi::lua_upvalueindex(1));
+    if !ffi::lua_isnil(state, -1) {
+        ffi::lua_pushvalue(state, 1);
+        ffi::lua_pushvalue(state, 2);
+        ffi::lua_pushvalue(state, 3);
+        ffi::lua_call(state, 3, 0);
+        return 0;
+    }
+
+    {
+        let ctx = Context::from_state_weak(state);
+        let msg = format!("cannot assign '{}' on {}",
+                          ::value::preview(&ctx, 2).trim_matches('"'),
+                          String::read(&ctx, ffi::lua_upvalueindex(2)));
+        ctx.push(msg);
+    }
+    ffi::lua_error(state)
+}
+
+unsafe extern "C" fn gc<T>(state: *mut ffi::lua_State) -> libc::c_int {
+    ptr::drop_in_place(ffi::lua_touserdata(state, 1) as *mut UserDataCell<T>);
+    0
+}
+
+unsafe extern "C" fn tostring<T: UserData>(state: *mut ffi::lua_State) -> libc::c_int {
+    let ctx = Context::from_state_weak(state);
+    ctx.push(format!("{}: {:p}", T::NAME, ffi::lua_touserdata(state, 1)));
+    1
+}
+
+#[cfg(test)]
+struct Counter {
+    count: i32,
+}
+
+#[cfg(test)]
+impl UserData for Counter {
+    const NAME: &'static str = "Counter";
+
+    fn register(registry: &mut UserDataRegistry<Self>) {
+        registry.function("new", |ctx| {
+            let start = match ctx.check_arg::<i32>(1, "new") {
+                Ok(v) => v,
+                Err(e) => return e
+            };
+            ctx.push_userdata(Counter { count: start });
+            1
+        });
+        registry.method("bump", |ctx| {
+            match ctx.check_userdata_mut::<Counter>(1, "bump") {
+                Ok(mut c) => c.count += 1,
+                Err(e) => return e
+            }
+            0
+        });
+        registry.getter("count", |ctx| {
+            let count = ctx.userdata::<Counter>(1).unwrap().count;
+            ctx.push(count);
+            1
+        });
+    }
+}
+
+#[test]
+fn userdata() {
+    let ctx = Context::new();
+    ctx.register::<Counter>();
+
+    ctx.eval("c = Counter.new(5) c:bump() c:bump() return c.count").unwrap();
+    assert_eq!(ctx.pop::<i32>(), 7);
+
+    ctx.eval("return c").unwrap();
+    let c = ctx.pop::<UserDataRef<Counter>>();
+    assert_eq!(c.count, 7);
+
+    // scripts can't mutate it while Rust holds a borrow
+    let err = ctx.eval("c:bump()").unwrap_err();
+    assert_eq!(err.to_string(), "bad argument #1 to 'bump' (Counter is already borrowed)");
+    drop(c);
+    ctx.eval("c:bump()").unwrap();
+
+    // and a borrow keeps it alive once scripts drop it
+    ctx.eval("return c").unwrap();
+    let mut c = ctx.pop::<UserDataRefMut<Counter>>();
+    unsafe {
+        ffi::lua_pushnil(ctx.handle);
+        ffi::lua_setfield(ctx.handle, ffi::LUA_GLOBALSINDEX, c_str!("c"));
+        ffi::lua_gc(ctx.handle, ffi::LUA_GCCOLLECT, 0);
+    }
+    c.count += 1;
+    assert_eq!(c.count, 9);
+    drop(c);
+
+    ctx.eval("c = Counter.new(1)").unwrap();
+    let ud = ctx.get::<AnyUserData>("c");
+    assert!(ud.is::<Counter>());
+    let (a, b) = (ud.get::<Counter>().unwrap(), ud.get::<Counter>().unwrap());
+    assert_eq!(a.count + b.count, 2);
+    assert_eq!(ud.get_mut::<Counter>().err(), Some(UserDataError::Borrowed("Counter")));
+    drop((a, b));
+    ud.get_mut::<Counter>().unwrap().count = 3;
+    assert_eq!(ud.get::<Counter>().unwrap().count, 3);
+
+    let err = ctx.eval("Counter.new('x')").unwrap_err();
+    assert_eq!(err.to_string(), "bad argument #1 to 'new' (expected i32, found string)");
+
+    let err = ctx.eval("c.bump({})").unwrap_err();
+    assert_eq!(err.to_string(), "bad argument #1 to 'bump' (expected Counter, found table)");
+
+    let err = ctx.eval("c.count = 1").unwrap_err();
+    assert_eq!(err.to_string(), "cannot assign 'count' on Counter");
+    assert_eq!(ctx.size(), 0);
+}
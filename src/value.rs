@@ -1,50 +1,269 @@
+use AnyUserData;
 use Context;
 use LuaRef;
 use Function;
 use Table;
+use Thread;
 use ffi;
 use nil;
 
 use stack::Read;
+use stack::Push;
 use stack::Size;
 
+use libc;
+
+use std::ffi::CStr;
+use std::fmt;
+use std::slice;
+
 #[derive(Debug, PartialEq)]
 pub enum LuaValue<'a> {
     Number(f64),
-    String(&'a str),
+    // strings are copied out, so they stay valid after the value is popped
+    String(String),
+    // a string that isn't valid UTF-8
+    Bytes(Vec<u8>),
     Bool(bool),
     Table(Table<'a>),
     Function(Function<'a>),
-    /*Userdata,
-    Thread,*/
+    LightUserData(*mut libc::c_void),
+    UserData(AnyUserData<'a>),
+    Thread(Thread<'a>),
     Nil,
     None,
 }
 
+impl<'a> LuaValue<'a> {
+    // the name `type` would return
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            &LuaValue::Number(..) => "number",
+            &LuaValue::String(..) |
+            &LuaValue::Bytes(..) => "string",
+            &LuaValue::Bool(..) => "boolean",
+            &LuaValue::Table(..) => "table",
+            &LuaValue::Function(..) => "function",
+            &LuaValue::LightUserData(..) |
+            &LuaValue::UserData(..) => "userdata",
+            &LuaValue::Thread(..) => "thread",
+            &LuaValue::Nil => "nil",
+            &LuaValue::None => "no value",
+        }
+    }
+
+    // the raw bytes of a string, whether or not it is UTF-8
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            LuaValue::String(s) => Some(s.as_bytes()),
+            LuaValue::Bytes(b) => Some(b),
+            _ => None
+        }
+    }
+}
+
 impl<'a> Read<'a> for LuaValue<'a> {
     fn read(ctx: &'a Context, idx: i32) -> Self {
         unsafe {
             match ffi::lua_type(ctx.handle, idx) {
-                ffi::LUA_TNONE => LuaValue::None,
                 ffi::LUA_TNIL => LuaValue::Nil,
                 ffi::LUA_TBOOLEAN => LuaValue::Bool(bool::read(ctx, idx)),
-                ffi::LUA_TLIGHTUSERDATA => unimplemented!(),
+                ffi::LUA_TLIGHTUSERDATA => LuaValue::LightUserData(ffi::lua_touserdata(ctx.handle, idx)),
                 ffi::LUA_TNUMBER => LuaValue::Number(f64::read(ctx, idx)),
-                ffi::LUA_TSTRING => LuaValue::String(<&str>::read(ctx, idx)),
-                ffi::LUA_TTABLE => LuaValue::Table(Table { ctx: ctx, ptr: <LuaRef>::read(ctx, idx) }),
+                ffi::LUA_TSTRING => {
+                    let mut size = 0;
+                    let s = ffi::lua_tolstring(ctx.handle, idx, &mut size);
+                    let bytes = slice::from_raw_parts(s as *const u8, size).to_vec();
+
+                    match String::from_utf8(bytes) {
+                        Ok(s) => LuaValue::String(s),
+                        Err(e) => LuaValue::Bytes(e.into_bytes())
+                    }
+                }
+                ffi::LUA_TTABLE => LuaValue::Table(Table { ctx, ptr: <LuaRef>::read(ctx, idx) }),
                 ffi::LUA_TFUNCTION => LuaValue::Function(Function::read(ctx, idx)),
-                ffi::LUA_TUSERDATA => unimplemented!(),
-                ffi::LUA_TTHREAD => unimplemented!(),
-                _ => panic!("yahallo"),
+                ffi::LUA_TUSERDATA => LuaValue::UserData(AnyUserData::read(ctx, idx)),
+                ffi::LUA_TTHREAD => LuaValue::Thread(Thread::read(ctx, idx)),
+                _ => LuaValue::None,
             }
         }
     }
 
-    fn check(ctx: &'a Context, idx: i32) -> bool {
+    fn check(_ctx: &'a Context, _idx: i32) -> bool {
         true
     }
 }
 
+impl<'a> Push for LuaValue<'a> {
+    fn push(&self, ctx: &Context) {
+        match self {
+            &LuaValue::Number(n) => n.push(ctx),
+            LuaValue::String(s) => s.push(ctx),
+            LuaValue::Bytes(b) => unsafe {
+                ffi::lua_pushlstring(ctx.handle, b.as_ptr() as *const _, b.len())
+            },
+            &LuaValue::Bool(b) => b.push(ctx),
+            LuaValue::Table(t) => t.ptr.push(ctx),
+            LuaValue::Function(f) => f.push(ctx),
+            &LuaValue::LightUserData(p) => unsafe {
+                ffi::lua_pushlightuserdata(ctx.handle, p)
+            },
+            LuaValue::UserData(ud) => ud.push(ctx),
+            LuaValue::Thread(t) => t.push(ctx),
+            // always takes up a slot, to agree with `Size`
+            &LuaValue::Nil |
+            &LuaValue::None => nil.push(ctx),
+        }
+    }
+}
+
+impl<'a> LuaValue<'a> {
+    fn context(&self) -> Option<&'a Context> {
+        match self {
+            LuaValue::Table(t) => Some(t.ctx),
+            LuaValue::Function(f) => Some(f.ctx),
+            LuaValue::UserData(ud) => Some(ud.ctx),
+            LuaValue::Thread(t) => Some(t.ctx),
+            _ => None
+        }
+    }
+}
+
+// what `tostring` returns, including the result of `__tostring`
+impl<'a> fmt::Display for LuaValue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &LuaValue::Number(n) => write!(f, "{}", format_number(n)),
+            LuaValue::String(s) => write!(f, "{}", s),
+            LuaValue::Bytes(b) => write!(f, "{}", String::from_utf8_lossy(b)),
+            &LuaValue::Bool(b) => write!(f, "{}", b),
+            &LuaValue::LightUserData(p) => write!(f, "userdata: {:p}", p),
+            &LuaValue::Nil |
+            &LuaValue::None => write!(f, "nil"),
+            other => {
+                let ctx = other.context().unwrap();
+                other.push(ctx);
+                let s = tostring(ctx, -1);
+                ctx.pop_discard(1);
+                write!(f, "{}", s)
+            }
+        }
+    }
+}
+
+// formats like Lua's `LUA_NUMBER_FMT`, "%.14g"
+pub(crate) fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return match n.is_sign_negative() {
+            true => "-nan".to_string(),
+            false => "nan".to_string()
+        };
+    }
+    if n.is_infinite() {
+        return match n < 0.0 {
+            true => "-inf".to_string(),
+            false => "inf".to_string()
+        };
+    }
+
+    // the exponent after rounding to 14 significant digits
+    let sci = format!("{:.13e}", n);
+    let pos = sci.find('e').unwrap();
+    let exp: i32 = sci[pos + 1..].parse().unwrap();
+
+    let trim = |s: &str| -> String {
+        match s.contains('.') {
+            true => s.trim_end_matches('0').trim_end_matches('.').to_string(),
+            false => s.to_string()
+        }
+    };
+
+    match (-4..14).contains(&exp) {
+        true => trim(&format!("{:.*}", (13 - exp) as usize, n)),
+        false => {
+            let sign = if exp < 0 { '-' } else { '+' };
+            format!("{}e{}{:02}", trim(&sci[..pos]), sign, exp.abs())
+        }
+    }
+}
+
+// Unlike `format_number`, keeps every digit so the number reads back
+// unchanged. `non_finite` spells NaN, infinity and minus infinity, which have
+// no literals in Lua or JSON.
+pub(crate) fn exact_number(n: f64, non_finite: [&str; 3]) -> String {
+    if n.is_nan() {
+        non_finite[0].to_string()
+    } else if n.is_infinite() {
+        match n > 0.0 {
+            true => non_finite[1].to_string(),
+            false => non_finite[2].to_string()
+        }
+    } else if n == n.trunc() && n.abs() < 1e15 && !(n == 0.0 && n.is_sign_negative()) {
+        format!("{}", n as i64)
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+// `tostring` for the value at `idx`, calling `__tostring` if there is one
+pub(crate) fn tostring(ctx: &Context, idx: i32) -> String {
+    unsafe {
+        let idx = match idx < 0 && idx > ffi::LUA_REGISTRYINDEX {
+            true => ctx.size() + idx + 1,
+            false => idx
+        };
+
+        if ffi::luaL_getmetafield(ctx.handle, idx, c_str!("__tostring")) != 0 {
+            ffi::lua_pushvalue(ctx.handle, idx);
+
+            let s = match ffi::lua_pcall(ctx.handle, 1, 1, 0) == 0 && ffi::lua_type(ctx.handle, -1) == ffi::LUA_TSTRING {
+                true => Some(String::read(ctx, -1)),
+                false => None
+            };
+            ctx.pop_discard(1);
+
+            if let Some(s) = s {
+                return s;
+            }
+        }
+
+        match ffi::lua_type(ctx.handle, idx) {
+            ffi::LUA_TNONE |
+            ffi::LUA_TNIL => "nil".to_string(),
+            ffi::LUA_TBOOLEAN => bool::read(ctx, idx).to_string(),
+            ffi::LUA_TNUMBER => format_number(ffi::lua_tonumber(ctx.handle, idx)),
+            ffi::LUA_TSTRING => String::read(ctx, idx),
+            t => {
+                let name = CStr::from_ptr(ffi::lua_typename(ctx.handle, t));
+                format!("{}: {:p}", name.to_string_lossy(), ffi::lua_topointer(ctx.handle, idx))
+            }
+        }
+    }
+}
+
+// a short, single line rendering of the value at `idx` for debugging output
+pub(crate) fn preview(ctx: &Context, idx: i32) -> String {
+    unsafe {
+        match ffi::lua_type(ctx.handle, idx) {
+            ffi::LUA_TNONE => "none".to_string(),
+            ffi::LUA_TNIL => "nil".to_string(),
+            ffi::LUA_TBOOLEAN => format!("{}", bool::read(ctx, idx)),
+            ffi::LUA_TNUMBER => format!("{}", f64::read(ctx, idx)),
+            ffi::LUA_TSTRING => {
+                let s = String::read(ctx, idx);
+                match s.chars().count() > 40 {
+                    true => format!("{:?}...", s.chars().take(40).collect::<String>()),
+                    false => format!("{:?}", s)
+                }
+            }
+            t => {
+                let name = CStr::from_ptr(ffi::lua_typename(ctx.handle, t));
+                format!("{}: {:p}", name.to_string_lossy(), ffi::lua_topointer(ctx.handle, idx))
+            }
+        }
+    }
+}
+
 impl<'a> Size for LuaValue<'a> {
     fn size() -> i32 {
         1
@@ -59,6 +278,54 @@ fn read_value() {
 
     assert_eq!(ctx.remove::<LuaValue>(1), LuaValue::Nil);
     assert_eq!(ctx.remove::<LuaValue>(1), LuaValue::Number(45f64));
-    assert_eq!(ctx.remove::<LuaValue>(1), LuaValue::String("Hello world!"));
+    assert_eq!(ctx.remove::<LuaValue>(1), LuaValue::String("Hello world!".to_string()));
+}
+
+#[test]
+fn display() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    ctx.eval("t = setmetatable({}, { __tostring = function() return 'custom' end }) u = {}").unwrap();
+
+    let cases = ["1", "-2.5", "1/3", "1e15", "2^53", "1e-5", "0/0 == 0/0", "nil", "'text'", "t", "u", "print"];
+    for case in cases.iter() {
+        ctx.eval(&format!("return {0}, tostring({0})", case)).unwrap();
+        let expected = ctx.pop::<String>();
+        assert_eq!(ctx.pop::<LuaValue>().to_string(), expected);
+    }
+
+    assert_eq!(format_number(1.0 / 0.0), "inf");
+    assert_eq!(format_number(-0.0), "-0");
+    assert_eq!(format_number(123456789012345678.0), "1.2345678901235e+17");
+    assert_eq!(ctx.size(), 0);
+}
+
+#[test]
+fn read_other_values() {
+    let ctx = Context::new();
+    unsafe { ffi::luaL_openlibs(ctx.handle) };
+
+    // every kind of value `_G` holds, including the library userdata
+    ctx.eval("return _G").unwrap();
+    let globals = ctx.pop::<Table>();
+    assert!(globals.pairs::<LuaValue, LuaValue>().count() > 10);
+
+    ctx.eval("return io.stdout, coroutine.create(function() end), '\\255'").unwrap();
+    assert_eq!(ctx.peek::<LuaValue>(-1), LuaValue::Bytes(vec![255]));
+    assert_eq!(ctx.peek::<LuaValue>(-2).type_name(), "thread");
+    assert_eq!(ctx.peek::<LuaValue>(-3).type_name(), "userdata");
+
+    let mut x = 5;
+    unsafe { ffi::lua_pushlightuserdata(ctx.handle, &mut x as *mut i32 as *mut _) };
+    assert_eq!(ctx.pop::<LuaValue>(), LuaValue::LightUserData(&mut x as *mut i32 as *mut _));
+
+    // values read from one place can be written back somewhere else
+    let values: Vec<LuaValue> = (1..4).map(|i| ctx.peek::<LuaValue>(i)).collect();
+    ctx.pop_discard(3);
+    ctx.set("copy", values);
+    ctx.eval("return io.type(copy[1]) == 'file' and type(copy[2]) == 'thread' and copy[3] == '\\255'").unwrap();
+    assert!(ctx.pop::<bool>());
+    assert_eq!(ctx.size(), 0);
 }
 